@@ -0,0 +1,61 @@
+use serde::Deserialize;
+
+use crate::uniform::ShaderType;
+
+// Scene motion. The whole scene turns about the vertical axis; in the
+// window time runs from start, still renders are taken at start.
+//
+//  [animation]
+//  enabled = true
+//  spin = 1.0
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnimationConfig {
+    pub enabled: bool,
+    // Radians a second
+    pub spin: f32,
+    // Seconds into the animation
+    pub start: f32,
+}
+
+impl Default for AnimationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            spin: 0.5,
+            start: 0.0,
+        }
+    }
+}
+
+impl AnimationConfig {
+    // elapsed is the seconds since the animation started running
+    pub fn to_shader(&self, elapsed: f32) -> Animation {
+        Animation {
+            time: self.start + elapsed,
+            spin: if self.enabled { self.spin } else { 0.0 },
+            _pad: [0.0; 2],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Animation {
+    // When the frame's exposure opens
+    pub time: f32,
+    pub spin: f32,
+    pub _pad: [f32; 2],
+}
+
+impl ShaderType for Animation {
+    const WGSL_TYPE: &'static str = "Animation";
+    const WGSL_STRUCT: &'static str = "
+struct Animation {
+    time: f32,
+    spin: f32,
+    _pad0: f32,
+    _pad1: f32,
+}
+";
+}
@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::uniform::ShaderType;
+
+// What rays that miss everything see. The values must match the
+// BACKGROUND_* constants in shader.wgsl.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundKind {
+    #[default]
+    Solid = 0,
+    // From color straight down to top straight up
+    Gradient,
+    // Analytic sky, color at the horizon, top at the zenith and a sun
+    Sky,
+    // Equirectangular HDR image loaded from path
+    Environment,
+}
+
+//  [background]
+//  kind = "environment"
+//  path = "studio.hdr"
+//  rotation = 90.0
+//  ambient = 1.0
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BackgroundConfig {
+    pub kind: BackgroundKind,
+    pub color: [f32; 3],
+    pub top: [f32; 3],
+    // Towards the sun
+    pub sun_direction: [f32; 3],
+    pub sun_color: [f32; 3],
+    // Angular radius of the sun disk in degrees
+    pub sun_size: f32,
+    pub path: Option<PathBuf>,
+    // Turns the environment about the vertical axis, in degrees
+    pub rotation: f32,
+    pub intensity: f32,
+    // How much the background lights surfaces, on top of [ambient]
+    pub ambient: f32,
+}
+
+impl Default for BackgroundConfig {
+    fn default() -> Self {
+        Self {
+            kind: BackgroundKind::Solid,
+            color: [0.0, 0.0, 0.0],
+            top: [0.0, 0.0, 0.0],
+            sun_direction: [0.5, 0.6, 0.5],
+            sun_color: [20.0, 18.0, 15.0],
+            sun_size: 0.5,
+            path: None,
+            rotation: 0.0,
+            intensity: 1.0,
+            ambient: 0.0,
+        }
+    }
+}
+
+impl BackgroundConfig {
+    // env_levels is the mip count of the environment texture
+    pub fn to_shader(&self, env_levels: u32) -> Background {
+        let d = self.sun_direction;
+        let len = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+        Background {
+            color: self.color,
+            kind: self.kind as u32,
+            top: self.top,
+            intensity: self.intensity,
+            sun_direction: d.map(|x| x / len),
+            sun_cos: self.sun_size.to_radians().cos(),
+            sun_color: self.sun_color,
+            ambient: self.ambient,
+            rotation: self.rotation.to_radians(),
+            env_levels: env_levels as f32,
+            _pad: [0.0; 2],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Background {
+    pub color: [f32; 3],
+    pub kind: u32,
+    pub top: [f32; 3],
+    pub intensity: f32,
+    pub sun_direction: [f32; 3],
+    pub sun_cos: f32,
+    pub sun_color: [f32; 3],
+    pub ambient: f32,
+    pub rotation: f32,
+    pub env_levels: f32,
+    pub _pad: [f32; 2],
+}
+
+impl ShaderType for Background {
+    const WGSL_TYPE: &'static str = "Background";
+    const WGSL_STRUCT: &'static str = "
+struct Background {
+    color: vec3f,
+    kind: u32,
+    top: vec3f,
+    intensity: f32,
+    sun_direction: vec3f,
+    sun_cos: f32,
+    sun_color: vec3f,
+    ambient: f32,
+    rotation: f32,
+    env_levels: f32,
+    _pad0: f32,
+    _pad1: f32,
+}
+";
+}
@@ -0,0 +1,410 @@
+//  Benchmark runs - a scene rendered for a fixed number of frames with
+//  the camera on a scripted path, so two runs see exactly the same
+//  frames and their times can be compared. The report is JSON.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera::CameraConfig;
+use crate::config::SceneConfig;
+use crate::error::{Error, Result};
+use crate::offscreen::Offscreen;
+use crate::profile::FrameTiming;
+use crate::redraw::Redraw;
+use crate::stats::MarchStats;
+
+//  [bench]
+//  frames = 240
+//  orbit = 90.0
+//  dolly = 0.5
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BenchConfig {
+    // Measured, after the warmup
+    pub frames: u32,
+    // Rendered at the first camera position and left out of the report,
+    // while pipelines and caches settle
+    pub warmup: u32,
+    // Degrees the camera orbits its target over the measured frames
+    pub orbit: f32,
+    // Distance from the target at the end of the path over the distance
+    // at the start
+    pub dolly: f32,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            frames: 120,
+            warmup: 5,
+            orbit: 360.0,
+            dolly: 1.0,
+        }
+    }
+}
+
+impl BenchConfig {
+    // Where start is moved to for frame, counting the warmup
+    pub fn camera(&self, start: &CameraConfig, frame: u32) -> CameraConfig {
+        let t = frame.saturating_sub(self.warmup) as f32 / self.frames.max(1) as f32;
+        let mut camera = start.clone();
+        camera.orbit((self.orbit * t).to_radians(), 0.0);
+        camera.dolly(self.dolly.powf(t));
+        camera
+    }
+}
+
+// One measured frame
+#[derive(Debug, Clone)]
+pub struct Sample {
+    // Recording the frame to the GPU finishing it
+    pub cpu: Duration,
+    // Of the timed passes, None without TIMESTAMP_QUERY
+    pub gpu: Option<Duration>,
+    pub passes: Vec<(String, Duration)>,
+    pub stats: Option<MarchStats>,
+}
+
+impl Sample {
+    pub fn new(timing: &FrameTiming, stats: Option<MarchStats>) -> Self {
+        Self {
+            cpu: timing.frame,
+            gpu: timing.gpu(),
+            passes: timing.passes.clone(),
+            stats,
+        }
+    }
+}
+
+// What was benchmarked, for the report
+pub struct RunInfo {
+    pub scene: String,
+    pub width: u32,
+    pub height: u32,
+    pub windowed: bool,
+    pub adapter: wgpu::AdapterInfo,
+}
+
+pub struct Bench {
+    config: BenchConfig,
+    start: CameraConfig,
+    // Rendered so far, counting the warmup
+    frame: u32,
+    samples: Vec<Sample>,
+}
+
+impl Bench {
+    pub fn new(config: &BenchConfig, start: &CameraConfig) -> Self {
+        Self {
+            config: config.clone(),
+            start: start.clone(),
+            frame: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    // For the next frame, None once every frame has been recorded
+    pub fn next_camera(&self) -> Option<CameraConfig> {
+        (!self.done()).then(|| self.config.camera(&self.start, self.frame))
+    }
+
+    pub fn done(&self) -> bool {
+        self.frame >= self.config.warmup + self.config.frames
+    }
+
+    // Call after each frame, warmup frames are dropped
+    pub fn record(&mut self, sample: Sample) {
+        if self.frame >= self.config.warmup {
+            self.samples.push(sample);
+        }
+        self.frame += 1;
+    }
+
+    pub fn report<'a>(&'a self, info: &'a RunInfo) -> Report<'a> {
+        let cpu: Vec<Duration> = self.samples.iter().map(|s| s.cpu).collect();
+        let gpu: Option<Vec<Duration>> = self.samples.iter().map(|s| s.gpu).collect();
+
+        // Passes that didn't run on a frame count as 0 on it, like the
+        // profiler's averages
+        let mut labels: Vec<&str> = Vec::new();
+        for (label, _) in self.samples.iter().flat_map(|s| &s.passes) {
+            if !labels.contains(&label.as_str()) {
+                labels.push(label);
+            }
+        }
+        let passes = labels.into_iter()
+            .filter_map(|label| {
+                let times: Vec<Duration> = self.samples.iter()
+                    .map(|s| s.passes.iter()
+                        .filter(|(l, _)| l == label)
+                        .map(|(_, t)| *t)
+                        .sum())
+                    .collect();
+                Some((label, summary(&times)?))
+            })
+            .collect();
+
+        let stats: Option<Vec<MarchStats>> = self.samples.iter().map(|s| s.stats).collect();
+        let march = stats.filter(|s| !s.is_empty()).map(|stats| {
+            let total = |f: fn(&MarchStats) -> u64| -> u64 {
+                stats.iter().map(f).sum()
+            };
+            let rays = total(|s| s.rays);
+            let steps = total(|s| s.steps);
+            MarchTotals {
+                rays,
+                steps,
+                steps_per_ray: steps as f64 / rays.max(1) as f64,
+                exhausted: total(|s| s.exhausted),
+                misses: total(|s| s.misses),
+                shadow_steps: total(|s| s.shadow_steps),
+            }
+        });
+
+        let samples = self.samples.iter().enumerate()
+            .map(|(frame, s)| FrameReport {
+                frame,
+                cpu_ms: ms(s.cpu),
+                gpu_ms: s.gpu.map(ms),
+                steps_per_ray: s.stats.as_ref().map(MarchStats::steps_per_ray),
+            })
+            .collect();
+
+        Report {
+            scene: &info.scene,
+            width: info.width,
+            height: info.height,
+            windowed: info.windowed,
+            adapter: &info.adapter.name,
+            backend: info.adapter.backend.to_string(),
+            driver: &info.adapter.driver_info,
+            frames: self.samples.len(),
+            warmup: self.config.warmup,
+            orbit: self.config.orbit,
+            dolly: self.config.dolly,
+            cpu_ms: summary(&cpu),
+            gpu_ms: gpu.as_deref().and_then(summary),
+            passes_ms: Passes(passes),
+            march,
+            samples,
+        }
+    }
+}
+
+// What a bench run writes, as JSON. Times are in milliseconds, and
+// what wasn't measured is null.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub scene: &'a str,
+    pub width: u32,
+    pub height: u32,
+    pub windowed: bool,
+    pub adapter: &'a str,
+    pub backend: String,
+    pub driver: &'a str,
+    pub frames: usize,
+    pub warmup: u32,
+    pub orbit: f32,
+    pub dolly: f32,
+    pub cpu_ms: Option<Summary>,
+    // None without TIMESTAMP_QUERY
+    pub gpu_ms: Option<Summary>,
+    pub passes_ms: Passes<'a>,
+    pub march: Option<MarchTotals>,
+    pub samples: Vec<FrameReport>,
+}
+
+// Each pass by its label, in the order they first ran
+#[derive(Debug)]
+pub struct Passes<'a>(pub Vec<(&'a str, Summary)>);
+
+impl Serialize for Passes<'_> {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(label, times)| (label, times)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Summary {
+    pub mean: f64,
+    pub min: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+// Over every measured frame
+#[derive(Debug, Serialize)]
+pub struct MarchTotals {
+    pub rays: u64,
+    pub steps: u64,
+    pub steps_per_ray: f64,
+    pub exhausted: u64,
+    pub misses: u64,
+    pub shadow_steps: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FrameReport {
+    pub frame: usize,
+    pub cpu_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gpu_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps_per_ray: Option<f32>,
+}
+
+fn ms(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000.0
+}
+
+// Mean, extremes and percentiles in milliseconds, None for no times
+fn summary(times: &[Duration]) -> Option<Summary> {
+    let mut sorted: Vec<f64> = times.iter().map(|&t| ms(t)).collect();
+    sorted.sort_by(f64::total_cmp);
+    let &max = sorted.last()?;
+    // Nearest rank
+    let percentile = |p: f64| {
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    };
+    Some(Summary {
+        mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+        min: sorted[0],
+        p50: percentile(50.0),
+        p90: percentile(90.0),
+        p95: percentile(95.0),
+        p99: percentile(99.0),
+        max,
+    })
+}
+
+// A bench to run and where its report goes
+pub struct BenchRun {
+    pub bench: Bench,
+    pub scene: String,
+    pub width: u32,
+    pub height: u32,
+    pub report: PathBuf,
+}
+
+impl BenchRun {
+    pub fn write_report(&self, info: &RunInfo) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.bench.report(info))
+            .expect("bench reports serialize");
+        std::fs::write(&self.report, json + "\n")
+            .map_err(|source| Error::Io { path: self.report.clone(), source })?;
+        log::info!("bench report written to {}", self.report.display());
+        Ok(())
+    }
+}
+
+// Turns on what a bench measures with, and off what would make two runs
+// differ
+pub fn configure(config: &mut SceneConfig) {
+    config.profile.enabled = true;
+    config.stats.enabled = true;
+    config.stats.interval = 1;
+    config.redraw = Redraw::Continuous;
+    // The camera path is the only motion
+    config.animation.enabled = false;
+}
+
+// Renders every frame of run without a window, then writes its report
+pub async fn offscreen(config: &SceneConfig, mut run: BenchRun) -> Result<()> {
+    let mut offscreen = Offscreen::new(config, run.width, run.height).await?;
+    while let Some(camera) = run.bench.next_camera() {
+        offscreen.set_camera(&camera);
+        offscreen.render_frame();
+        let timing = offscreen.last_frame_timing()
+            .expect("benches are profiled");
+        run.bench.record(Sample::new(timing, offscreen.march_stats()));
+    }
+    run.write_report(&RunInfo {
+        scene: run.scene.clone(),
+        width: run.width,
+        height: run.height,
+        windowed: false,
+        adapter: offscreen.adapter_info(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: &[u64]) -> Vec<Duration> {
+        ms.iter().map(|&ms| Duration::from_millis(ms)).collect()
+    }
+
+    fn run_info(scene: &str) -> RunInfo {
+        RunInfo {
+            scene: scene.into(),
+            width: 64,
+            height: 32,
+            windowed: false,
+            adapter: wgpu::AdapterInfo {
+                name: "test".into(),
+                vendor: 0,
+                device: 0,
+                device_type: wgpu::DeviceType::Cpu,
+                driver: String::new(),
+                driver_info: String::new(),
+                backend: wgpu::Backend::Noop,
+            },
+        }
+    }
+
+    #[test]
+    fn summary_percentiles_are_nearest_rank() {
+        // Out of order, 1 to 100 ms
+        let times = millis(&(1..=100).rev().collect::<Vec<_>>());
+        let s = summary(&times).unwrap();
+        assert_eq!((s.min, s.max, s.mean), (1.0, 100.0, 50.5));
+        assert_eq!((s.p50, s.p90, s.p95, s.p99), (50.0, 90.0, 95.0, 99.0));
+    }
+
+    #[test]
+    fn summary_of_few_times() {
+        let s = summary(&millis(&[4])).unwrap();
+        assert_eq!((s.min, s.p50, s.p99, s.max), (4.0, 4.0, 4.0, 4.0));
+        let s = summary(&millis(&[1, 3])).unwrap();
+        assert_eq!((s.p50, s.p90), (1.0, 3.0));
+        assert_eq!(summary(&[]), None);
+    }
+
+    #[test]
+    fn report_is_valid_json() {
+        let config = BenchConfig { frames: 2, warmup: 1, orbit: f32::NAN, ..Default::default() };
+        let mut bench = Bench::new(&config, &CameraConfig::default());
+        for ms in [50, 10, 20] {
+            bench.record(Sample {
+                cpu: Duration::from_millis(ms),
+                gpu: None,
+                passes: vec![("raymarch".into(), Duration::from_millis(ms / 2))],
+                stats: None,
+            });
+        }
+        assert!(bench.done());
+        let info = run_info("a \"quoted\"\\scene\n");
+        let json = serde_json::to_string(&bench.report(&info)).unwrap();
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(report["scene"], "a \"quoted\"\\scene\n");
+        assert_eq!(report["frames"], 2);
+        // Not a number, not measured
+        assert!(report["orbit"].is_null());
+        assert!(report["gpu_ms"].is_null());
+        assert!(report["march"].is_null());
+        assert_eq!(report["cpu_ms"]["max"], 20.0);
+        assert_eq!(report["passes_ms"]["raymarch"]["min"], 5.0);
+        assert_eq!(report["samples"][1]["cpu_ms"], 20.0);
+        assert!(report["samples"][1].get("gpu_ms").is_none());
+    }
+}
@@ -0,0 +1,217 @@
+use serde::Deserialize;
+
+use crate::math::{vec3, Vec3};
+use crate::uniform::ShaderType;
+
+// How screen positions become rays. The values must match the
+// PROJECTION_* constants in shader.wgsl.
+named_enum! {
+    #[derive(Default)]
+    pub enum Projection("projection") {
+        // Pinhole, fov across the screen
+        #[default]
+        Perspective = "perspective",
+        // Parallel rays, view_height across the screen
+        Orthographic = "orthographic",
+        // Equidistant fisheye, fov across the screen, more in the corners
+        Fisheye = "fisheye",
+        // Full 360 by 180 degree panorama
+        Equirectangular = "equirectangular",
+    }
+}
+
+// Both eyes in one image, the left eye on the left or at the top. The
+// values must match the STEREO_* constants in shader.wgsl.
+named_enum! {
+    #[derive(Default)]
+    pub enum Stereo("stereo mode") {
+        #[default]
+        None = "none",
+        SideBySide = "side_by_side",
+        TopBottom = "top_bottom",
+    }
+}
+
+//  [camera]
+//  position = [0.0, 1.0, 3.0]
+//  target = [0.0, 0.0, 0.0]
+//  projection = "perspective"
+//  fov = 60.0
+//  aperture = 0.05
+//  shutter = 0.02
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    pub position: [f32; 3],
+    // What the camera looks at, also what it orbits around
+    pub target: [f32; 3],
+    pub projection: Projection,
+    // Degrees across the screen for perspective and fisheye
+    pub fov: f32,
+    // Scene units across the screen for orthographic
+    pub view_height: f32,
+    pub stereo: Stereo,
+    // Distance between the eyes for stereo
+    pub eye_separation: f32,
+    // Radius of the thin lens, 0 is a pinhole with everything sharp
+    pub aperture: f32,
+    // Distance that is in focus, the target's when left out
+    pub focus_distance: Option<f32>,
+    // Seconds the shutter is open, objects moving by [animation] blur
+    // over it
+    pub shutter: f32,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 0.0, 2.0],
+            target: [0.0, 0.0, 0.0],
+            projection: Projection::default(),
+            fov: 90.0,
+            view_height: 2.0,
+            stereo: Stereo::default(),
+            eye_separation: 0.065,
+            aperture: 0.0,
+            focus_distance: None,
+            shutter: 0.0,
+        }
+    }
+}
+
+impl CameraConfig {
+    pub fn to_shader(&self) -> Camera {
+        let to_target = Vec3::from(self.target) - Vec3::from(self.position);
+        Camera {
+            position: self.position,
+            aperture: self.aperture,
+            look_at: self.target,
+            focus_distance: self.focus_distance
+                .unwrap_or_else(|| to_target.length()),
+            shutter: self.shutter,
+            projection: self.projection as u32,
+            stereo: self.stereo as u32,
+            fov: self.fov.to_radians(),
+            // Distance to the screen for a pinhole, 1 at 90 degrees
+            focal: (1.0 / (self.fov as f64 / 2.0).to_radians().tan()) as f32,
+            view_height: self.view_height,
+            eye_separation: self.eye_separation,
+            _pad: 0.0,
+        }
+    }
+
+    // Turns the camera about the target, yaw about the vertical and
+    // pitch up and down, in radians. Stops short of straight up or down
+    // where the camera's up vector is undefined. A camera at its target
+    // has nowhere to go and stays put.
+    pub fn orbit(&mut self, yaw: f32, pitch: f32) {
+        let target = Vec3::from(self.target);
+        let offset = Vec3::from(self.position) - target;
+        let radius = offset.length();
+        if radius == 0.0 { return; }
+        let theta = offset.x.atan2(offset.z) + yaw;
+        let phi = ((offset.y / radius).clamp(-1.0, 1.0).asin() + pitch)
+            .clamp(-1.5, 1.5);
+        let offset = vec3(
+            theta.sin() * phi.cos(),
+            phi.sin(),
+            theta.cos() * phi.cos(),
+        ) * radius;
+        self.position = (target + offset).into();
+    }
+
+    // Moves towards (factor < 1) or away from the target
+    pub fn dolly(&mut self, factor: f32) {
+        let target = Vec3::from(self.target);
+        let offset = Vec3::from(self.position) - target;
+        self.position = (target + offset * factor).into();
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Camera {
+    pub position: [f32; 3],
+    pub aperture: f32,
+    // target in the config, which WGSL reserves
+    pub look_at: [f32; 3],
+    pub focus_distance: f32,
+    pub shutter: f32,
+    pub projection: u32,
+    pub stereo: u32,
+    // Radians
+    pub fov: f32,
+    pub focal: f32,
+    pub view_height: f32,
+    pub eye_separation: f32,
+    pub _pad: f32,
+}
+
+impl ShaderType for Camera {
+    const WGSL_TYPE: &'static str = "Camera";
+    const WGSL_STRUCT: &'static str = "
+struct Camera {
+    position: vec3f,
+    aperture: f32,
+    look_at: vec3f,
+    focus_distance: f32,
+    shutter: f32,
+    projection: u32,
+    stereo: u32,
+    fov: f32,
+    focal: f32,
+    view_height: f32,
+    eye_separation: f32,
+    _pad: f32,
+}
+";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn looking_at(position: [f32; 3], target: [f32; 3]) -> CameraConfig {
+        CameraConfig { position, target, ..Default::default() }
+    }
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        (Vec3::from(a) - Vec3::from(b)).length() < 1e-5
+    }
+
+    #[test]
+    fn orbit_keeps_the_distance() {
+        let mut camera = looking_at([1.0, 0.0, 2.0], [1.0, 0.0, 0.0]);
+        camera.orbit(std::f32::consts::FRAC_PI_2, 0.0);
+        assert!(close(camera.position, [3.0, 0.0, 0.0]), "{:?}", camera.position);
+        camera.orbit(0.3, 0.4);
+        let offset = Vec3::from(camera.position) - Vec3::from(camera.target);
+        assert!((offset.length() - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn orbit_stops_short_of_the_pole() {
+        let mut camera = CameraConfig::default();
+        camera.orbit(0.0, 10.0);
+        let offset = Vec3::from(camera.position);
+        assert!((offset.y / offset.length() - 1.5f32.sin()).abs() < 1e-5);
+        assert!(offset.z > 0.0);
+    }
+
+    #[test]
+    fn dolly_scales_the_distance() {
+        let mut camera = looking_at([1.0, 2.0, 3.0], [1.0, 0.0, 3.0]);
+        camera.dolly(0.5);
+        assert!(close(camera.position, [1.0, 1.0, 3.0]));
+        camera.dolly(4.0);
+        assert!(close(camera.position, [1.0, 4.0, 3.0]));
+    }
+
+    #[test]
+    fn camera_at_its_target_stays_put() {
+        let mut camera = looking_at([1.0, 1.0, 1.0], [1.0, 1.0, 1.0]);
+        camera.orbit(0.5, 0.5);
+        camera.dolly(2.0);
+        assert_eq!(camera.position, [1.0, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,158 @@
+use serde::Deserialize;
+
+use crate::math::{saturate, Vec3};
+use crate::uniform::ShaderType;
+
+// How the linear scene color is turned into something for the screen.
+// The values must match the OUTPUT_* constants in shader.wgsl.
+named_enum! {
+    #[derive(Default)]
+    pub enum OutputTransform("output transform") {
+        // Clip to 0..1 then sRGB encode
+        #[default]
+        Srgb = "srgb",
+        // Written as is, for when the numbers matter more than the look
+        Linear = "linear",
+        Reinhard = "reinhard",
+        // Narkowicz's fit of the ACES filmic curve
+        Aces = "aces",
+        // Troy Sobotka's AgX, as approximated by Benjamin Wrensch
+        Agx = "agx",
+    }
+}
+
+//  [output]
+//  transform = "aces"
+//  exposure = 1.5
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub transform: OutputTransform,
+    // Scales the scene color before the transform
+    pub exposure: f32,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            transform: OutputTransform::Srgb,
+            exposure: 1.0,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn to_shader(&self) -> Output {
+        Output {
+            transform: self.transform as u32,
+            exposure: self.exposure,
+            _pad: [0.0; 2],
+        }
+    }
+
+    // What debug views use, their values go to the screen untouched
+    pub fn raw() -> Self {
+        Self {
+            transform: OutputTransform::Linear,
+            exposure: 1.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Output {
+    pub transform: u32,
+    pub exposure: f32,
+    pub _pad: [f32; 2],
+}
+
+impl ShaderType for Output {
+    const WGSL_TYPE: &'static str = "Output";
+    const WGSL_STRUCT: &'static str = "
+struct Output {
+    transform: u32,
+    exposure: f32,
+    _pad0: f32,
+    _pad1: f32,
+}
+";
+}
+
+//  The same math as post/tonemap.wgsl, for the CPU renderer
+
+// Linear scene color to display linear color
+pub fn output_transform(color: Vec3, transform: OutputTransform) -> Vec3 {
+    match transform {
+        OutputTransform::Srgb | OutputTransform::Linear => color,
+        OutputTransform::Reinhard => color.map(|c| c / (1.0 + c)),
+        OutputTransform::Aces => color.map(aces),
+        OutputTransform::Agx => agx(color),
+    }
+}
+
+// Scene color to an 8 bit sRGB pixel, what the tonemap pass ends up
+// writing when it's the only post pass
+pub fn encode(color: Vec3, output: &Output) -> [u8; 3] {
+    let transform = OutputTransform::ALL
+        .get(output.transform as usize)
+        .copied()
+        .unwrap_or_default();
+    let color = output_transform(color * output.exposure, transform);
+    let color = if transform == OutputTransform::Linear {
+        color
+    } else {
+        color.map(|c| linear_to_srgb(saturate(c)))
+    };
+    [color.x, color.y, color.z].map(|c| (saturate(c) * 255.0 + 0.5) as u8)
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn aces(x: f32) -> f32 {
+    let x = x * 0.6;
+    saturate((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14))
+}
+
+// Column major like the WGSL mat3x3f constructor
+fn mul3(m: [[f32; 3]; 3], v: Vec3) -> Vec3 {
+    Vec3::from(m[0]) * v.x + Vec3::from(m[1]) * v.y + Vec3::from(m[2]) * v.z
+}
+
+// Published values, kept as is to match the shader
+#[allow(clippy::excessive_precision)]
+const AGX_INSET: [[f32; 3]; 3] = [
+    [0.842479062253094, 0.0423282422610123, 0.0423756549057051],
+    [0.0784335999999992, 0.878468636469772, 0.0784336],
+    [0.0792237451477643, 0.0791661274605434, 0.879142973793104],
+];
+#[allow(clippy::excessive_precision)]
+const AGX_OUTSET: [[f32; 3]; 3] = [
+    [1.19687900512017, -0.0528968517574562, -0.0529716355144438],
+    [-0.0980208811401368, 1.15190312990417, -0.0980434501171241],
+    [-0.0990297440797205, -0.0989611768448433, 1.15107367264116],
+];
+const AGX_MIN_EV: f32 = -12.47393;
+const AGX_MAX_EV: f32 = 4.026069;
+
+fn agx(color: Vec3) -> Vec3 {
+    let v = mul3(AGX_INSET, color);
+    let v = v.map(|c| {
+        let ev = c.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV);
+        let x = (ev - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV);
+        // Polynomial fit of the AgX contrast curve
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4
+            - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+    // Back out of the AgX space and undo the 2.2 display encoding the
+    // curve assumes, the sRGB encode comes after
+    mul3(AGX_OUTSET, v).map(|c| c.max(0.0).powf(2.2))
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::animation::AnimationConfig;
+use crate::background::{BackgroundConfig, BackgroundKind};
+use crate::camera::CameraConfig;
+use crate::color::OutputConfig;
+use crate::denoise::DenoiseConfig;
+use crate::error::{Error, Result};
+use crate::light::{Ambient, LightConfig, OcclusionConfig};
+use crate::march::MarchConfig;
+use crate::slice::SliceConfig;
+use crate::stats::StatsConfig;
+use crate::profile::ProfileConfig;
+use crate::overlay::OverlayConfig;
+use crate::bench::BenchConfig;
+use crate::gpu::GpuConfig;
+use crate::material::{self, Material, MaterialConfig, TraceConfig};
+use crate::pathtrace::PathTraceConfig;
+use crate::post::PostPass;
+use crate::redraw::Redraw;
+use crate::sampling::SamplingConfig;
+use crate::view::ViewMode;
+
+// Everything about a scene that can be changed without editing the
+// shader. Read from a TOML scene file, any missing section keeps its
+// default.
+//
+//  view = "ao"
+//  redraw = "continuous"
+//
+//  [march]
+//  quality = "final"
+//
+//  [camera]
+//  position = [0.0, 1.0, 3.0]
+//
+//  [sampling]
+//  samples = 2
+//
+//  [animation]
+//  enabled = true
+//
+//  [ambient]
+//  sky = [0.3, 0.36, 0.6]
+//
+//  [background]
+//  kind = "gradient"
+//  color = [0.3, 0.36, 0.6]
+//
+//  [occlusion]
+//  samples = 8
+//
+//  [trace]
+//  max_bounces = 4
+//
+//  [path_trace]
+//  enabled = true
+//
+//  [denoise]
+//  enabled = true
+//
+//  [output]
+//  transform = "agx"
+//
+//  [[post]]
+//  pass = "vignette"
+//
+//  [[post]]
+//  pass = "tonemap"
+//
+//  [materials.red]
+//  transparency = 0.9
+//
+//  [[lights]]
+//  kind = "point"
+//  position = [1.0, 1.0, 2.0]
+//  color = [1.0, 0.9, 0.8]
+//  intensity = 4.0
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SceneConfig {
+    pub view: ViewMode,
+    pub redraw: Redraw,
+    pub march: MarchConfig,
+    pub slice: SliceConfig,
+    pub stats: StatsConfig,
+    pub profile: ProfileConfig,
+    pub overlay: OverlayConfig,
+    pub bench: BenchConfig,
+    pub gpu: GpuConfig,
+    pub camera: CameraConfig,
+    pub animation: AnimationConfig,
+    pub sampling: SamplingConfig,
+    pub background: BackgroundConfig,
+    pub ambient: Ambient,
+    pub occlusion: OcclusionConfig,
+    pub trace: TraceConfig,
+    pub path_trace: PathTraceConfig,
+    pub denoise: DenoiseConfig,
+    pub output: OutputConfig,
+    // Run in order after the raymarch pass
+    pub post: Vec<PostPass>,
+    pub materials: BTreeMap<String, MaterialConfig>,
+    pub lights: Vec<LightConfig>,
+}
+
+impl Default for SceneConfig {
+    fn default() -> Self {
+        Self {
+            view: ViewMode::default(),
+            redraw: Redraw::default(),
+            march: MarchConfig::default(),
+            slice: SliceConfig::default(),
+            stats: StatsConfig::default(),
+            profile: ProfileConfig::default(),
+            overlay: OverlayConfig::default(),
+            bench: BenchConfig::default(),
+            gpu: GpuConfig::default(),
+            camera: CameraConfig::default(),
+            animation: AnimationConfig::default(),
+            sampling: SamplingConfig::default(),
+            background: BackgroundConfig::default(),
+            ambient: Ambient::default(),
+            occlusion: OcclusionConfig::default(),
+            trace: TraceConfig::default(),
+            path_trace: PathTraceConfig::default(),
+            denoise: DenoiseConfig::default(),
+            output: OutputConfig::default(),
+            post: vec![PostPass::Tonemap],
+            materials: BTreeMap::new(),
+            lights: vec![LightConfig::sun()],
+        }
+    }
+}
+
+impl SceneConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid = |message: String| Error::Config {
+            path: path.to_path_buf(),
+            message,
+        };
+        let text = std::fs::read_to_string(path).map_err(|source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut config: Self = toml::from_str(&text)
+            .map_err(|e| invalid(e.to_string()))?;
+        if config.background.kind == BackgroundKind::Environment
+            && config.background.path.is_none()
+        {
+            return Err(invalid("environment background needs a path".into()));
+        }
+        // Files named in the scene are relative to it
+        if let (Some(env), Some(dir)) =
+            (&mut config.background.path, path.parent())
+        {
+            *env = dir.join(&*env);
+        }
+        for pass in &mut config.post {
+            if let PostPass::Lut(lut) = pass {
+                let lut_path = lut.path.as_mut()
+                    .ok_or_else(|| invalid("lut pass needs a path".into()))?;
+                if let Some(dir) = path.parent() {
+                    *lut_path = dir.join(&*lut_path);
+                }
+            }
+        }
+        if config.denoise.iterations > DenoiseConfig::MAX_ITERATIONS {
+            log::warn!(
+                "{} denoise iterations is too many, using {}",
+                config.denoise.iterations, DenoiseConfig::MAX_ITERATIONS);
+        }
+        // Catch misspelled material names now rather than at render time
+        material::materials(&config.materials).map_err(invalid)?;
+        Ok(config)
+    }
+
+    // The sampling actually used. Path tracing needs frames averaged so
+    // it turns progressive on.
+    pub fn effective_sampling(&self) -> SamplingConfig {
+        let mut sampling = self.sampling.clone();
+        sampling.progressive |= self.path_trace.enabled;
+        sampling
+    }
+
+    // Material table for the shader, names were checked by load
+    pub fn material_table(&self) -> Vec<Material> {
+        material::materials(&self.materials)
+            .expect("material names are checked when the scene is loaded")
+    }
+}
@@ -0,0 +1,893 @@
+//  CPU reference renderer - a line by line port of shader.wgsl so GPU
+//  output can be checked against something that can be stepped through
+//  in a debugger. Function names follow the shader; keep the two in sync.
+
+use std::cell::Cell;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::animation::Animation;
+use crate::background::{Background, BackgroundKind};
+use crate::camera::{Camera, Projection, Stereo};
+use crate::color::{self, Output, OutputConfig};
+use crate::config::SceneConfig;
+use crate::denoise::{self, Aov, DenoiseConfig};
+use crate::environment::{env_uv, Environment};
+use crate::error::Result;
+use crate::light::{Light, LightKind, Lighting, Occlusion};
+use crate::march::{March, NormalMethod, Quality};
+use crate::material::{Material, Trace};
+use crate::math::{saturate, smoothstep, vec3, Vec3};
+use crate::pathtrace::PathTrace;
+use crate::sampling::SamplingConfig;
+use crate::slice::Slice;
+use crate::view::ViewMode;
+
+
+// Mirrors Result in the shader, material is an id into the table
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub dist: f32,
+    pub material: u32,
+}
+
+const BLACK: u32 = 0;
+const RED: u32 = 1;
+const GREEN: u32 = 2;
+
+const BACKGROUND: Hit = Hit { dist: -1.0, material: BLACK };
+
+// Same limit as MAX_RAYS in the shader
+const MAX_RAYS: usize = 8;
+
+const GRADIENT_LIMIT: f32 = 1.01;
+
+struct TraceRay {
+    origin: Vec3,
+    dir: Vec3,
+    weight: Vec3,
+    depth: u32,
+    side: f32,
+    t_start: f32,
+    t_end: f32,
+}
+
+struct LightSample {
+    dir: Vec3,
+    dist: f32,
+    radiance: Vec3,
+}
+
+// The shader's random numbers, so a pixel follows the same paths here as
+// on the GPU until the float math drifts apart
+struct Rng(u32);
+
+impl Rng {
+    // seedRandom
+    fn new(pixel: [u32; 2], sample: u32) -> Self {
+        Self(pcg(pixel[0].wrapping_add(pcg(pixel[1].wrapping_add(pcg(sample))))))
+    }
+
+    // random
+    fn next(&mut self) -> f32 {
+        self.0 = pcg(self.0);
+        (self.0 >> 8) as f32 / 16777216.0
+    }
+}
+
+fn pcg(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+pub struct CpuRenderer {
+    march: March,
+    // March steps taken by the last render
+    steps: AtomicU64,
+    camera: Camera,
+    animation: Animation,
+    sampling: SamplingConfig,
+    path_trace: PathTrace,
+    denoise: DenoiseConfig,
+    lights: Vec<Light>,
+    lighting: Lighting,
+    occlusion: Occlusion,
+    view: ViewMode,
+    slice: Slice,
+    materials: Vec<Material>,
+    trace: Trace,
+    backdrop: Background,
+    env: Environment,
+    output: Output,
+}
+
+impl CpuRenderer {
+    pub fn new(config: &SceneConfig) -> Result<Self> {
+        let lights: Vec<Light> =
+            config.lights.iter().map(|l| l.to_shader()).collect();
+        let env = Environment::for_background(&config.background)?;
+        Ok(Self {
+            march: config.march.march(),
+            steps: AtomicU64::new(0),
+            camera: config.camera.to_shader(),
+            animation: config.animation.to_shader(0.0),
+            sampling: config.effective_sampling(),
+            path_trace: config.path_trace.to_shader(),
+            denoise: config.denoise.clone(),
+            lighting: config.ambient.to_shader(lights.len()),
+            lights,
+            occlusion: config.occlusion.to_shader(),
+            view: config.view,
+            slice: config.slice.to_shader(),
+            materials: config.material_table(),
+            trace: config.trace.to_shader(),
+            backdrop: config.background.to_shader(env.level_count()),
+            env,
+            output: config.output.to_shader(),
+        })
+    }
+
+    // Linear color for every pixel, row by row from the top left. Does
+    // every frame of a progressive render, averaged like the GPU does,
+    // then denoises if asked to.
+    pub fn render(&self, width: u32, height: u32) -> Vec<Vec3> {
+        self.steps.store(0, Ordering::Relaxed);
+        let frames = self.sampling.frames();
+        let pixel = [2.0 / width as f32, 2.0 / height as f32];
+        let mut pixels = vec![Vec3::ZERO; (width * height) as usize];
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get());
+        let rows_per_thread = (height as usize).div_ceil(threads);
+        std::thread::scope(|s| {
+            let chunks = pixels.chunks_mut(rows_per_thread * width as usize);
+            for (i, chunk) in chunks.enumerate() {
+                s.spawn(move || {
+                    set_march(self.march);
+                    STEPS.set(0);
+                    let first_row = i * rows_per_thread;
+                    for (j, pixel_color) in chunk.iter_mut().enumerate() {
+                        let x = (j % width as usize) as u32;
+                        let y = (first_row + j / width as usize) as u32;
+                        let uv = uv(x, y, width, height);
+                        let mut sum = Vec3::ZERO;
+                        for frame in 0..frames {
+                            sum = sum + self.fs_main(uv, pixel, [x, y], frame);
+                        }
+                        *pixel_color = sum / frames as f32;
+                    }
+                    self.steps.fetch_add(STEPS.get(), Ordering::Relaxed);
+                });
+            }
+        });
+        if self.denoise.enabled && self.view == ViewMode::Shaded {
+            set_march(self.march);
+            let aovs: Vec<Aov> = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| self.fs_aov(uv(x, y, width, height), pixel))
+                .collect();
+            pixels = denoise::denoise(&pixels, &aovs, width, height, &self.denoise);
+        }
+        pixels
+    }
+
+    // Steps march took in the last render, not counting shadow rays or
+    // the denoiser's
+    pub fn steps(&self) -> u64 {
+        self.steps.load(Ordering::Relaxed)
+    }
+
+    // setPixelAngle
+    fn set_pixel_angle(&self, pixel: [f32; 2]) {
+        let c = &self.camera;
+        let mut size = pixel[0].max(pixel[1]) * 0.5;
+        if c.stereo != Stereo::None as u32 { size *= 2.0; }
+        PIXEL_ANGLE.set(match c.projection {
+            p if p == Projection::Orthographic as u32 => 0.0,
+            p if p == Projection::Fisheye as u32 => size * c.fov * 0.5,
+            p if p == Projection::Equirectangular as u32 => size * PI * 0.5,
+            _ => size / c.focal,
+        });
+    }
+
+    // farDistance
+    fn far_distance(&self, ray_dir: Vec3) -> f32 {
+        let m = MARCH.get();
+        if m.far_plane <= 0.0 { return m.max_distance; }
+        if self.camera.projection <= Projection::Orthographic as u32 {
+            return m.far_plane / ray_dir.dot(self.camera_basis()[0]).max(1e-4);
+        }
+        m.far_plane
+    }
+
+    // Forward, right and up
+    fn camera_basis(&self) -> [Vec3; 3] {
+        let cam_forward =
+            (Vec3::from(self.camera.look_at) - self.camera.position.into()).normalize();
+        let cam_right = cam_forward.cross(vec3(0.0, 1.0, 0.0)).normalize();
+        let cam_up = cam_right.cross(cam_forward).normalize();
+        [cam_forward, cam_right, cam_up]
+    }
+
+    // Origin and direction
+    fn camera_ray(&self, xy: [f32; 2]) -> (Vec3, Vec3) {
+        let c = &self.camera;
+        let [forward, right, up] = self.camera_basis();
+
+        let mut uv = xy;
+        let mut eye = 0.0;
+        if c.stereo == Stereo::SideBySide as u32 {
+            eye = if xy[0] < 0.0 { -0.5 } else { 0.5 };
+            uv[0] = xy[0] * 2.0 + if xy[0] < 0.0 { 1.0 } else { -1.0 };
+        } else if c.stereo == Stereo::TopBottom as u32 {
+            eye = if xy[1] > 0.0 { -0.5 } else { 0.5 };
+            uv[1] = xy[1] * 2.0 + if xy[1] > 0.0 { -1.0 } else { 1.0 };
+        }
+
+        let mut origin = Vec3::from(c.position);
+        let mut side = right;
+        let dir = match c.projection {
+            p if p == Projection::Orthographic as u32 => {
+                origin = origin + (right * uv[0] + up * uv[1]) * (c.view_height * 0.5);
+                forward
+            }
+            p if p == Projection::Fisheye as u32 => {
+                let r = (uv[0] * uv[0] + uv[1] * uv[1]).sqrt().max(1e-6);
+                let theta = r * c.fov * 0.5;
+                forward * theta.cos() + (right * uv[0] + up * uv[1]) * (theta.sin() / r)
+            }
+            p if p == Projection::Equirectangular as u32 => {
+                let longitude = uv[0] * PI;
+                let latitude = uv[1] * PI * 0.5;
+                let around = forward * longitude.cos() + right * longitude.sin();
+                side = right * longitude.cos() - forward * longitude.sin();
+                around * latitude.cos() + up * latitude.sin()
+            }
+            _ => (right * uv[0] + up * uv[1] + forward * c.focal).normalize(),
+        };
+        (origin + side * (eye * c.eye_separation), dir)
+    }
+
+    fn lens_ray(&self, uv: [f32; 2], lens: [f32; 2]) -> (Vec3, Vec3) {
+        let c = &self.camera;
+        let (ray_origin, ray_dir) = self.camera_ray(uv);
+        if c.aperture <= 0.0 { return (ray_origin, ray_dir); }
+
+        let [forward, right, up] = self.camera_basis();
+        let mut dist = c.focus_distance;
+        let [mut lens_u, mut lens_v] = [right, up];
+        if c.projection <= Projection::Orthographic as u32 {
+            dist /= ray_dir.dot(forward);
+        } else {
+            [lens_u, lens_v] = perpendicular(ray_dir);
+        }
+        let focus = ray_origin + ray_dir * dist;
+        let r = c.aperture * lens[0].sqrt();
+        let phi = 2.0 * PI * lens[1];
+        let origin = ray_origin + (lens_u * phi.cos() + lens_v * phi.sin()) * r;
+        (origin, (focus - origin).normalize())
+    }
+
+    fn fs_aov(&self, uv: [f32; 2], pixel: [f32; 2]) -> Aov {
+        set_scene_time(&self.animation, self.animation.time);
+        self.set_pixel_angle(pixel);
+        let (origin, dir) = self.camera_ray(uv);
+        let t = march(
+            origin, dir, MARCH.get().start_distance, self.far_distance(dir), 1.0);
+        if t.dist == -1.0 { return Aov::default(); }
+        let m = &self.materials[t.material as usize];
+        Aov {
+            normal: calc_normal(origin + dir * t.dist, t.dist),
+            depth: t.dist,
+            albedo: vec3(m.color[0], m.color[1], m.color[2]),
+        }
+    }
+
+    // What the default post chain, a lone tonemap pass, does to a color
+    // rendered with render
+    pub fn encode(&self, color: Vec3) -> [u8; 3] {
+        // Debug views are already display values
+        if self.view != ViewMode::Shaded {
+            return color::encode(color, &OutputConfig::raw().to_shader());
+        }
+        color::encode(color, &self.output)
+    }
+
+    // pixel is the size of a pixel in uv, coord is its position in pixels
+    fn fs_main(
+        &self, uv: [f32; 2], pixel: [f32; 2], coord: [u32; 2], frame: u32,
+    ) -> Vec3 {
+        let sampling = self.sampling.to_shader(frame);
+        self.set_pixel_angle(pixel);
+        let n = sampling.samples;
+        // Offset from the pixel center of grid cell k
+        let offset = |k: u32, jitter: f32| (k as f32 + 0.5 + jitter) / n as f32 - 0.5;
+        let mut color = Vec3::ZERO;
+        for j in 0..n {
+            for i in 0..n {
+                let uv = [
+                    uv[0] + offset(i, sampling.jitter[0]) * pixel[0],
+                    uv[1] - offset(j, sampling.jitter[1]) * pixel[1],
+                ];
+                let mut rng = Rng::new(coord, (sampling.frame * n + j) * n + i);
+                let lens = [rng.next(), rng.next()];
+                set_scene_time(
+                    &self.animation,
+                    self.animation.time + rng.next() * self.camera.shutter);
+                let (origin, dir) = self.lens_ray(uv, lens);
+                if self.view != ViewMode::Shaded {
+                    color = color + self.debug_view(origin, dir);
+                } else if self.path_trace.enabled != 0 {
+                    color = color + self.path_trace(origin, dir, &mut rng);
+                } else {
+                    color = color + self.render_ray(origin, dir);
+                }
+            }
+        }
+        color / (n * n) as f32
+    }
+
+    fn shadow_mask(&self, pos: Vec3, n: Vec3) -> f32 {
+        let count = self.lighting.light_count;
+        if count == 0 { return 1.0; }
+        let shadow_ray_origin = pos + n * 0.01;
+        let mut lit = 0.0;
+        for light in &self.lights[..count as usize] {
+            let ls = sample_light(light, pos);
+            if n.dot(ls.dir) <= 0.0 { continue; }
+            lit += shadow(light, shadow_ray_origin, &ls);
+        }
+        lit / count as f32
+    }
+
+    fn slice_view(&self, ray_origin: Vec3, ray_dir: Vec3) -> Vec3 {
+        let s = &self.slice;
+        let normal = Vec3::from(s.normal);
+        let facing = ray_dir.dot(normal);
+        let t = (s.offset - ray_origin.dot(normal)) / facing;
+        if facing.abs() < 1e-6 || t <= 0.0 { return Vec3::ZERO; }
+
+        let p = ray_origin + ray_dir * t;
+        let d = the_shape(p).dist;
+        let mut color = if d > 0.0 { vec3(0.9, 0.6, 0.3) } else { vec3(0.4, 0.7, 1.0) };
+        color = color * (1.0 - 0.7 * (-4.0 * d.abs()).exp());
+
+        let width = (PIXEL_ANGLE.get() * t).max(0.002) * 1.5;
+        let x = d / s.spacing + 0.5;
+        let to_line = (x - x.floor() - 0.5).abs() * s.spacing;
+        color = color * (1.0 - 0.6 * (1.0 - smoothstep(0.0, width, to_line)));
+
+        let e = MARCH.get().normal_epsilon;
+        let f = |p: Vec3| the_shape(p).dist;
+        let [ex, ey, ez] = [vec3(e, 0.0, 0.0), vec3(0.0, e, 0.0), vec3(0.0, 0.0, e)];
+        let gradient = vec3(
+            f(p + ex) - f(p - ex),
+            f(p + ey) - f(p - ey),
+            f(p + ez) - f(p - ez),
+        ) / (2.0 * e);
+        if gradient.length() > GRADIENT_LIMIT {
+            color = color.mix(vec3(1.0, 0.0, 1.0), 0.75);
+        }
+
+        color.mix(Vec3::splat(1.0), 1.0 - smoothstep(0.0, width * 2.0, d.abs()))
+    }
+
+    fn debug_view(&self, ray_origin: Vec3, ray_dir: Vec3) -> Vec3 {
+        if self.view == ViewMode::Slice { return self.slice_view(ray_origin, ray_dir); }
+        let t = march(
+            ray_origin, ray_dir, MARCH.get().start_distance,
+            self.far_distance(ray_dir), 1.0);
+        let (steps, closest) = LAST_MARCH.get();
+        if self.view == ViewMode::Steps {
+            return heat(steps as f32 / MARCH.get().max_steps as f32);
+        }
+        if t.dist == -1.0 {
+            return match self.view {
+                ViewMode::Occlusion => self.background_color(ray_dir),
+                ViewMode::MissDistance =>
+                    heat(-closest.max(1e-6).log2() / 1e6f32.log2()),
+                _ => Vec3::ZERO,
+            };
+        }
+
+        let pos = hit_point(ray_origin, ray_dir, t.dist);
+        let n = calc_normal(pos, t.dist);
+        match self.view {
+            ViewMode::Occlusion => Vec3::splat(self.calc_ao(pos, n)),
+            ViewMode::Normals => n * 0.5 + Vec3::splat(0.5),
+            ViewMode::Depth => Vec3::splat(1.0 / (1.0 + 0.2 * t.dist)),
+            ViewMode::Material => {
+                let hue = (t.material as f32 * 0.618034).fract();
+                let phase = |k: f32| 0.5 + 0.5 * (2.0 * PI * (hue + k / 3.0)).cos();
+                vec3(phase(0.0), phase(1.0), phase(2.0))
+            }
+            ViewMode::Shadow => Vec3::splat(self.shadow_mask(pos, n)),
+            _ => Vec3::splat(0.2),
+        }
+    }
+
+    fn render_ray(&self, ray_origin: Vec3, ray_dir: Vec3) -> Vec3 {
+        let mut color = Vec3::ZERO;
+        let mut stack = Vec::with_capacity(MAX_RAYS);
+        stack.push(TraceRay {
+            origin: ray_origin, dir: ray_dir, weight: Vec3::splat(1.0),
+            depth: 0, side: 1.0, t_start: MARCH.get().start_distance,
+            t_end: self.far_distance(ray_dir),
+        });
+
+        while let Some(ray) = stack.pop() {
+            let t = march(ray.origin, ray.dir, ray.t_start, ray.t_end, ray.side);
+            let m = &self.materials[t.material as usize];
+            let m_color = vec3(m.color[0], m.color[1], m.color[2]);
+
+            if t.dist == -1.0 {
+                color = color + ray.weight * self.background_color(ray.dir);
+                continue;
+            }
+
+            let pos = hit_point(ray.origin, ray.dir, t.dist);
+            let n = calc_normal(pos, t.dist);
+
+            let facing = n * ray.side;
+            let mut kr = 0.0;
+            let mut kt = 0.0;
+            if m.reflectivity > 0.0 || m.transparency > 0.0 {
+                let f = fresnel(saturate(-ray.dir.dot(facing)), m.ior);
+                kr = m.reflectivity + (1.0 - m.reflectivity) * f;
+                kt = m.transparency * (1.0 - kr);
+            }
+
+            let local = 1.0 - kr - kt;
+            if local > 0.0 && ray.side > 0.0 {
+                color = color + ray.weight * self.shade(pos, n, m_color) * local;
+            }
+            if ray.side > 0.0 {
+                color = color + ray.weight * Vec3::from(m.emission);
+            }
+
+            if ray.depth >= self.trace.max_bounces { continue; }
+
+            if kt > 0.0 {
+                let eta = if ray.side < 0.0 { m.ior } else { 1.0 / m.ior };
+                let refracted = ray.dir.refract(facing, eta);
+                if refracted == Vec3::ZERO {
+                    kr += kt;
+                } else if stack.len() < MAX_RAYS {
+                    let tint =
+                        if ray.side > 0.0 { m_color } else { Vec3::splat(1.0) };
+                    stack.push(TraceRay {
+                        origin: pos - facing * 0.01, dir: refracted,
+                        weight: ray.weight * tint * kt,
+                        depth: ray.depth + 1, side: -ray.side, t_start: 0.0,
+                        t_end: MARCH.get().max_distance,
+                    });
+                }
+            }
+            if kr > 0.0 && stack.len() < MAX_RAYS {
+                let tint =
+                    if m.reflectivity > 0.0 { m_color } else { Vec3::splat(1.0) };
+                stack.push(TraceRay {
+                    origin: pos + facing * 0.01, dir: ray.dir.reflect(facing),
+                    weight: ray.weight * tint * kr,
+                    depth: ray.depth + 1, side: ray.side, t_start: 0.0,
+                    t_end: MARCH.get().max_distance,
+                });
+            }
+        }
+        color
+    }
+
+    fn direct_light(&self, pos: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        let mut color = Vec3::ZERO;
+        let shadow_ray_origin = pos + n * 0.01;
+        for light in &self.lights[..self.lighting.light_count as usize] {
+            let ls = sample_light(light, pos);
+            let no_l = n.dot(ls.dir).max(0.0);
+            if no_l <= 0.0 || ls.radiance == Vec3::ZERO { continue; }
+            let visible = shadow(light, shadow_ray_origin, &ls);
+            color = color + albedo * ls.radiance * no_l * visible;
+        }
+        color
+    }
+
+    fn path_trace(&self, ray_origin: Vec3, ray_dir: Vec3, rng: &mut Rng) -> Vec3 {
+        let mut color = Vec3::ZERO;
+        let mut throughput = Vec3::splat(1.0);
+        let mut origin = ray_origin;
+        let mut dir = ray_dir;
+        let mut side = 1.0;
+        let mut t_start = MARCH.get().start_distance;
+        let mut t_end = self.far_distance(ray_dir);
+
+        for bounce in 0..=self.path_trace.max_bounces {
+            let t = march(origin, dir, t_start, t_end, side);
+            if t.dist == -1.0 {
+                color = color + throughput * self.background_color(dir);
+                break;
+            }
+
+            let m = &self.materials[t.material as usize];
+            let m_color = vec3(m.color[0], m.color[1], m.color[2]);
+            let pos = hit_point(origin, dir, t.dist);
+            let n = calc_normal(pos, t.dist);
+            let facing = n * side;
+            if side > 0.0 {
+                color = color + throughput * Vec3::from(m.emission);
+            }
+
+            let mut kr = 0.0;
+            let mut kt = 0.0;
+            if m.reflectivity > 0.0 || m.transparency > 0.0 {
+                let f = fresnel(saturate(-dir.dot(facing)), m.ior);
+                kr = m.reflectivity + (1.0 - m.reflectivity) * f;
+                kt = m.transparency * (1.0 - kr);
+            }
+            let mirror_tint =
+                if m.reflectivity > 0.0 { m_color } else { Vec3::splat(1.0) };
+
+            let choice = rng.next();
+            if choice < kt {
+                let eta = if side < 0.0 { m.ior } else { 1.0 / m.ior };
+                let refracted = dir.refract(facing, eta);
+                if refracted == Vec3::ZERO {
+                    throughput = throughput * mirror_tint;
+                    dir = dir.reflect(facing);
+                    origin = pos + facing * 0.01;
+                } else {
+                    if side > 0.0 { throughput = throughput * m_color; }
+                    dir = refracted;
+                    origin = pos - facing * 0.01;
+                    side = -side;
+                }
+            } else if choice < kt + kr {
+                throughput = throughput * mirror_tint;
+                dir = dir.reflect(facing);
+                origin = pos + facing * 0.01;
+            } else {
+                if side < 0.0 { break; }
+                color = color + throughput * self.direct_light(pos, n, m_color);
+                throughput = throughput * m_color;
+                dir = cosine_sample(n, rng);
+                origin = pos + n * 0.01;
+            }
+            t_start = 0.0;
+            t_end = MARCH.get().max_distance;
+
+            if bounce >= self.path_trace.roulette_start {
+                let p = throughput.max_element().clamp(0.05, 1.0);
+                if rng.next() >= p { break; }
+                throughput = throughput / p;
+            }
+        }
+        color
+    }
+
+    fn environment(&self, dir: Vec3, level: f32) -> Vec3 {
+        self.env.sample(env_uv(dir, self.backdrop.rotation), level)
+    }
+
+    fn sky(&self, dir: Vec3, with_sun: bool) -> Vec3 {
+        let b = &self.backdrop;
+        let horizon = Vec3::from(b.color);
+        let mut color = horizon.mix(b.top.into(), dir.y.max(0.0).sqrt());
+        if dir.y < 0.0 {
+            color = color * (1.0 + (0.3 - 1.0) * saturate(-dir.y * 4.0));
+        }
+        let mu = dir.dot(b.sun_direction.into()).max(0.0);
+        let sun = Vec3::from(b.sun_color);
+        color = color + sun * 0.002 * mu.powi(32);
+        if with_sun {
+            color = color + sun * smoothstep(b.sun_cos - 0.00005, b.sun_cos, mu);
+        }
+        color
+    }
+
+    fn background_color(&self, dir: Vec3) -> Vec3 {
+        let b = &self.backdrop;
+        let color = match self.background_kind() {
+            BackgroundKind::Solid => b.color.into(),
+            BackgroundKind::Gradient => Vec3::from(b.color)
+                .mix(b.top.into(), dir.y * 0.5 + 0.5),
+            BackgroundKind::Sky => self.sky(dir, true),
+            BackgroundKind::Environment => self.environment(dir, 0.0),
+        };
+        color * b.intensity
+    }
+
+    fn background_ambient(&self, n: Vec3) -> Vec3 {
+        let b = &self.backdrop;
+        let color = match self.background_kind() {
+            BackgroundKind::Solid => b.color.into(),
+            BackgroundKind::Gradient => Vec3::from(b.color)
+                .mix(b.top.into(), n.y * 0.5 + 0.5),
+            BackgroundKind::Sky => self.sky(n, false),
+            BackgroundKind::Environment =>
+                self.environment(n, (b.env_levels - 4.0).max(0.0)),
+        };
+        color * b.intensity * b.ambient
+    }
+
+    fn background_kind(&self) -> BackgroundKind {
+        match self.backdrop.kind {
+            1 => BackgroundKind::Gradient,
+            2 => BackgroundKind::Sky,
+            3 => BackgroundKind::Environment,
+            _ => BackgroundKind::Solid,
+        }
+    }
+
+    fn shade(&self, pos: Vec3, n: Vec3, surface: Vec3) -> Vec3 {
+        let ambient = self.hemisphere(n) + self.background_ambient(n);
+        let mut color = surface * ambient * self.calc_ao(pos, n);
+
+        let shadow_ray_origin = pos + n * 0.01;
+        for light in &self.lights[..self.lighting.light_count as usize] {
+            let ls = sample_light(light, pos);
+            let no_l = n.dot(ls.dir).max(0.0);
+            if no_l <= 0.0 || ls.radiance == Vec3::ZERO { continue; }
+            let visible = shadow(light, shadow_ray_origin, &ls);
+            color = color + surface * ls.radiance * no_l * visible;
+        }
+        color
+    }
+
+    fn calc_ao(&self, pos: Vec3, n: Vec3) -> f32 {
+        let mut occ = 0.0;
+        let mut weight = 1.0;
+        for i in 1..=self.occlusion.samples {
+            let h = self.occlusion.step * i as f32;
+            let d = the_shape(pos + n * h).dist;
+            occ += (h - d) * weight;
+            weight *= 0.5;
+        }
+        saturate(1.0 - self.occlusion.strength * occ)
+    }
+
+    fn hemisphere(&self, n: Vec3) -> Vec3 {
+        let up = n.y * 0.5 + 0.5;
+        Vec3::from(self.lighting.ground)
+            .mix(self.lighting.sky.into(), up) * self.lighting.intensity
+    }
+}
+
+// Pixel center to the -1..1 coordinates the vertex shader interpolates,
+// y is up.
+pub fn uv(x: u32, y: u32, width: u32, height: u32) -> [f32; 2] {
+    [
+        (x as f32 + 0.5) / width as f32 * 2.0 - 1.0,
+        1.0 - (y as f32 + 0.5) / height as f32 * 2.0,
+    ]
+}
+
+
+fn heat(x: f32) -> Vec3 {
+    let x4 = saturate(x) * 4.0;
+    let band = |c: f32| saturate(1.5 - (x4 - c).abs());
+    vec3(band(3.0), band(2.0), band(1.0))
+}
+
+fn perpendicular(n: Vec3) -> [Vec3; 2] {
+    let s = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    [
+        vec3(1.0 + s * n.x * n.x * a, s * b, -s * n.x),
+        vec3(b, s + n.y * n.y * a, -n.y),
+    ]
+}
+
+fn cosine_sample(n: Vec3, rng: &mut Rng) -> Vec3 {
+    let phi = 2.0 * PI * rng.next();
+    let r2 = rng.next();
+    let r = r2.sqrt();
+    let [u, v] = perpendicular(n);
+    (u * (r * phi.cos()) + v * (r * phi.sin()) + n * (1.0 - r2).sqrt()).normalize()
+}
+
+thread_local! {
+    // The shader's override constants, set by each thread that marches
+    static MARCH: Cell<March> = const { Cell::new(Quality::Normal.march()) };
+    static PIXEL_ANGLE: Cell<f32> = const { Cell::new(0.0) };
+    // Steps taken by march on this thread
+    static STEPS: Cell<u64> = const { Cell::new(0) };
+    // marchSteps and marchClosest
+    static LAST_MARCH: Cell<(u32, f32)> = const { Cell::new((0, 0.0)) };
+}
+
+pub fn set_march(march: March) {
+    MARCH.set(march);
+}
+
+pub fn march(
+    ray_origin: Vec3, ray_dir: Vec3, t_start: f32, t_end: f32, side: f32,
+) -> Hit {
+    let m = MARCH.get();
+    let mut t = t_start;
+    let mut omega = m.relaxation;
+    let mut last_dist = 0.0;
+    let mut step = 0.0;
+    let threshold = m.epsilon.max(m.cone * PIXEL_ANGLE.get());
+    LAST_MARCH.set((0, 1e10));
+    for _ in 0..m.max_steps {
+        if t >= t_end { break; }
+        STEPS.set(STEPS.get() + 1);
+        let res = the_shape(ray_origin + ray_dir * t);
+        let d = side * res.dist;
+        let (steps, closest) = LAST_MARCH.get();
+        LAST_MARCH.set((steps + 1, closest.min(d)));
+        if omega > 1.0 && d.abs() + last_dist < step {
+            step -= omega * step;
+            omega = 1.0;
+        } else {
+            if d < threshold * t { return Hit { dist: t, ..res }; }
+            step = d * omega;
+        }
+        last_dist = d.abs();
+        t += step;
+    }
+    BACKGROUND
+}
+
+pub fn hit_point(ray_origin: Vec3, ray_dir: Vec3, t: f32) -> Vec3 {
+    let pos = ray_origin + ray_dir * t;
+    if MARCH.get().cone <= 0.0 { return pos; }
+    pos - calc_normal(pos, t) * the_shape(pos).dist
+}
+
+fn fresnel(cos_theta: f32, ior: f32) -> f32 {
+    let r0 = ((1.0 - ior) / (1.0 + ior)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+pub fn calc_normal(pos: Vec3, t: f32) -> Vec3 {
+    let m = MARCH.get();
+    let f = |p: Vec3| the_shape(p).dist;
+    let eps = m.normal_epsilon.max(m.normal_scale * t);
+    let [x, y, z] = [vec3(eps, 0.0, 0.0), vec3(0.0, eps, 0.0), vec3(0.0, 0.0, eps)];
+    match m.normal_method {
+        NormalMethod::Analytic => the_gradient(pos).normalize(),
+        NormalMethod::Central => vec3(
+            f(pos + x) - f(pos - x),
+            f(pos + y) - f(pos - y),
+            f(pos + z) - f(pos - z),
+        ).normalize(),
+        NormalMethod::Tetrahedral => {
+            let [a, b, c, d] = [
+                vec3(1.0, -1.0, -1.0), vec3(-1.0, -1.0, 1.0),
+                vec3(-1.0, 1.0, -1.0), vec3(1.0, 1.0, 1.0),
+            ];
+            (a * f(pos + a * eps) + b * f(pos + b * eps)
+                + c * f(pos + c * eps) + d * f(pos + d * eps)).normalize()
+        }
+        NormalMethod::Forward => {
+            let c = f(pos);
+            (vec3(f(pos + x), f(pos + y), f(pos + z)) - Vec3::splat(c)).normalize()
+        }
+    }
+}
+
+fn sample_light(light: &Light, pos: Vec3) -> LightSample {
+    let radiance = Vec3::from(light.color) * light.intensity;
+    let direction = Vec3::from(light.direction);
+    if light.kind == LightKind::Directional as u32 {
+        return LightSample { dir: -direction, dist: 1e10, radiance };
+    }
+    let to_light = Vec3::from(light.position) - pos;
+    let dist = to_light.length();
+    let dir = to_light / dist;
+    let window = saturate(1.0 - (dist / light.range).powi(4));
+    let mut falloff = window * window / (dist * dist + 1.0);
+    if light.kind == LightKind::Spot as u32 {
+        falloff *= smoothstep(
+            light.cos_outer, light.cos_inner, (-dir).dot(direction));
+    }
+    LightSample { dir, dist, radiance: radiance * falloff }
+}
+
+fn shadow_ray(ray_origin: Vec3, ray_dir: Vec3, max_dist: f32) -> f32 {
+    let m = MARCH.get();
+    let mut t = 0.0;
+    for _ in 0..m.max_steps {
+        if t >= max_dist { break; }
+        let d = the_shape(ray_origin + ray_dir * t).dist;
+        if d < m.epsilon { return 0.0; }
+        t += d;
+    }
+    1.0
+}
+
+fn soft_shadow(
+    ray_origin: Vec3, ray_dir: Vec3, max_dist: f32, softness: f32,
+) -> f32 {
+    let mut res: f32 = 1.0;
+    let mut t = 0.0;
+    let mut last_dist = 1e10;
+    let m = MARCH.get();
+    for _ in 0..m.max_steps {
+        if t >= max_dist { break; }
+        let d = the_shape(ray_origin + ray_dir * t).dist;
+        if d < m.epsilon { return 0.0; }
+        let y = d * d / (2.0 * last_dist);
+        let closest = (d * d - y * y).max(0.0).sqrt();
+        res = res.min(closest / (softness * (t - y).max(0.0001)));
+        last_dist = d;
+        t += d;
+    }
+    let res = saturate(res);
+    res * res * (3.0 - 2.0 * res)
+}
+
+fn shadow(light: &Light, ray_origin: Vec3, ls: &LightSample) -> f32 {
+    if light.shadows == 0 { return 1.0; }
+    let max_dist = ls.dist.min(light.shadow_distance);
+    if light.softness <= 0.0 {
+        return shadow_ray(ray_origin, ls.dir, max_dist);
+    }
+    soft_shadow(ray_origin, ls.dir, max_dist, light.softness)
+}
+
+//////////////////////////////////////////////////////////////////////////
+//
+//  Shapes - only the ones theShape currently uses
+//
+//////////////////////////////////////////////////////////////////////////
+
+thread_local! {
+    // animation.spin * sceneTime in the shader, the angle the scene is
+    // turned by for the ray being marched on this thread
+    static SCENE_ANGLE: Cell<f32> = const { Cell::new(0.0) };
+}
+
+fn set_scene_time(animation: &Animation, time: f32) {
+    SCENE_ANGLE.set(-animation.spin * time);
+}
+
+fn spin(p: Vec3) -> Vec3 {
+    let a = SCENE_ANGLE.get();
+    vec3(a.cos() * p.x - a.sin() * p.z, p.y, a.sin() * p.x + a.cos() * p.z)
+}
+
+fn unspin(v: Vec3) -> Vec3 {
+    let a = SCENE_ANGLE.get();
+    vec3(a.cos() * v.x + a.sin() * v.z, v.y, -a.sin() * v.x + a.cos() * v.z)
+}
+
+pub fn the_shape(p: Vec3) -> Hit { shape7(spin(p)) }
+
+pub fn the_gradient(p: Vec3) -> Vec3 { unspin(shape7_gradient(spin(p)).grad) }
+
+struct Gradient {
+    dist: f32,
+    grad: Vec3,
+}
+
+fn shape7_gradient(p: Vec3) -> Gradient {
+    let p1 = p + Vec3::splat(0.5);
+    let p2 = p - Vec3::splat(0.5);
+    unions_gradient(sphere_gradient(p1, 0.5), sphere_gradient(p2, 0.5))
+}
+
+fn sphere_gradient(p: Vec3, radius: f32) -> Gradient {
+    Gradient { dist: sphere(p, radius), grad: p / p.length().max(1e-6) }
+}
+
+fn unions_gradient(g1: Gradient, g2: Gradient) -> Gradient {
+    if g1.dist < g2.dist { g1 } else { g2 }
+}
+
+fn shape7(p: Vec3) -> Hit {
+    let p1 = p + Vec3::splat(0.5);
+    let p2 = p - Vec3::splat(0.5);
+    unions(
+        Hit { dist: sphere(p1, 0.5), material: RED },
+        Hit { dist: sphere(p2, 0.5), material: GREEN },
+    )
+}
+
+fn sphere(p: Vec3, radius: f32) -> f32 {
+    p.length() - radius
+}
+
+fn unions(c1: Hit, c2: Hit) -> Hit {
+    if c1.dist < c2.dist { c1 } else { c2 }
+}
@@ -0,0 +1,179 @@
+//  Edge-avoiding à-trous wavelet denoiser (Dammertz et al. 2010). Each
+//  iteration blurs with a 5x5 B3 spline kernel whose taps are spread
+//  twice as far as the last one's, weighted down where the color,
+//  normal, depth or albedo of a tap differs from the center pixel's.
+//  The GPU runs it as compute passes in the post chain and the CPU
+//  renderer calls denoise on its output; keep the two in sync.
+
+use serde::Deserialize;
+
+use crate::math::Vec3;
+use crate::uniform::ShaderType;
+
+//  [denoise]
+//  enabled = true
+//  strength = 0.5
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DenoiseConfig {
+    pub enabled: bool,
+    // Each one doubles the filter's reach, 5 covers 61x61 pixels. At
+    // most MAX_ITERATIONS are run.
+    pub iterations: u32,
+    // How different two colors can be and still be averaged, halved
+    // every iteration. Bigger is smoother and blurrier.
+    pub strength: f32,
+    // Sharpness of the edge stopping on normals, depth and albedo
+    pub normal_power: f32,
+    pub depth_sigma: f32,
+    pub albedo_sigma: f32,
+}
+
+impl Default for DenoiseConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            iterations: 5,
+            strength: 1.0,
+            normal_power: 64.0,
+            depth_sigma: 0.05,
+            albedo_sigma: 0.1,
+        }
+    }
+}
+
+impl DenoiseConfig {
+    // Taps 256 pixels apart, past which more iterations only smear the
+    // image and the spacing soon overflows the shader's i32
+    pub const MAX_ITERATIONS: u32 = 8;
+
+    // How many iterations are actually run
+    pub fn iteration_count(&self) -> u32 {
+        self.iterations.min(Self::MAX_ITERATIONS)
+    }
+
+    pub fn to_shader(&self, iteration: u32) -> Denoise {
+        let iteration = iteration.min(Self::MAX_ITERATIONS - 1);
+        Denoise {
+            step: 1 << iteration,
+            color_sigma: (self.strength * 0.5f32.powi(iteration as i32)).max(1e-4),
+            normal_power: self.normal_power,
+            depth_sigma: self.depth_sigma.max(1e-4),
+            albedo_sigma: self.albedo_sigma.max(1e-4),
+            _pad: [0.0; 3],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Denoise {
+    // Pixels between taps
+    pub step: i32,
+    pub color_sigma: f32,
+    pub normal_power: f32,
+    pub depth_sigma: f32,
+    pub albedo_sigma: f32,
+    pub _pad: [f32; 3],
+}
+
+impl ShaderType for Denoise {
+    const WGSL_TYPE: &'static str = "Denoise";
+    const WGSL_STRUCT: &'static str = "
+struct Denoise {
+    step: i32,
+    color_sigma: f32,
+    normal_power: f32,
+    depth_sigma: f32,
+    albedo_sigma: f32,
+    _pad0: f32,
+    _pad1: f32,
+    _pad2: f32,
+}
+";
+}
+
+// What the first hit under a pixel center looks like, the AOVs the
+// filter is guided by. depth is 0 where the ray missed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aov {
+    pub normal: Vec3,
+    pub depth: f32,
+    pub albedo: Vec3,
+}
+
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Filters color, both row by row from the top left
+pub fn denoise(
+    color: &[Vec3],
+    aovs: &[Aov],
+    width: u32,
+    height: u32,
+    config: &DenoiseConfig,
+) -> Vec<Vec3> {
+    let mut color = color.to_vec();
+    for iteration in 0..config.iteration_count() {
+        let params = config.to_shader(iteration);
+        color = (0..height as i32)
+            .flat_map(|y| (0..width as i32).map(move |x| (x, y)))
+            .map(|(x, y)| filter(&color, aovs, width as i32, height as i32, x, y, &params))
+            .collect();
+    }
+    color
+}
+
+// One output pixel of one iteration, cs_main in denoise.wgsl
+fn filter(
+    color: &[Vec3],
+    aovs: &[Aov],
+    width: i32,
+    height: i32,
+    x: i32,
+    y: i32,
+    params: &Denoise,
+) -> Vec3 {
+    let index = |x: i32, y: i32| (y * width + x) as usize;
+    let c = color[index(x, y)];
+    let p = aovs[index(x, y)];
+    // Misses show the background, which has no noise to remove
+    if p.depth == 0.0 { return c; }
+
+    let mut sum = Vec3::ZERO;
+    let mut weights = 0.0;
+    for (j, ky) in KERNEL.iter().enumerate() {
+        for (i, kx) in KERNEL.iter().enumerate() {
+            let qx = x + (i as i32 - 2) * params.step;
+            let qy = y + (j as i32 - 2) * params.step;
+            if qx < 0 || qy < 0 || qx >= width || qy >= height { continue; }
+
+            let cq = color[index(qx, qy)];
+            let q = aovs[index(qx, qy)];
+            if q.depth == 0.0 { continue; }
+            let dc = c - cq;
+            let wc = (-dc.dot(dc) / (params.color_sigma * params.color_sigma)).exp();
+            let wn = p.normal.dot(q.normal).max(0.0).powf(params.normal_power);
+            let wd = (-(p.depth - q.depth).abs()
+                / (params.depth_sigma * params.step as f32)).exp();
+            let da = p.albedo - q.albedo;
+            let wa = (-da.dot(da) / (params.albedo_sigma * params.albedo_sigma)).exp();
+
+            let w = kx * ky * wc * wn * wd * wa;
+            sum = sum + cq * w;
+            weights += w;
+        }
+    }
+    if weights > 0.0 { sum / weights } else { c }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iterations_are_clamped_where_used() {
+        let config = DenoiseConfig { iterations: 40, ..Default::default() };
+        assert_eq!(config.iteration_count(), DenoiseConfig::MAX_ITERATIONS);
+        assert_eq!(config.to_shader(40).step, 1 << (DenoiseConfig::MAX_ITERATIONS - 1));
+    }
+}
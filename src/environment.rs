@@ -0,0 +1,210 @@
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+
+use wgpu::util::DeviceExt;
+
+use crate::background::{BackgroundConfig, BackgroundKind};
+use crate::error::{Error, Result};
+use crate::math::{vec3, Vec3};
+
+// An equirectangular environment map with a box filtered mip chain. The
+// GPU samples the texture made by upload, the CPU renderer samples the
+// same levels with sample so the two agree.
+pub struct Environment {
+    pub width: u32,
+    pub height: u32,
+    // Level 0 is the full image, each level after is half the size
+    pub levels: Vec<Vec<[f32; 4]>>,
+}
+
+impl Environment {
+    pub fn load(path: &Path) -> Result<Self> {
+        let (width, height, pixels) = crate::hdr::read_hdr(path)
+            .map_err(|source| Error::Io { path: path.to_path_buf(), source })?;
+        log::info!("environment {} is {width}x{height}", path.display());
+        let pixels = pixels.into_iter().map(|[r, g, b]| [r, g, b, 1.0]);
+        Ok(Self::new(width, height, pixels.collect()))
+    }
+
+    // The map a background needs, a black placeholder unless it's an
+    // environment background
+    pub fn for_background(config: &BackgroundConfig) -> Result<Self> {
+        match (&config.kind, &config.path) {
+            (BackgroundKind::Environment, Some(path)) => Self::load(path),
+            (BackgroundKind::Environment, None) => Err(Error::Io {
+                path: PathBuf::new(),
+                source: std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "environment background needs a path"),
+            }),
+            _ => Ok(Self::black()),
+        }
+    }
+
+    // Used when there's no environment, the shader still needs a texture
+    pub fn black() -> Self {
+        Self::new(1, 1, vec![[0.0, 0.0, 0.0, 1.0]])
+    }
+
+    fn new(width: u32, height: u32, pixels: Vec<[f32; 4]>) -> Self {
+        let mut levels = vec![pixels];
+        let (mut w, mut h) = (width, height);
+        while w > 1 || h > 1 {
+            let (nw, nh) = ((w / 2).max(1), (h / 2).max(1));
+            let prev = levels.last().unwrap();
+            let mut next = Vec::with_capacity((nw * nh) as usize);
+            for y in 0..nh {
+                for x in 0..nw {
+                    let mut sum = [0.0; 4];
+                    // Clamped so odd and 1 pixel wide levels still work
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let sx = (x * 2 + dx).min(w - 1);
+                        let sy = (y * 2 + dy).min(h - 1);
+                        let p = prev[(sy * w + sx) as usize];
+                        for c in 0..4 { sum[c] += p[c] * 0.25; }
+                    }
+                    next.push(sum);
+                }
+            }
+            levels.push(next);
+            (w, h) = (nw, nh);
+        }
+        Self { width, height, levels }
+    }
+
+    pub fn level_count(&self) -> u32 {
+        self.levels.len() as u32
+    }
+
+    fn level_size(&self, level: usize) -> (u32, u32) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    // Fails if the map is larger than the device takes as one texture
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<wgpu::Texture> {
+        let max = device.limits().max_texture_dimension_2d;
+        if self.width > max || self.height > max {
+            return Err(Error::TooLarge {
+                what: "environment map".into(),
+                size: format!("{}x{}", self.width, self.height),
+                max: format!("{max}x{max}"),
+            });
+        }
+        let data: Vec<half::f16> = self.levels.iter()
+            .flatten()
+            .flatten()
+            .map(|&c| half::f16::from_f32(c))
+            .collect();
+        Ok(device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("environment"),
+                size: wgpu::Extent3d {
+                    width: self.width,
+                    height: self.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: self.level_count(),
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba16Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(&data),
+        ))
+    }
+
+    // The sampler upload's texture is meant to be used with. Wraps
+    // around the horizon and clamps at the poles.
+    pub fn sampler(device: &wgpu::Device) -> wgpu::Sampler {
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("environment"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        })
+    }
+
+    // Trilinear lookup like textureSampleLevel with sampler
+    pub fn sample(&self, uv: [f32; 2], level: f32) -> Vec3 {
+        let max = (self.levels.len() - 1) as f32;
+        let level = level.clamp(0.0, max);
+        let l0 = level.floor() as usize;
+        let l1 = (l0 + 1).min(self.levels.len() - 1);
+        let a = self.bilinear(uv, l0);
+        let b = self.bilinear(uv, l1);
+        a.mix(b, level.fract())
+    }
+
+    fn bilinear(&self, uv: [f32; 2], level: usize) -> Vec3 {
+        let (w, h) = self.level_size(level);
+        let pixels = &self.levels[level];
+        let x = uv[0] * w as f32 - 0.5;
+        let y = uv[1] * h as f32 - 0.5;
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+        let texel = |x: i64, y: i64| {
+            let x = x.rem_euclid(w as i64) as u32;
+            let y = y.clamp(0, h as i64 - 1) as u32;
+            let p = pixels[(y * w + x) as usize];
+            vec3(p[0], p[1], p[2])
+        };
+        let (x, y) = (x.floor() as i64, y.floor() as i64);
+        let top = texel(x, y).mix(texel(x + 1, y), fx);
+        let bottom = texel(x, y + 1).mix(texel(x + 1, y + 1), fx);
+        top.mix(bottom, fy)
+    }
+}
+
+// Direction to equirectangular texture coordinates, must match envUV in
+// shader.wgsl. rotation turns the map about the vertical axis.
+pub fn env_uv(dir: Vec3, rotation: f32) -> [f32; 2] {
+    let u = dir.x.atan2(-dir.z) / (2.0 * PI) + 0.5 + rotation / (2.0 * PI);
+    let v = dir.y.clamp(-1.0, 1.0).acos() / PI;
+    [u, v]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: [f32; 2], b: [f32; 2]) -> bool {
+        (a[0] - b[0]).abs() < 1e-6 && (a[1] - b[1]).abs() < 1e-6
+    }
+
+    #[test]
+    fn forward_is_the_middle_of_the_map() {
+        assert!(close(env_uv(vec3(0.0, 0.0, -1.0), 0.0), [0.5, 0.5]));
+    }
+
+    #[test]
+    fn directions_around_the_horizon() {
+        assert!(close(env_uv(vec3(1.0, 0.0, 0.0), 0.0), [0.75, 0.5]));
+        assert!(close(env_uv(vec3(-1.0, 0.0, 0.0), 0.0), [0.25, 0.5]));
+        // Behind is the seam, either edge
+        let [u, _] = env_uv(vec3(0.0, 0.0, 1.0), 0.0);
+        assert!((u - 1.0).abs() < 1e-6 || u.abs() < 1e-6, "{u}");
+    }
+
+    #[test]
+    fn poles_are_the_top_and_bottom_rows() {
+        assert_eq!(env_uv(vec3(0.0, 1.0, 0.0), 0.0)[1], 0.0);
+        assert_eq!(env_uv(vec3(0.0, -1.0, 0.0), 0.0)[1], 1.0);
+        // Not quite unit length, still in range
+        assert_eq!(env_uv(vec3(0.0, 1.0 + 1e-6, 0.0), 0.0)[1], 0.0);
+    }
+
+    #[test]
+    fn rotation_turns_the_map() {
+        let dir = vec3(0.0, 0.0, -1.0);
+        assert!(close(env_uv(dir, PI / 2.0), [0.75, 0.5]));
+    }
+}
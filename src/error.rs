@@ -0,0 +1,100 @@
+//  What can go wrong setting up the GPU side, returned by the public
+//  constructors instead of panicking so a caller can say what happened.
+//  Mistakes in the crate itself, like setting a uniform by a name that
+//  was never added, still panic.
+
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error {
+    // Nothing that can draw, or present to the window
+    NoAdapter(wgpu::RequestAdapterError),
+    // [gpu] adapter matches none of them
+    AdapterNotFound { name: String, available: Vec<String> },
+    // [gpu] adapter with power or fallback, which only apply when wgpu
+    // picks
+    AdapterConflict,
+    RequestDevice(wgpu::RequestDeviceError),
+    CreateSurface(wgpu::CreateSurfaceError),
+    // A shader that doesn't parse or validate
+    Shader { label: String, message: String },
+    // A pipeline whose shader doesn't agree with the bindings made for it
+    Binding { label: String, message: String },
+    // More than the device takes in one texture, what is an image,
+    // environment map or lut
+    TooLarge { what: String, size: String, max: String },
+    Io { path: PathBuf, source: std::io::Error },
+    // A scene file that doesn't parse or names things that don't exist
+    Config { path: PathBuf, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoAdapter(e) => write!(f, "no usable GPU adapter: {e}"),
+            Error::AdapterNotFound { name, available } if available.is_empty() =>
+                write!(f, "no adapter named like {name}, or any others"),
+            Error::AdapterNotFound { name, available } => write!(
+                f, "no adapter named like {name}, there are: {}",
+                available.join(", ")),
+            Error::AdapterConflict => write!(
+                f, "an adapter picked by name can't have a power preference or be the fallback"),
+            Error::RequestDevice(e) => write!(f, "can't get a GPU device: {e}"),
+            Error::CreateSurface(e) => write!(f, "can't make a surface for the window: {e}"),
+            Error::Shader { label, message } =>
+                write!(f, "shader {label} doesn't compile: {message}"),
+            Error::Binding { label, message } =>
+                write!(f, "pipeline {label} doesn't match its bindings: {message}"),
+            Error::TooLarge { what, size, max } =>
+                write!(f, "{what} is {size}, more than the GPU's limit of {max}"),
+            Error::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::Config { path, message } => write!(f, "{}: {message}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::NoAdapter(e) => Some(e),
+            Error::RequestDevice(e) => Some(e),
+            Error::CreateSurface(e) => Some(e),
+            Error::Io { source, .. } => Some(source),
+            Error::AdapterNotFound { .. } | Error::AdapterConflict | Error::Shader { .. }
+                | Error::Binding { .. } | Error::TooLarge { .. } | Error::Config { .. } => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestAdapterError> for Error {
+    fn from(e: wgpu::RequestAdapterError) -> Self {
+        Error::NoAdapter(e)
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for Error {
+    fn from(e: wgpu::RequestDeviceError) -> Self {
+        Error::RequestDevice(e)
+    }
+}
+
+impl From<wgpu::CreateSurfaceError> for Error {
+    fn from(e: wgpu::CreateSurfaceError) -> Self {
+        Error::CreateSurface(e)
+    }
+}
+
+// Validation errors from whatever was made since the last
+// push_error_scope, as an Error made by wrap
+pub fn pop_scope(
+    device: &wgpu::Device,
+    wrap: impl FnOnce(String) -> Error,
+) -> Result<()> {
+    match pollster::block_on(device.pop_error_scope()) {
+        Some(error) => Err(wrap(error.to_string())),
+        None => Ok(()),
+    }
+}
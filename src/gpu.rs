@@ -0,0 +1,193 @@
+//  Which GPU to use and how to present to the window. Left alone wgpu
+//  picks, and its WGPU_BACKEND, WGPU_POWER_PREF and WGPU_ADAPTER_NAME
+//  environment variables still work; anything set here wins over them.
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+named_enum! {
+    // The graphics APIs wgpu can run on
+    pub enum Backend("backend") {
+        Vulkan = "vulkan",
+        Metal = "metal",
+        Dx12 = "dx12",
+        Gl = "gl",
+    }
+}
+
+impl Backend {
+    fn backends(self) -> wgpu::Backends {
+        match self {
+            Backend::Vulkan => wgpu::Backends::VULKAN,
+            Backend::Metal => wgpu::Backends::METAL,
+            Backend::Dx12 => wgpu::Backends::DX12,
+            Backend::Gl => wgpu::Backends::GL,
+        }
+    }
+}
+
+named_enum! {
+    #[derive(Default)]
+    pub enum Power("power preference") {
+        // Whatever wgpu picks
+        #[default]
+        Default = "default",
+        // An integrated GPU when there is one as well as a discrete one
+        Low = "low",
+        High = "high",
+    }
+}
+
+impl Power {
+    fn preference(self) -> wgpu::PowerPreference {
+        match self {
+            Power::Default => wgpu::PowerPreference::from_env()
+                .unwrap_or_default(),
+            Power::Low => wgpu::PowerPreference::LowPower,
+            Power::High => wgpu::PowerPreference::HighPerformance,
+        }
+    }
+}
+
+named_enum! {
+    // How frames are handed to the display. Each falls back to the next
+    // of its candidates the surface supports, ending with fifo which all
+    // do.
+    #[derive(Default)]
+    pub enum PresentMode("present mode") {
+        // Waits for vertical blank, no tearing
+        #[default]
+        Fifo = "fifo",
+        // Replaces the waiting frame with a newer one, no tearing and
+        // less latency than fifo
+        Mailbox = "mailbox",
+        // Shows frames straight away, tearing
+        Immediate = "immediate",
+    }
+}
+
+impl PresentMode {
+    // In order of preference
+    fn candidates(self) -> &'static [wgpu::PresentMode] {
+        use wgpu::PresentMode::{Fifo, Immediate, Mailbox};
+        match self {
+            PresentMode::Fifo => &[Fifo],
+            PresentMode::Mailbox => &[Mailbox, Fifo],
+            PresentMode::Immediate => &[Immediate, Mailbox, Fifo],
+        }
+    }
+}
+
+//  [gpu]
+//  backends = ["vulkan"]
+//  power = "high"
+//  adapter = "radeon"
+//  present_mode = "mailbox"
+//  frame_latency = 1
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpuConfig {
+    // Tried together, empty for all of them
+    pub backends: Vec<Backend>,
+    pub power: Power,
+    // Only a software adapter, like llvmpipe or WARP
+    pub fallback: bool,
+    // Part of the adapter's name, any case
+    pub adapter: Option<String>,
+    pub present_mode: PresentMode,
+    // Frames queued for the display at most
+    pub frame_latency: u32,
+}
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        Self {
+            backends: Vec::new(),
+            power: Power::default(),
+            fallback: false,
+            adapter: None,
+            present_mode: PresentMode::default(),
+            frame_latency: 2,
+        }
+    }
+}
+
+impl GpuConfig {
+    // The backends asked for, or from WGPU_BACKEND, or all of them
+    fn backends(&self) -> wgpu::Backends {
+        if self.backends.is_empty() {
+            wgpu::Backends::from_env().unwrap_or(wgpu::Backends::all())
+        } else {
+            self.backends.iter()
+                .fold(wgpu::Backends::empty(), |all, b| all | b.backends())
+        }
+    }
+
+    pub fn instance(&self) -> wgpu::Instance {
+        let mut descriptor = wgpu::InstanceDescriptor::from_env_or_default();
+        descriptor.backends = self.backends();
+        wgpu::Instance::new(&descriptor)
+    }
+
+    // The adapter asked for that can present to surface, if there is one
+    pub async fn adapter(
+        &self,
+        instance: &wgpu::Instance,
+        surface: Option<&wgpu::Surface<'_>>,
+    ) -> Result<wgpu::Adapter> {
+        let adapter = match &self.adapter {
+            // Picked by name, so there is nothing for these to choose
+            // between
+            Some(_) if self.power != Power::Default || self.fallback => {
+                return Err(Error::AdapterConflict);
+            }
+            Some(name) => {
+                let adapters = instance.enumerate_adapters(self.backends());
+                let names: Vec<String> = adapters.iter()
+                    .map(|a| a.get_info().name)
+                    .collect();
+                adapters.into_iter()
+                    .find(|a| {
+                        a.get_info().name.to_lowercase().contains(&name.to_lowercase())
+                            && surface.is_none_or(|s| a.is_surface_supported(s))
+                    })
+                    .ok_or_else(|| Error::AdapterNotFound {
+                        name: name.clone(),
+                        available: names,
+                    })?
+            }
+            None => instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: self.power.preference(),
+                    force_fallback_adapter: self.fallback,
+                    compatible_surface: surface,
+                })
+                .await?,
+        };
+        let info = adapter.get_info();
+        let driver = format!("{} {}", info.driver, info.driver_info);
+        let chosen = match &self.adapter {
+            Some(name) => format!("by name {name}"),
+            None if self.fallback => "as the fallback".into(),
+            None => format!("by power preference {}", self.power.name()),
+        };
+        log::info!(
+            "adapter {} ({:?}, {}, driver {}), chosen {chosen}",
+            info.name, info.device_type, info.backend, driver.trim());
+        Ok(adapter)
+    }
+
+    // The first of present_mode's candidates the surface can do
+    pub fn present_mode(&self, caps: &wgpu::SurfaceCapabilities) -> wgpu::PresentMode {
+        let mode = self.present_mode.candidates().iter()
+            .copied()
+            .find(|m| caps.present_modes.contains(m))
+            .unwrap_or(wgpu::PresentMode::Fifo);
+        if self.present_mode.candidates()[0] != mode {
+            log::warn!(
+                "no {} present mode, using {mode:?}", self.present_mode.name());
+        }
+        mode
+    }
+}
@@ -0,0 +1,221 @@
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+// Radiance .hdr (RGBE) reader, the usual format for equirectangular
+// environment maps. Handles flat and run length encoded scanlines.
+// Returns linear RGB pixels row by row from the top left.
+pub fn read_hdr(path: &Path) -> std::io::Result<(u32, u32, Vec<[f32; 3]>)> {
+    let mut file = BufReader::new(std::fs::File::open(path)?);
+    let (width, height) = read_header(&mut file)?;
+
+    // Not reserved up front from the header, so a short file with a huge
+    // resolution fails at the end of its data instead of at allocation
+    let mut pixels = Vec::new();
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        read_scanline(&mut file, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_f32(rgbe)));
+    }
+    Ok((width, height, pixels))
+}
+
+// Largest side and pixel count accepted, a 16k by 8k map is well past
+// what a GPU takes as one texture anyway
+const MAX_SIDE: u32 = 1 << 16;
+const MAX_PIXELS: u32 = 16384 * 8192;
+
+// Returns the width and height
+fn read_header(file: &mut impl BufRead) -> std::io::Result<(u32, u32)> {
+    let mut line = String::new();
+    file.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid("not a Radiance HDR file"));
+    }
+    // Header lines up to a blank line, only the format matters
+    loop {
+        line.clear();
+        if file.read_line(&mut line)? == 0 {
+            return Err(invalid("missing resolution"));
+        }
+        let l = line.trim();
+        if l.is_empty() { break; }
+        if let Some(format) = l.strip_prefix("FORMAT=")
+            && format != "32-bit_rle_rgbe"
+        {
+            return Err(invalid(&format!("unsupported format {format}")));
+        }
+    }
+    // Only the standard top to bottom, left to right orientation
+    line.clear();
+    file.read_line(&mut line)?;
+    let size: Vec<&str> = line.split_whitespace().collect();
+    let (height, width): (u32, u32) = match size[..] {
+        ["-Y", h, "+X", w] => (
+            h.parse().map_err(|_| invalid("bad height"))?,
+            w.parse().map_err(|_| invalid("bad width"))?,
+        ),
+        _ => return Err(invalid(&format!("unsupported layout {}", line.trim()))),
+    };
+    if width == 0 || height == 0 {
+        return Err(invalid(&format!("empty image {width}x{height}")));
+    }
+    if width > MAX_SIDE || height > MAX_SIDE
+        || width.checked_mul(height).is_none_or(|n| n > MAX_PIXELS)
+    {
+        return Err(invalid(&format!("image {width}x{height} is too large")));
+    }
+    Ok((width, height))
+}
+
+fn read_scanline(file: &mut impl Read, scanline: &mut [[u8; 4]]) -> std::io::Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    file.read_exact(&mut first)?;
+
+    // New style RLE starts with 2 2 and the width, each channel is then
+    // stored separately as runs and literals.
+    let is_rle = (8..0x8000).contains(&width)
+        && first[0] == 2 && first[1] == 2
+        && ((first[2] as usize) << 8 | first[3] as usize) == width;
+    if !is_rle {
+        scanline[0] = first;
+        for pixel in &mut scanline[1..] {
+            file.read_exact(pixel)?;
+        }
+        return Ok(());
+    }
+
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            file.read_exact(&mut count)?;
+            let count = count[0] as usize;
+            if count > 128 {
+                let run = count - 128;
+                let mut value = [0u8; 1];
+                file.read_exact(&mut value)?;
+                if x + run > width { return Err(invalid("bad run length")); }
+                for pixel in &mut scanline[x..x + run] {
+                    pixel[channel] = value[0];
+                }
+                x += run;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(invalid("bad run length"));
+                }
+                let mut values = vec![0u8; count];
+                file.read_exact(&mut values)?;
+                for (pixel, v) in scanline[x..x + count].iter_mut().zip(values) {
+                    pixel[channel] = v;
+                }
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn rgbe_to_f32(rgbe: [u8; 4]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    // Mantissas are 0..255 so the exponent is offset by 8 more
+    let scale = 2f32.powi(rgbe[3] as i32 - 136);
+    [rgbe[0], rgbe[1], rgbe[2]].map(|c| (c as f32 + 0.5) * scale)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgbe_decodes_to_linear() {
+        // Exponent 129 is 2^1, and each mantissa is taken from the middle
+        // of its step of 1/256
+        let step = 1.0 / 256.0;
+        assert_eq!(rgbe_to_f32([128, 0, 255, 129]), [1.0 + step, step, 2.0 - step]);
+        assert_eq!(rgbe_to_f32([200, 100, 50, 0]), [0.0; 3]);
+    }
+
+    fn header(resolution: &str) -> std::io::Result<(u32, u32)> {
+        let text = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{resolution}\n");
+        read_header(&mut text.as_bytes())
+    }
+
+    #[test]
+    fn header_resolution() {
+        assert_eq!(header("-Y 2 +X 3").unwrap(), (3, 2));
+        assert!(header("+Y 2 +X 3").is_err());
+    }
+
+    #[test]
+    fn empty_images_are_rejected() {
+        for resolution in ["-Y 1 +X 0", "-Y 0 +X 1", "-Y 0 +X 0"] {
+            let error = header(resolution).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn huge_images_are_rejected() {
+        // 70000 squared doesn't fit in a u32
+        for resolution in ["-Y 70000 +X 70000", "-Y 1 +X 4294967295", "-Y 16384 +X 16384"] {
+            let error = header(resolution).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn flat_scanline() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut scanline = [[0u8; 4]; 2];
+        read_scanline(&mut &data[..], &mut scanline).unwrap();
+        assert_eq!(scanline, [[1, 2, 3, 4], [5, 6, 7, 8]]);
+    }
+
+    #[test]
+    fn rle_scanline() {
+        let mut data = vec![2, 2, 0, 8];
+        // Red a run of 8, green 8 literals, blue two runs, exponent a
+        // literal then a run
+        data.extend([128 + 8, 10]);
+        data.extend([8, 0, 1, 2, 3, 4, 5, 6, 7]);
+        data.extend([128 + 3, 20, 128 + 5, 30]);
+        data.extend([1, 100, 128 + 7, 128]);
+        let mut scanline = [[0u8; 4]; 8];
+        read_scanline(&mut &data[..], &mut scanline).unwrap();
+        let expected: Vec<[u8; 4]> = (0..8u8)
+            .map(|x| [10, x, if x < 3 { 20 } else { 30 }, if x == 0 { 100 } else { 128 }])
+            .collect();
+        assert_eq!(scanline.to_vec(), expected);
+    }
+
+    #[test]
+    fn rle_runs_past_the_end_are_rejected() {
+        let mut scanline = [[0u8; 4]; 8];
+        for data in [
+            // A run of 9
+            vec![2, 2, 0, 8, 128 + 9, 1],
+            // 9 literals
+            vec![2, 2, 0, 8, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            // Zero literals, which would never finish
+            vec![2, 2, 0, 8, 0],
+        ] {
+            let error = read_scanline(&mut &data[..], &mut scanline).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn short_scanline_is_an_error() {
+        let data = [2, 2, 0, 8, 128 + 8, 1];
+        let mut scanline = [[0u8; 4]; 8];
+        let error = read_scanline(&mut &data[..], &mut scanline).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,17 @@
+use std::io::Write;
+use std::path::Path;
+
+// Writes 8 bit RGB pixels, row by row from the top left, as a binary PPM.
+// Any image viewer worth having can open these and there's nothing to
+// depend on.
+pub fn write_ppm(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[u8; 3]],
+) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write!(file, "P6\n{width} {height}\n255\n")?;
+    file.write_all(pixels.as_flattened())?;
+    file.flush()
+}
@@ -1,11 +1,53 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[macro_use]
+mod named;
 mod uniform;
+pub mod animation;
+pub mod background;
+pub mod bench;
+pub mod camera;
+pub mod color;
+pub mod config;
+pub mod cpu;
+pub mod denoise;
+pub mod environment;
+pub mod error;
+mod font;
+pub mod gpu;
+pub mod hdr;
+pub mod image;
+pub mod light;
+pub mod lut;
+pub mod march;
+pub mod material;
+pub mod math;
+pub mod offscreen;
+pub mod overlay;
+pub mod pathtrace;
+pub mod post;
+pub mod profile;
+mod readback;
+pub mod redraw;
+pub mod sampling;
+pub mod slice;
+pub mod stats;
+pub mod view;
 use crate:: uniform::*;
+use crate::background::BackgroundConfig;
+use crate::camera::CameraConfig;
+use crate::config::SceneConfig;
+use crate::environment::Environment;
+use crate::error::{Error, Result};
+use crate::post::{PostChain, HDR_FORMAT};
+use crate::redraw::Redraw;
+use crate::sampling::SamplingConfig;
 
 use winit::{
     application::ApplicationHandler,
     event::WindowEvent,
-    event_loop::ActiveEventLoop,
+    event_loop::{ActiveEventLoop, ControlFlow},
+    keyboard::{Key, NamedKey},
     window::{Window, WindowId},
 };
 
@@ -17,15 +59,120 @@ const BINDINGS: &str = "bindings";
 
 const SCREEN_X: &str = "screen_x";
 const SCREEN_Y: &str = "screen_y";
+const LIGHTS: &str = "lights";
+const LIGHTING: &str = "lighting";
+const OCCLUSION: &str = "occlusion";
+const VIEW_MODE: &str = "view_mode";
+const MATERIALS: &str = "materials";
+const TRACE: &str = "trace";
+const BACKDROP: &str = "backdrop";
+const ENV_MAP: &str = "env_map";
+const ENV_SAMPLER: &str = "env_sampler";
+const CAMERA: &str = "camera";
+const SAMPLING: &str = "sampling";
+const PATH_TRACE: &str = "path_trace";
+const ANIMATION: &str = "animation";
+const SLICE: &str = "slice";
 
 // Event driven window handler for this application
 #[derive(Default)]
 pub struct App {
     window: Option<Arc<Window>>,
     renderer: Option<Renderer>,
+    config: SceneConfig,
+    title: String,
+    // Set for a bench run in the window
+    bench: Option<bench::BenchRun>,
+    // What made the event loop exit early, for main to report
+    error: Option<Error>,
+    // Loads the scene again for F5, None for the built in scene
+    reload: Option<Box<dyn Fn() -> Result<SceneConfig>>>,
     // last_size: winit::dpi::PhysicalSize<u32>,
 }
 
+impl App {
+    pub fn new(config: SceneConfig, title: String) -> Self {
+        Self {
+            config,
+            title,
+            ..Default::default()
+        }
+    }
+
+    // Runs bench at its size instead of taking input, exiting when it is
+    // done
+    pub fn with_bench(mut self, bench: bench::BenchRun) -> Self {
+        self.bench = Some(bench);
+        self
+    }
+
+    // Why the window closed, if it wasn't asked to. Call after the event
+    // loop has run.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    // Scenes loaded by reload are given to the window as they are, so it
+    // should apply anything the command line overrides
+    pub fn with_reload(
+        mut self,
+        reload: impl Fn() -> Result<SceneConfig> + 'static,
+    ) -> Self {
+        self.reload = Some(Box::new(reload));
+        self
+    }
+
+    // From self.config, after the device was lost or the scene reloaded.
+    // The old renderer and its surface are dropped first, as some backends
+    // won't make a second surface for the same window.
+    fn remake_renderer(&mut self) -> Result<()> {
+        let Some(window) = self.window.clone() else { return Ok(()); };
+        self.renderer = None;
+        self.renderer = Some(pollster::block_on(Renderer::new(window, &self.config))?);
+        Ok(())
+    }
+
+    // Everything in a scene ends up in the renderer somewhere, so it is
+    // made again for the new one. A scene that doesn't load, or can't be
+    // drawn, leaves the old one up with the error on the overlay.
+    fn reload(&mut self, event_loop: &ActiveEventLoop) {
+        let Some(reload) = &self.reload else {
+            log::info!("no scene file to reload");
+            return;
+        };
+        let error = match reload() {
+            Ok(config) => {
+                let old = std::mem::replace(&mut self.config, config);
+                match self.remake_renderer() {
+                    Ok(()) => {
+                        log::info!("scene reloaded");
+                        None
+                    }
+                    Err(e) => {
+                        self.config = old;
+                        if let Err(e) = self.remake_renderer() {
+                            log::error!("can't go back to the old scene");
+                            self.error = Some(e);
+                            event_loop.exit();
+                            return;
+                        }
+                        Some(e)
+                    }
+                }
+            }
+            Err(e) => Some(e),
+        };
+        if let Some(renderer) = &mut self.renderer
+            && let Some(e) = error
+        {
+            renderer.show_error(Err(e));
+        }
+        // In on demand mode nothing else would draw the new scene
+        if let Some(window) = &self.window {
+            window.request_redraw();
+        }
+    }
+}
 
 
 impl ApplicationHandler for App {
@@ -33,7 +180,12 @@ impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         // Create window object
         let mut attributes = Window::default_attributes();
-        attributes = attributes.with_title("Title");
+        attributes = attributes.with_title(&self.title);
+        if let Some(run) = &self.bench {
+            attributes = attributes
+                .with_inner_size(winit::dpi::PhysicalSize::new(run.width, run.height))
+                .with_resizable(false);
+        }
 
         if let Ok(window) = event_loop.create_window(attributes) {
             let window_handle = Arc::new(window);
@@ -42,10 +194,15 @@ impl ApplicationHandler for App {
             // Done in main? Better to have it here?
             // env_logger::init();
 
-            let renderer = pollster::block_on(
-                Renderer::new(window_handle.clone())
-            );
-            self.renderer = Some(renderer);
+            match pollster::block_on(
+                Renderer::new(window_handle.clone(), &self.config)
+            ) {
+                Ok(renderer) => self.renderer = Some(renderer),
+                Err(e) => {
+                    self.error = Some(e);
+                    event_loop.exit();
+                }
+            }
 
         }
     }
@@ -56,6 +213,25 @@ impl ApplicationHandler for App {
         _id:            WindowId,
         event:          WindowEvent
     ) {
+        if let WindowEvent::RedrawRequested = event
+            && self.renderer.as_ref()
+                .is_some_and(|r| !r.paused() && r.gpu.device_lost())
+        {
+            log::warn!("making the renderer again on a new device");
+            if let Err(e) = self.remake_renderer() {
+                log::error!("can't recover from losing the device");
+                self.error = Some(e);
+                event_loop.exit();
+            }
+        }
+        if let WindowEvent::KeyboardInput { event, .. } = &event
+            && self.bench.is_none()
+            && event.state.is_pressed()
+            && event.logical_key == Key::Named(NamedKey::F5)
+        {
+            self.reload(event_loop);
+            return;
+        }
         let (Some(window), Some(renderer),) = (
             self.window.as_mut(),
             self.renderer.as_mut(),
@@ -69,9 +245,44 @@ impl ApplicationHandler for App {
                 event_loop.exit();
             }
             WindowEvent::RedrawRequested => {
-                renderer.render();
-                // Emits a new redraw requested event.
-                window.request_redraw();
+                // Resized and Occluded ask for a redraw once there is
+                // something to draw to again
+                if renderer.paused() {
+                    event_loop.set_control_flow(ControlFlow::Wait);
+                    return;
+                }
+                event_loop.set_control_flow(match self.config.redraw {
+                    Redraw::Continuous => ControlFlow::Poll,
+                    // Still wakes for the redraws asked for below
+                    Redraw::OnDemand => ControlFlow::Wait,
+                });
+                if let Some(run) = &self.bench {
+                    let Some(camera) = run.bench.next_camera() else {
+                        let written = run.write_report(&bench::RunInfo {
+                            scene: run.scene.clone(),
+                            width: renderer.size.width,
+                            height: renderer.size.height,
+                            windowed: true,
+                            adapter: renderer.gpu.adapter.get_info(),
+                        });
+                        if let Err(e) = written {
+                            self.error = Some(e);
+                        }
+                        event_loop.exit();
+                        return;
+                    };
+                    self.config.camera = camera;
+                    renderer.set_camera(&self.config.camera);
+                }
+                renderer.update_overlay(&self.config);
+                // A bench measures its frames one at a time
+                let drawn = renderer.render(self.bench.is_some());
+                if drawn
+                    && let Some(run) = &mut self.bench
+                    && let Some(timing) = renderer.last_frame_timing()
+                {
+                    run.bench.record(bench::Sample::new(timing, renderer.march_stats()));
+                }
             }
             WindowEvent::Resized(size) => {
                 // Reconfigures the size of the surface. We do not re-render
@@ -80,71 +291,260 @@ impl ApplicationHandler for App {
                 renderer.resize(size);
                 // self.last_size = size;
             }
+            WindowEvent::Occluded(occluded) => {
+                renderer.set_occluded(occluded);
+            }
+            // A bench's frames are scripted, the same from run to run
+            WindowEvent::KeyboardInput { .. } if self.bench.is_some() => (),
+            WindowEvent::KeyboardInput { event, .. }
+                if event.state.is_pressed()
+                    && camera_key(&mut self.config.camera, &event.logical_key) =>
+            {
+                renderer.set_camera(&self.config.camera);
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.state.is_pressed()
+                    && event.logical_key == Key::Character("q".into()) =>
+            {
+                let march = &mut self.config.march;
+                march.quality = march.quality.next();
+                log::info!("quality {}", march.quality.name());
+                renderer.set_march(march);
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.state.is_pressed()
+                    && event.logical_key == Key::Character("o".into()) =>
+            {
+                renderer.toggle_overlay();
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.state.is_pressed()
+                    && event.logical_key == Key::Character("r".into()) =>
+            {
+                self.config.redraw = self.config.redraw.next();
+                log::info!("redraw {}", self.config.redraw.name());
+                renderer.set_redraw(self.config.redraw);
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.state.is_pressed()
+                    && view_key(&mut self.config.view, &event.logical_key) =>
+            {
+                log::info!("view {}", self.config.view.name());
+                renderer.set_view_mode(self.config.view);
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.state.is_pressed()
+                    && self.config.view == view::ViewMode::Slice
+                    && slice_key(&mut self.config.slice, &event.logical_key) =>
+            {
+                let slice = &self.config.slice;
+                log::info!("slice {:?} at {}", slice.normal, slice.offset);
+                renderer.set_slice(slice);
+            }
             _ => (),
         }
+
+        // Emits a new redraw requested event. Resizing and coming back
+        // from minimized or occluded count as changes, which starts the
+        // redraws again after a pause.
+        if self.config.redraw == Redraw::Continuous || renderer.needs_redraw() {
+            window.request_redraw();
+        }
+    }
+}
+
+// Arrow keys orbit the camera, W and S move it in and out, P and E step
+// through the projections and stereo modes. Returns whether the key
+// changed the camera.
+fn camera_key(camera: &mut CameraConfig, key: &Key) -> bool {
+    let step = 5f32.to_radians();
+    match key.as_ref() {
+        Key::Named(NamedKey::ArrowLeft) => camera.orbit(-step, 0.0),
+        Key::Named(NamedKey::ArrowRight) => camera.orbit(step, 0.0),
+        Key::Named(NamedKey::ArrowUp) => camera.orbit(0.0, step),
+        Key::Named(NamedKey::ArrowDown) => camera.orbit(0.0, -step),
+        Key::Character("w") => camera.dolly(0.9),
+        Key::Character("s") => camera.dolly(1.0 / 0.9),
+        Key::Character("p") => {
+            camera.projection = camera.projection.next();
+            log::info!("projection {}", camera.projection.name());
+        }
+        Key::Character("e") => {
+            camera.stereo = camera.stereo.next();
+            log::info!("stereo {}", camera.stereo.name());
+        }
+        _ => return false,
+    }
+    true
+}
+
+// V steps through the view modes and 1 to 9 pick one. Returns whether
+// the key changed the view.
+fn view_key(view: &mut view::ViewMode, key: &Key) -> bool {
+    match key.as_ref() {
+        Key::Character("v") => *view = view.next(),
+        Key::Character(c) => {
+            let Some(mode) = c.parse::<usize>().ok()
+                .and_then(|n| n.checked_sub(1))
+                .and_then(|i| view::ViewMode::ALL.get(i))
+            else {
+                return false;
+            };
+            *view = *mode;
+        }
+        _ => return false,
+    }
+    true
+}
+
+// [ and ] move the slice plane an isoline at a time, X, Y and Z turn it
+// to face along the axis. Returns whether the key changed the slice.
+fn slice_key(slice: &mut slice::SliceConfig, key: &Key) -> bool {
+    match key.as_ref() {
+        Key::Character("[") => slice.shift(-slice.spacing),
+        Key::Character("]") => slice.shift(slice.spacing),
+        Key::Character("x") => slice.face_axis(0),
+        Key::Character("y") => slice.face_axis(1),
+        Key::Character("z") => slice.face_axis(2),
+        _ => return false,
     }
+    true
+}
+
+// Adapter, device and queue, able to present to surface if there is one
+async fn request_device(
+    config: &gpu::GpuConfig,
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface<'_>>,
+) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    let adapter = config.adapter(instance, surface).await?;
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                // Downlevel adapters can often still render to float
+                // formats like the accumulation texture's, but only say so
+                // with this. Timestamps are for [profile].
+                required_features: adapter.features()
+                    & (wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+                        | wgpu::Features::TIMESTAMP_QUERY),
+                ..Default::default()
+            },
+            // None, // Trace path
+        )
+        .await?;
+    Ok((adapter, device, queue))
 }
 
 // Creates a surface, device and queue for a window
 // Allows public access to all of its fields for use by Renderer
 pub struct Gpu {
     // window: Arc<Window>,
+    pub adapter: wgpu::Adapter,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     // size: winit::dpi::PhysicalSize<u32>,
     pub surface: wgpu::Surface<'static>,
     pub surface_config: wgpu::SurfaceConfiguration,
     pub surface_format: wgpu::TextureFormat,
+    // Set when the device goes away, after a driver reset or a GPU being
+    // unplugged. Everything made with it has to be made again.
+    lost: Arc<AtomicBool>,
 }
 
 impl Gpu {
-    async fn new(
+    pub async fn new(
         window: Arc<Window>,
+        config: &gpu::GpuConfig,
         // bindings: &mut PipelineBindGroups
-    ) -> Gpu {
-        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions::default())
-            .await
-            .unwrap();
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor::default(),
-                // None, // Trace path
-            )
-            .await
-            .unwrap();
-
+    ) -> Result<Gpu> {
+        let instance = config.instance();
         let size = window.inner_size();
+        let surface = instance.create_surface(window)?;
+        let (adapter, device, queue) =
+            request_device(config, &instance, Some(&surface)).await?;
 
-        let surface = instance.create_surface(window).unwrap();
+        // Prefer a format that sRGB encodes in hardware, otherwise the
+        // shader does it. Either way it's only done once.
         let cap = surface.get_capabilities(&adapter);
-        let surface_format = cap.formats[0];
+        let surface_format = cap.formats.iter()
+            .copied()
+            .find(|f| f.is_srgb())
+            .unwrap_or(cap.formats[0]);
+        log::info!(
+            "surface format {surface_format:?}, sRGB encoded by the {}",
+            if surface_format.is_srgb() { "hardware" } else { "shader" });
+        let present_mode = config.present_mode(&cap);
+        // The window isn't see-through, so opaque when the surface can be
+        let alpha_mode = if cap.alpha_modes.contains(&wgpu::CompositeAlphaMode::Opaque) {
+            wgpu::CompositeAlphaMode::Opaque
+        } else {
+            cap.alpha_modes[0]
+        };
+        let frame_latency = config.frame_latency.max(1);
+        log::info!(
+            "present mode {present_mode:?}, alpha {alpha_mode:?}, \
+             frame latency {frame_latency}");
 
         // Configure surface for the first time
         let surface_config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
-            // Request compatibility with the sRGB-format texture view we‘re going to create later.
-            view_formats: vec![surface_format.add_srgb_suffix()],
+            view_formats: vec![],
             width: size.width,
             height: size.height,
-            present_mode: cap.present_modes[0],
-            alpha_mode: cap.alpha_modes[0],
-            desired_maximum_frame_latency: 2,
+            present_mode,
+            alpha_mode,
+            desired_maximum_frame_latency: frame_latency,
         };
 
-        surface.configure(&device, &surface_config);
- 
+        // A minimized window has no size to configure with, resize does
+        // it when it gets one
+        if size.width > 0 && size.height > 0 {
+            surface.configure(&device, &surface_config);
+        }
 
-        Self {
+        let lost = Arc::new(AtomicBool::new(false));
+        let flag = lost.clone();
+        device.set_device_lost_callback(move |reason, message| {
+            // Destroyed is the device being dropped
+            if reason == wgpu::DeviceLostReason::Unknown {
+                log::error!("lost the GPU device: {message}");
+                flag.store(true, Ordering::Relaxed);
+            }
+        });
+        // Work already queued fails once the device is lost, which isn't
+        // worth more than a log line. Anything else is a bug, so it
+        // panics like wgpu's own handler.
+        let flag = lost.clone();
+        device.on_uncaptured_error(Arc::new(move |error| {
+            if flag.load(Ordering::Relaxed) {
+                log::debug!("error after losing the device: {error}");
+            } else {
+                panic!("wgpu error: {error}");
+            }
+        }));
+
+        Ok(Self {
             // window,
+            adapter,
             device,
             queue,
             // size,
             surface,
             surface_config,
             surface_format,
+            lost,
+        })
+    }
+
+    pub fn device_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    // After the surface was lost or went out of date
+    fn configure(&self) {
+        if self.surface_config.width > 0 && self.surface_config.height > 0 {
+            self.surface.configure(&self.device, &self.surface_config);
         }
     }
 
@@ -180,97 +580,377 @@ pub struct Renderer {
     scene: Scene,
     size: winit::dpi::PhysicalSize<u32>,
     bindings: PipelineBindGroups,
+    post: PostChain,
+    sampling: SamplingConfig,
+    animation: animation::AnimationConfig,
+    // When the animation started running
+    start: std::time::Instant,
+    stats: Option<stats::Stats>,
+    profiler: Option<profile::Profiler>,
+    overlay: overlay::Overlay,
+    // Hidden behind other windows or on a sleeping display
+    occluded: bool,
+    // Set by anything that changes the next frame, cleared by drawing it
+    changed: bool,
+    // Shown on the overlay
+    redraw: Redraw,
 
     // depth_texture_view: wgpu::TextureView,
 }
 
 impl Renderer {
-    async fn new(window: Arc<Window>) -> Self {
+    pub async fn new(window: Arc<Window>, config: &SceneConfig) -> Result<Self> {
         let size = window.inner_size();
-        let gpu = Gpu::new(window).await;
+        let gpu = Gpu::new(window, &config.gpu).await?;
         let mut bindings = PipelineBindGroups::new(BINDINGS);
-        Self::init_bindings(&mut bindings, &size, &gpu.device);
+        Self::init_bindings(
+            &mut bindings, size.width, size.height, config,
+            &gpu.device, &gpu.queue,
+        )?;
+        let stats = stats::Stats::new(
+            &config.stats, &gpu.adapter, &gpu.device, &mut bindings);
+        let profiler = profile::Profiler::new(
+            &config.profile, &gpu.device, &gpu.queue);
         let scene = Scene::new(
-            &gpu.device, gpu.surface_format, &mut bindings);
-        Self {
+            &gpu.device, HDR_FORMAT, &mut bindings, config.march.march(),
+            config.denoise.enabled)?;
+        let post = PostChain::new(
+            &gpu.adapter, &gpu.device, &gpu.queue, config,
+            size.width, size.height, gpu.surface_format,
+        )?;
+        let overlay = overlay::Overlay::new(
+            &config.overlay, &gpu.device, gpu.surface_format)?;
+        Ok(Self {
             gpu,
             scene,
             size,
             bindings,
-        }
+            post,
+            sampling: config.effective_sampling(),
+            animation: config.animation.clone(),
+            start: std::time::Instant::now(),
+            stats,
+            profiler,
+            overlay,
+            occluded: false,
+            changed: true,
+            redraw: config.redraw,
+        })
     }
 
     fn resize(&mut self, size: winit::dpi::PhysicalSize<u32>) {
         self.size = size;
+        self.changed = true;
         self.gpu.resize(size);
+        if size.width > 0 && size.height > 0 {
+            self.bindings.set_uniform(
+                SCREEN_X, size.width as i32, &self.gpu.queue);
+            self.bindings.set_uniform(
+                SCREEN_Y, size.height as i32, &self.gpu.queue);
+            self.post.resize(&self.gpu.device, size.width, size.height);
+        }
     }
 
     fn init_bindings(
         bindings: &mut PipelineBindGroups,
-        size: &winit::dpi::PhysicalSize<u32>,
+        width: u32,
+        height: u32,
+        config: &SceneConfig,
         device: &wgpu::Device,
-    ) {
+        queue: &wgpu::Queue,
+    ) -> Result<()> {
          // Set the window size
         bindings.new_uniform(
-            SCREEN_X, GroupIndex::Scalars, size.width as i32, device,
+            SCREEN_X, GroupIndex::Scalars, width as i32, device,
+        );
+        bindings.new_uniform(
+            SCREEN_Y, GroupIndex::Scalars, height as i32, device,
+        );
+
+        let lights: Vec<light::Light> =
+            config.lights.iter().map(|l| l.to_shader()).collect();
+        bindings.new_uniform(
+            CAMERA, GroupIndex::Scalars, config.camera.to_shader(), device,
+        );
+        bindings.new_uniform(
+            ANIMATION, GroupIndex::Scalars, config.animation.to_shader(0.0),
+            device,
         );
         bindings.new_uniform(
-            SCREEN_Y, GroupIndex::Scalars, size.height as i32, device,
+            SAMPLING, GroupIndex::Scalars,
+            config.effective_sampling().to_shader(0), device,
         );
 
+        bindings.new_uniform(
+            LIGHTING, GroupIndex::Scalars,
+            config.ambient.to_shader(lights.len()), device,
+        );
+        bindings.new_storage(LIGHTS, GroupIndex::Buffers, &lights, device);
+        bindings.new_uniform(
+            OCCLUSION, GroupIndex::Scalars,
+            config.occlusion.to_shader(), device,
+        );
+        bindings.new_uniform(
+            VIEW_MODE, GroupIndex::Scalars, config.view as u32, device,
+        );
+        bindings.new_uniform(
+            SLICE, GroupIndex::Scalars, config.slice.to_shader(), device,
+        );
+        bindings.new_uniform(
+            TRACE, GroupIndex::Scalars, config.trace.to_shader(), device,
+        );
+        bindings.new_uniform(
+            PATH_TRACE, GroupIndex::Scalars, config.path_trace.to_shader(),
+            device,
+        );
+        bindings.new_storage(
+            MATERIALS, GroupIndex::Buffers, &config.material_table(), device,
+        );
+
+        let (env, texture) =
+            Self::load_environment(&config.background, device, queue)?;
+        bindings.new_uniform(
+            BACKDROP, GroupIndex::Scalars,
+            config.background.to_shader(env.level_count()), device,
+        );
+        bindings.new_texture(
+            ENV_MAP, GroupIndex::Textures,
+            texture.create_view(&Default::default()),
+            wgpu::TextureViewDimension::D2,
+        );
+        bindings.new_sampler(
+            ENV_SAMPLER, GroupIndex::Textures, Environment::sampler(device));
+        Ok(())
+    }
+
+    fn load_environment(
+        config: &BackgroundConfig,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<(Environment, wgpu::Texture)> {
+        let env = Environment::for_background(config)?;
+        let texture = env.upload(device, queue)?;
+        Ok((env, texture))
+    }
+
+    // Switches to another background, loading its environment map if it
+    // has one
+    pub fn set_background(&mut self, config: &BackgroundConfig) -> Result<()> {
+        let (env, texture) = Self::load_environment(
+            config, &self.gpu.device, &self.gpu.queue)?;
+        self.changed = true;
+        self.bindings.set_uniform(
+            BACKDROP, config.to_shader(env.level_count()), &self.gpu.queue);
+        self.bindings.set_texture(
+            ENV_MAP, texture.create_view(&Default::default()));
+        self.post.reset_accumulation();
+        Ok(())
+    }
+
+    // The post chain is made again for the view, an error making it is
+    // shown on the overlay
+    pub fn set_view_mode(&mut self, view: view::ViewMode) {
+        self.changed = true;
+        self.bindings.set_uniform(VIEW_MODE, view as u32, &self.gpu.queue);
+        let built = self.post.set_view_mode(view, &self.gpu.device, &self.gpu.queue);
+        self.show_error(built);
+    }
+
+    pub fn set_output(&mut self, config: &color::OutputConfig) {
+        self.changed = true;
+        let built = self.post.set_output(config, &self.gpu.device, &self.gpu.queue);
+        self.show_error(built);
+    }
+
+    fn show_error(&mut self, result: Result<()>) {
+        let error = result.err().map(|e| e.to_string());
+        if let Some(e) = &error {
+            log::error!("{e}");
+        }
+        self.overlay.set_error(error);
+    }
+
+    // The march settings are compiled into the raymarch pipelines, so
+    // they are made again. If that fails the old ones are kept and the
+    // error is shown on the overlay.
+    pub fn set_march(&mut self, config: &march::MarchConfig) {
+        self.changed = true;
+        let aovs = self.scene.aov_pipeline.is_some();
+        match Scene::new(
+            &self.gpu.device, HDR_FORMAT, &mut self.bindings, config.march(),
+            aovs)
+        {
+            Ok(scene) => {
+                self.overlay.set_error(None);
+                self.scene = scene;
+                self.post.reset_accumulation();
+            }
+            Err(e) => {
+                log::error!("{e}");
+                self.overlay.set_error(Some(e.to_string()));
+            }
+        }
+    }
+
+    pub fn set_slice(&mut self, slice: &slice::SliceConfig) {
+        self.changed = true;
+        self.bindings.set_uniform(SLICE, slice.to_shader(), &self.gpu.queue);
+        self.post.reset_accumulation();
+    }
+
+    // Counts from the last frame [stats] sampled, None when stats are off
+    // or no frame has been counted yet
+    pub fn march_stats(&self) -> Option<stats::MarchStats> {
+        self.stats.as_ref().and_then(|s| s.last())
+    }
+
+    // Averages over the last [profile] window, None when profiling is off
+    // or the first window hasn't finished
+    pub fn frame_timing(&self) -> Option<&profile::FrameTiming> {
+        self.profiler.as_ref().and_then(|p| p.last())
+    }
+
+    // Of the last frame, None when profiling is off
+    pub fn last_frame_timing(&self) -> Option<&profile::FrameTiming> {
+        self.profiler.as_ref().and_then(|p| p.latest())
+    }
+
+    pub fn toggle_overlay(&mut self) {
+        self.changed = true;
+        self.overlay.toggle();
+    }
+
+    // Lays out the overlay text from config when it's due
+    fn update_overlay(&mut self, config: &SceneConfig) {
+        // Drawing on demand, the frame after a change may be the last for
+        // a while, so it has to show the change
+        if self.changed && self.redraw == Redraw::OnDemand {
+            self.overlay.refresh();
+        }
+        if !self.overlay.tick() { return; }
+        let frame_time = self.overlay.frame_time();
+        let mut lines = vec![format!(
+            "{:.1} fps  {:.2} ms",
+            1.0 / frame_time.as_secs_f32().max(1e-6),
+            frame_time.as_secs_f32() * 1000.0)];
+        if let Some(gpu) = self.frame_timing().and_then(|t| t.gpu()) {
+            lines[0] += &format!("  gpu {:.2} ms", gpu.as_secs_f32() * 1000.0);
+        }
+        lines.push(format!(
+            "{} x {}  {}  {}  {}",
+            self.size.width, self.size.height, config.view.name(),
+            config.march.quality.name(), self.redraw.name()));
+        let [x, y, z] = config.camera.position;
+        lines.push(format!("camera {x:.2} {y:.2} {z:.2}"));
+        if self.post.needs_frame() && self.sampling.progressive {
+            lines.push(format!(
+                "frame {} of {}", self.post.accumulated(), self.sampling.frames()));
+        }
+        if let Some(stats) = self.march_stats() {
+            lines.push(format!("{:.1} steps per ray", stats.steps_per_ray()));
+        }
+        self.overlay.set_text(&lines, &self.gpu.queue);
+    }
+
+    pub fn set_camera(&mut self, camera: &CameraConfig) {
+        self.changed = true;
+        self.bindings.set_uniform(CAMERA, camera.to_shader(), &self.gpu.queue);
+        self.post.reset_accumulation();
+    }
+
+    pub fn set_occluded(&mut self, occluded: bool) {
+        self.occluded = occluded;
+        self.changed = true;
+    }
+
+    pub fn set_redraw(&mut self, redraw: Redraw) {
+        self.redraw = redraw;
+        self.changed = true;
+    }
+
+    // Whether there is a frame to draw even with nothing changing, while
+    // the scene animates or a progressive render hasn't all its frames
+    pub fn needs_redraw(&self) -> bool {
+        self.changed
+            || self.animation.enabled
+            || (self.sampling.progressive && self.post.needs_frame())
     }
 
-    fn render(&mut self) {
+    // Whether there is nothing to draw to, minimized or occluded
+    pub fn paused(&self) -> bool {
+        self.occluded || self.size.width == 0 || self.size.height == 0
+    }
+
+    // Returns whether a frame was drawn, there may not be a surface
+    // texture to draw it to. With wait, waits for the GPU to finish it
+    // so its times and counts are ready.
+    fn render(&mut self, wait: bool) -> bool {
+        // A moving scene can't be averaged over frames
+        if self.animation.enabled {
+            let elapsed = self.start.elapsed().as_secs_f32();
+            self.bindings.set_uniform(
+                ANIMATION, self.animation.to_shader(elapsed), &self.gpu.queue);
+            self.post.reset_accumulation();
+        }
+
+        let surface_texture = match self.gpu.surface.get_current_texture() {
+            Ok(texture) => texture,
+            // The window or display changed under the surface, it is set
+            // up again for the next frame
+            Err(error @ (wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated)) => {
+                log::info!("{error}, configuring the surface again");
+                self.gpu.configure();
+                return false;
+            }
+            Err(wgpu::SurfaceError::Timeout) => {
+                log::warn!("timed out waiting for a surface texture, skipping the frame");
+                return false;
+            }
+            Err(error) => {
+                log::error!("can't get a surface texture: {error}");
+                return false;
+            }
+        };
         // Create texture view
-        let surface_texture = self
-            .gpu
-            .surface
-            .get_current_texture()
-            .expect("failed to acquire next swapchain texture");
         let texture_view = surface_texture
             .texture
-            .create_view(&wgpu::TextureViewDescriptor {
-                // Without add_srgb_suffix() the image we will be working with
-                // might not be "gamma correct".
-                format: Some(self.gpu.surface_format.add_srgb_suffix()),
-                ..Default::default()
-            });
+            .create_view(&Default::default());
 
-        // Renders a GREEN screen
         let mut encoder = self.gpu.device.create_command_encoder(&Default::default());
-        // Create the renderpass which will clear the screen.
-        let mut renderpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: None,
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &texture_view,
-                depth_slice: None,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color::GREEN),
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: None,
-            timestamp_writes: None,
-            occlusion_query_set: None,
-        });
-
-        self.scene.render(
-            &mut renderpass, &self.gpu.device, &mut self.bindings);
-
-        // End the renderpass.
-        drop(renderpass);
+        render_frame(
+            &mut encoder, &self.gpu.device, &self.gpu.queue, &self.scene,
+            &mut self.bindings, &mut self.post, &self.sampling,
+            self.stats.as_mut(), self.profiler.as_mut(), &texture_view);
+        self.overlay.render(&mut encoder, &texture_view, &self.gpu.device);
 
         // Submit the command in the queue to execute
         self.gpu.queue.submit([encoder.finish()]);
+        if let Some(stats) = &mut self.stats
+            && let Some(counts) = stats.read(&self.gpu.device, wait)
+        {
+            log::info!("march stats: {counts}");
+        }
+        if let Some(profiler) = &mut self.profiler
+            && let Some(timing) = profiler.finish_frame(&self.gpu.device, wait)
+        {
+            log::info!("frame timing: {timing}");
+        }
         // self.gpu.window.pre_present_notify();
+        let suboptimal = surface_texture.suboptimal;
         surface_texture.present();
+        if suboptimal {
+            self.gpu.configure();
+        }
+        self.changed = false;
+        true
     }
 
 }
 
 struct Scene {
     pub pipeline: wgpu::RenderPipeline,
+    // Draws the denoiser's guides with fs_aov, when there is one
+    pub aov_pipeline: Option<wgpu::RenderPipeline>,
     // PipeLineBindGroups here?
 }
 
@@ -279,32 +959,55 @@ impl Scene {
         device: &wgpu::Device,
         surface_format: wgpu::TextureFormat,
         bindings: &mut PipelineBindGroups,
-    ) -> Self {
+        march: march::March,
+        aovs: bool,
+    ) -> Result<Self> {
         //  vertex buffer
         //  index buffer
         //  unifrom
         //  model
+        let constants = march.constants();
         let pipeline = Self::create_pipeline(
-            device, surface_format, bindings);
-        Self {
-            pipeline,
-        }
+            device, surface_format, bindings, &constants);
+        let aov_pipeline = aovs.then(|| fullscreen_pipeline_targets(
+            device, "aov",
+            bindings.make_wgsl() + include_str!("shader.wgsl") + stats::wgsl(false),
+            "fs_aov", &[HDR_FORMAT, HDR_FORMAT], &constants, bindings))
+            .transpose()?;
+        Ok(Self {
+            pipeline: pipeline?,
+            aov_pipeline,
+        })
+    }
+
+    // Normal and depth to one view, albedo to the other
+    pub fn render_aovs(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        views: [&wgpu::TextureView; 2],
+        device: &wgpu::Device,
+        pipeline_bind_groups: &mut PipelineBindGroups,
+        timestamps: Option<wgpu::RenderPassTimestampWrites>,
+    ) {
+        let pipeline = self.aov_pipeline.as_ref()
+            .expect("the scene was made without aovs");
+        fullscreen_pass_targets(
+            encoder, &views, "aov", pipeline, pipeline_bind_groups, device,
+            timestamps);
     }
 
     //  The values of PipeLineBindGroups are set here
-    pub fn render<'rpass>(
-        &'rpass self,
-        renderpass: &mut wgpu::RenderPass<'rpass>,
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
         device: &wgpu::Device,
         pipeline_bind_groups: &mut PipelineBindGroups,
+        timestamps: Option<wgpu::RenderPassTimestampWrites>,
     ) {
-        print!("{}", pipeline_bind_groups.make_wgsl());
-        renderpass.set_pipeline(&self.pipeline);
-        pipeline_bind_groups.set_render_pass(device, renderpass);
-        // If you wanted to call any drawing commands, they would go here.
-        renderpass.set_pipeline(&self.pipeline); // 2.
-        renderpass.draw(0..6, 0..1); // 3.
-    
+        fullscreen_pass(
+            encoder, view, "raymarch", &self.pipeline,
+            pipeline_bind_groups, device, timestamps);
 
         // renderpass.set_bind_group(0, &self.uniform.bind_group, &[]);
 
@@ -342,62 +1045,213 @@ impl Scene {
         device: &wgpu::Device,
         surface_config: wgpu::TextureFormat,
         pipeline_bind_groups: &mut PipelineBindGroups,
-    ) -> wgpu::RenderPipeline {
-        let shader = device.create_shader_module(
-            wgpu::include_wgsl!("shader.wgsl"));
-
-        let render_pipeline_layout =
-              pipeline_bind_groups.pipeline_layout(device);
-        // let render_pipeline_layout =
-        //     device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-        //         label: Some("Render Pipeline Layout"),
-        //         bind_group_layouts: &[],
-        //         push_constant_ranges: &[],
-        //     });
-
-        // let render_pipeline =
-        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: Some("vs_main"), // 1.
-                buffers: &[], // 2.
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            },
-            fragment: Some(wgpu::FragmentState { // 3.
-                module: &shader,
-                entry_point: Some("fs_main"),
-                targets: &[Some(wgpu::ColorTargetState { // 4.
-                    format: surface_config,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList, // 1.
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw, // 2.
-                cull_mode: Some(wgpu::Face::Back),
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                polygon_mode: wgpu::PolygonMode::Fill,
-                // Requires Features::DEPTH_CLIP_CONTROL
-                unclipped_depth: false,
-                // Requires Features::CONSERVATIVE_RASTERIZATION
-                conservative: false,
-            },
-            depth_stencil: None, // 1.
-            multisample: wgpu::MultisampleState {
-                count: 1, // 2.
-                mask: !0, // 3.
-                alpha_to_coverage_enabled: false, // 4.
-            },
-            multiview: None, // 5.
-            cache: None, // 6.
-        })
+        constants: &[(&str, f64)],
+    ) -> Result<wgpu::RenderPipeline> {
+        // The binding declarations come from the Rust side so the two
+        // can't disagree.
+        let source = pipeline_bind_groups.make_wgsl()
+            + include_str!("shader.wgsl")
+            + stats::wgsl(stats::counting(pipeline_bind_groups));
+        fullscreen_pipeline_targets(
+            device, "raymarch", source, "fs_main", &[surface_config],
+            constants, pipeline_bind_groups)
+    }
 
+}
 
+// The raymarch pass then the post chain to view. Once a progressive
+// render has all its frames only the post chain runs.
+#[allow(clippy::too_many_arguments)]
+fn render_frame(
+    encoder: &mut wgpu::CommandEncoder,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    scene: &Scene,
+    bindings: &mut PipelineBindGroups,
+    post: &mut PostChain,
+    sampling: &SamplingConfig,
+    stats: Option<&mut stats::Stats>,
+    mut profiler: Option<&mut profile::Profiler>,
+    view: &wgpu::TextureView,
+) {
+    if let Some(profiler) = profiler.as_deref_mut() {
+        profiler.begin_frame();
+    }
+    if post.needs_frame() {
+        // The guides only change when the average starts over
+        if post.accumulated() == 0 && let Some(views) = post.aov_views() {
+            scene.render_aovs(
+                encoder, views, device, bindings,
+                profiler.as_deref_mut().and_then(|p| p.render_pass("aov")));
+        }
+        bindings.set_uniform(
+            SAMPLING, sampling.to_shader(post.accumulated()), queue);
+        let timestamps = profiler.as_deref_mut()
+            .and_then(|p| p.render_pass("raymarch"));
+        if let Some(stats) = stats {
+            stats.begin_frame(encoder, bindings);
+            scene.render(encoder, post.hdr_view(), device, bindings, timestamps);
+            stats.end_frame(encoder, bindings);
+        } else {
+            scene.render(encoder, post.hdr_view(), device, bindings, timestamps);
+        }
     }
+    post.render(encoder, device, queue, view, profiler.as_deref_mut());
+    if let Some(profiler) = profiler {
+        profiler.end_frame(encoder);
+    }
+}
+
+// Draws the two triangles of vs_main over view
+fn fullscreen_pass(
+    encoder: &mut wgpu::CommandEncoder,
+    view: &wgpu::TextureView,
+    label: &str,
+    pipeline: &wgpu::RenderPipeline,
+    pipeline_bind_groups: &mut PipelineBindGroups,
+    device: &wgpu::Device,
+    timestamps: Option<wgpu::RenderPassTimestampWrites>,
+) {
+    fullscreen_pass_targets(
+        encoder, &[view], label, pipeline, pipeline_bind_groups, device,
+        timestamps);
+}
 
+// fullscreen_pass for a pipeline with more than one target
+fn fullscreen_pass_targets(
+    encoder: &mut wgpu::CommandEncoder,
+    views: &[&wgpu::TextureView],
+    label: &str,
+    pipeline: &wgpu::RenderPipeline,
+    pipeline_bind_groups: &mut PipelineBindGroups,
+    device: &wgpu::Device,
+    timestamps: Option<wgpu::RenderPassTimestampWrites>,
+) {
+    let color_attachments: Vec<_> = views.iter()
+        .map(|&view| Some(wgpu::RenderPassColorAttachment {
+            view,
+            depth_slice: None,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::GREEN),
+                store: wgpu::StoreOp::Store,
+            },
+        }))
+        .collect();
+    // Create the renderpass which will clear the screen.
+    let mut renderpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &color_attachments,
+        depth_stencil_attachment: None,
+        timestamp_writes: timestamps,
+        occlusion_query_set: None,
+    });
+    renderpass.set_pipeline(pipeline);
+    pipeline_bind_groups.set_render_pass(device, &mut renderpass);
+    renderpass.draw(0..6, 0..1);
+}
+
+//  Pipeline for a shader with vs_main and fs_main drawing the whole
+//  target. source already has the declarations of pipeline_bind_groups.
+fn fullscreen_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    source: String,
+    surface_config: wgpu::TextureFormat,
+    pipeline_bind_groups: &mut PipelineBindGroups,
+) -> Result<wgpu::RenderPipeline> {
+    fullscreen_pipeline_targets(
+        device, label, source, "fs_main", &[surface_config], &[],
+        pipeline_bind_groups)
+}
+
+// fullscreen_pipeline with another fragment entry point drawing to one
+// target per format, and values for the shader's override constants
+fn fullscreen_pipeline_targets(
+    device: &wgpu::Device,
+    label: &str,
+    source: String,
+    entry_point: &str,
+    formats: &[wgpu::TextureFormat],
+    constants: &[(&str, f64)],
+    pipeline_bind_groups: &mut PipelineBindGroups,
+) -> Result<wgpu::RenderPipeline> {
+    log::debug!("{}", source);
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let shader = device.create_shader_module(
+        wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+    error::pop_scope(device, |message| Error::Shader {
+        label: label.to_string(),
+        message,
+    })?;
+
+    let render_pipeline_layout =
+          pipeline_bind_groups.pipeline_layout(device);
+    // let render_pipeline_layout =
+    //     device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+    //         label: Some("Render Pipeline Layout"),
+    //         bind_group_layouts: &[],
+    //         push_constant_ranges: &[],
+    //     });
+
+    let targets: Vec<_> = formats.iter()
+        .map(|&format| Some(wgpu::ColorTargetState {
+            format,
+            blend: Some(wgpu::BlendState::REPLACE),
+            write_mask: wgpu::ColorWrites::ALL,
+        }))
+        .collect();
+
+    // Where the layout and shader disagree
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: Some("vs_main"), // 1.
+            buffers: &[], // 2.
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants,
+                ..Default::default()
+            },
+        },
+        fragment: Some(wgpu::FragmentState { // 3.
+            module: &shader,
+            entry_point: Some(entry_point),
+            targets: &targets, // 4.
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants,
+                ..Default::default()
+            },
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList, // 1.
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw, // 2.
+            cull_mode: Some(wgpu::Face::Back),
+            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+            polygon_mode: wgpu::PolygonMode::Fill,
+            // Requires Features::DEPTH_CLIP_CONTROL
+            unclipped_depth: false,
+            // Requires Features::CONSERVATIVE_RASTERIZATION
+            conservative: false,
+        },
+        depth_stencil: None, // 1.
+        multisample: wgpu::MultisampleState {
+            count: 1, // 2.
+            mask: !0, // 3.
+            alpha_to_coverage_enabled: false, // 4.
+        },
+        multiview: None, // 5.
+        cache: None, // 6.
+    });
+    error::pop_scope(device, |message| Error::Binding {
+        label: label.to_string(),
+        message,
+    })?;
+    Ok(render_pipeline)
 }
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+
+use crate::uniform::ShaderType;
+
+// Light kinds, the values must match the LIGHT_* constants in shader.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightKind {
+    Directional = 0,
+    Point,
+    Spot,
+}
+
+// A light as written in a scene file. Angles are in degrees and
+// direction is the way the light travels, so a sun overhead points down.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LightConfig {
+    pub kind: LightKind,
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    // Distance at which a point or spot light has faded to nothing
+    pub range: f32,
+    // Spot cone, full intensity inside inner and none outside outer
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub shadows: bool,
+    // Penumbra size, 0 gives hard shadows and larger values softer ones
+    pub softness: f32,
+    // How far shadow rays look for occluders
+    pub shadow_distance: f32,
+}
+
+impl Default for LightConfig {
+    fn default() -> Self {
+        Self {
+            kind: LightKind::Directional,
+            position: [0.0, 0.0, 0.0],
+            direction: [0.0, -1.0, 0.0],
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            range: 10.0,
+            inner_angle: 20.0,
+            outer_angle: 30.0,
+            shadows: true,
+            softness: 0.0,
+            shadow_distance: 20.0,
+        }
+    }
+}
+
+impl LightConfig {
+    // The light the shader used to hard code, coming from the upper right
+    pub fn sun() -> Self {
+        let time = std::f32::consts::FRAC_PI_4;
+        let to_light = [time.sin(), (time * 0.5).cos() + 0.5, 0.5];
+        Self {
+            direction: normalize(to_light.map(|x| -x)),
+            color: [1.80, 1.27, 0.99],
+            ..Default::default()
+        }
+    }
+
+    pub fn to_shader(&self) -> Light {
+        Light {
+            position: self.position,
+            kind: self.kind as u32,
+            direction: normalize(self.direction),
+            range: self.range,
+            color: self.color,
+            intensity: self.intensity,
+            cos_inner: self.inner_angle.to_radians().cos(),
+            cos_outer: self.outer_angle.to_radians().cos(),
+            shadows: self.shadows as u32,
+            softness: self.softness,
+            shadow_distance: self.shadow_distance,
+            _pad: [0.0; 3],
+        }
+    }
+}
+
+// Ambient light blended between the ground and sky colors by how much
+// the surface faces up.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Ambient {
+    pub sky: [f32; 3],
+    pub ground: [f32; 3],
+    pub intensity: f32,
+}
+
+impl Default for Ambient {
+    fn default() -> Self {
+        Self {
+            sky: [0.03, 0.04, 0.1],
+            ground: [0.03, 0.04, 0.1],
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Ambient {
+    pub fn to_shader(&self, light_count: usize) -> Lighting {
+        Lighting {
+            sky: self.sky,
+            light_count: light_count as u32,
+            ground: self.ground,
+            intensity: self.intensity,
+        }
+    }
+}
+
+// Ambient occlusion sampled along the normal. Each sample is step
+// further out and counts half as much as the one before.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OcclusionConfig {
+    pub samples: u32,
+    pub step: f32,
+    pub strength: f32,
+}
+
+impl Default for OcclusionConfig {
+    fn default() -> Self {
+        Self {
+            samples: 5,
+            step: 0.03,
+            strength: 3.0,
+        }
+    }
+}
+
+impl OcclusionConfig {
+    pub fn to_shader(&self) -> Occlusion {
+        Occlusion {
+            samples: self.samples,
+            step: self.step,
+            strength: self.strength,
+            _pad: 0.0,
+        }
+    }
+}
+
+// Shader side of a light, vec3s are paired with a scalar to keep the
+// WGSL layout tightly packed.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Light {
+    pub position: [f32; 3],
+    pub kind: u32,
+    pub direction: [f32; 3],
+    pub range: f32,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    pub shadows: u32,
+    pub softness: f32,
+    pub shadow_distance: f32,
+    pub _pad: [f32; 3],
+}
+
+impl ShaderType for Light {
+    const WGSL_TYPE: &'static str = "Light";
+    const WGSL_STRUCT: &'static str = "
+struct Light {
+    position: vec3f,
+    kind: u32,
+    direction: vec3f,
+    range: f32,
+    color: vec3f,
+    intensity: f32,
+    cos_inner: f32,
+    cos_outer: f32,
+    shadows: u32,
+    softness: f32,
+    shadow_distance: f32,
+    _pad0: f32,
+    _pad1: f32,
+    _pad2: f32,
+}
+";
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Lighting {
+    pub sky: [f32; 3],
+    pub light_count: u32,
+    pub ground: [f32; 3],
+    pub intensity: f32,
+}
+
+impl ShaderType for Lighting {
+    const WGSL_TYPE: &'static str = "Lighting";
+    const WGSL_STRUCT: &'static str = "
+struct Lighting {
+    sky: vec3f,
+    light_count: u32,
+    ground: vec3f,
+    intensity: f32,
+}
+";
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Occlusion {
+    pub samples: u32,
+    pub step: f32,
+    pub strength: f32,
+    pub _pad: f32,
+}
+
+impl ShaderType for Occlusion {
+    const WGSL_TYPE: &'static str = "Occlusion";
+    const WGSL_STRUCT: &'static str = "
+struct Occlusion {
+    samples: u32,
+    step: f32,
+    strength: f32,
+    _pad: f32,
+}
+";
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if len == 0.0 { return v; }
+    v.map(|x| x / len)
+}
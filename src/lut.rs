@@ -0,0 +1,110 @@
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use wgpu::util::DeviceExt;
+
+use crate::error::{Error, Result};
+
+// A 3D color lookup table read from an Adobe / Resolve .cube file
+pub struct Lut {
+    // Entries along each axis
+    pub size: u32,
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+    // size^3 output colors, red changing fastest then green then blue
+    pub data: Vec<[f32; 3]>,
+}
+
+impl Lut {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::read(path)
+            .map_err(|source| Error::Io { path: path.to_path_buf(), source })
+    }
+
+    fn read(path: &Path) -> std::io::Result<Self> {
+        let file = BufReader::new(std::fs::File::open(path)?);
+        let mut lut = Self {
+            size: 0,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+            data: Vec::new(),
+        };
+        for line in file.lines() {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                [] => {}
+                [w, ..] if w.starts_with('#') => {}
+                ["TITLE", ..] => {}
+                ["LUT_3D_SIZE", n] => {
+                    lut.size = n.parse().map_err(|_| invalid("bad size"))?;
+                }
+                ["LUT_1D_SIZE", _] => return Err(invalid("1D LUTs aren't supported")),
+                ["DOMAIN_MIN", r, g, b] => lut.domain_min = parse_rgb([r, g, b])?,
+                ["DOMAIN_MAX", r, g, b] => lut.domain_max = parse_rgb([r, g, b])?,
+                [r, g, b] => lut.data.push(parse_rgb([r, g, b])?),
+                _ => return Err(invalid(&format!("unexpected line {line}"))),
+            }
+        }
+        if lut.size < 2 {
+            return Err(invalid("missing LUT_3D_SIZE"));
+        }
+        if lut.size.checked_pow(3).map(|n| n as usize) != Some(lut.data.len()) {
+            return Err(invalid(&format!(
+                "{} entries for size {}", lut.data.len(), lut.size)));
+        }
+        log::info!("lut {} is {}^3", path.display(), lut.size);
+        Ok(lut)
+    }
+
+    // The table order is the 3D texture layout, red along x
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<wgpu::Texture> {
+        let max = device.limits().max_texture_dimension_3d;
+        if self.size > max {
+            return Err(Error::TooLarge {
+                what: "lut".into(),
+                size: format!("{}^3", self.size),
+                max: format!("{max}^3"),
+            });
+        }
+        let data: Vec<half::f16> = self.data.iter()
+            .flat_map(|&[r, g, b]| [r, g, b, 1.0])
+            .map(half::f16::from_f32)
+            .collect();
+        Ok(device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("lut"),
+                size: wgpu::Extent3d {
+                    width: self.size,
+                    height: self.size,
+                    depth_or_array_layers: self.size,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D3,
+                format: wgpu::TextureFormat::Rgba16Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(&data),
+        ))
+    }
+}
+
+fn parse_rgb(words: [&str; 3]) -> std::io::Result<[f32; 3]> {
+    let mut rgb = [0.0; 3];
+    for (c, w) in rgb.iter_mut().zip(words) {
+        *c = w.parse().map_err(|_| invalid(&format!("bad number {w}")))?;
+    }
+    Ok(rgb)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
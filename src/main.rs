@@ -1,9 +1,242 @@
-use winit::{
-    application::ApplicationHandler,
-    event::WindowEvent,
-    event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
-    window::{Window, WindowId},
-};
+use std::path::{Path, PathBuf};
+
+use winit::event_loop::{ControlFlow, EventLoop};
+
+use raymarch::bench::{self, Bench, BenchRun};
+use raymarch::camera::{Projection, Stereo};
+use raymarch::config::SceneConfig;
+use raymarch::cpu::CpuRenderer;
+use raymarch::gpu::{Backend, Power, PresentMode};
+use raymarch::color::OutputTransform;
+use raymarch::march::{NormalMethod, Quality};
+use raymarch::redraw::Redraw;
+use raymarch::view::ViewMode;
+
+const USAGE: &str = "\
+usage: raymarch [SCENE.toml] [options]
+    --cpu FILE.ppm      render with the CPU reference renderer and exit
+    --output FILE.ppm   render with the GPU to a file and exit
+    --size WIDTHxHEIGHT image size for --cpu and --output (default 512x512)
+    --view MODE         what to show: shaded, ao, normals, depth, steps,
+                        material, shadow, miss, slice
+    --transform NAME    output transform: srgb, linear, reinhard, aces, agx
+    --exposure X        scales the color before the output transform
+    --samples N         N x N rays per pixel
+    --quality NAME      march quality preset: draft, normal, final
+    --normals METHOD    forward, central, tetrahedral, analytic
+    --projection NAME   perspective, orthographic, fisheye, equirectangular
+    --stereo MODE       none, side_by_side, top_bottom
+    --path-trace        path trace instead of direct lighting
+    --denoise           run the denoiser on the image
+    --stats             log GPU march counts (RUST_LOG=info to see them)
+    --profile           log average pass times, per pass when the GPU has
+                        timestamp queries
+    --bench FILE.json   render [bench] frames along its camera path at
+                        --size and write a report of times and march counts
+    --windowed          run --bench in a window instead of offscreen
+    --redraw MODE       on_demand to draw only after a change, continuous
+                        to draw every frame
+    --backend LIST      graphics APIs to try, comma separated: vulkan,
+                        metal, dx12, gl
+    --power NAME        adapter power preference: default, low, high
+    --fallback-adapter  use a software adapter
+    --adapter NAME      use the adapter with NAME in its name
+    --present-mode NAME fifo, mailbox or immediate, falling back to fifo
+    --frame-latency N   frames queued for the display at most
+    --march-bench       count CPU march steps with the [march] settings and
+                        with plain sphere tracing, then exit
+
+In the window the arrow keys orbit the camera, W and S move it in and out,
+P changes the projection, E the stereo mode and Q the quality preset.
+O shows and hides the overlay with the frame rate and what is shown.
+R switches between drawing on demand and continuously.
+F5 reads the scene file again, keeping the options given here.
+V steps through the view modes, or 1 to 9 pick one in the order above.
+In the slice view [ and ] move the plane and X, Y and Z turn it to face
+along that axis.";
+
+// Command line, small enough not to need a parser crate
+#[derive(Default)]
+struct Args {
+    scene: Option<PathBuf>,
+    cpu: Option<PathBuf>,
+    output: Option<PathBuf>,
+    size: Option<(u32, u32)>,
+    view: Option<ViewMode>,
+    transform: Option<OutputTransform>,
+    exposure: Option<f32>,
+    samples: Option<u32>,
+    quality: Option<Quality>,
+    normals: Option<NormalMethod>,
+    projection: Option<Projection>,
+    stereo: Option<Stereo>,
+    path_trace: bool,
+    denoise: bool,
+    march_bench: bool,
+    stats: bool,
+    profile: bool,
+    bench: Option<PathBuf>,
+    windowed: bool,
+    redraw: Option<Redraw>,
+    backends: Option<Vec<Backend>>,
+    power: Option<Power>,
+    fallback_adapter: bool,
+    adapter: Option<String>,
+    present_mode: Option<PresentMode>,
+    frame_latency: Option<u32>,
+}
+
+impl Args {
+    fn parse() -> Self {
+        let mut args = Self::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next()
+                .unwrap_or_else(|| usage(&format!("{arg} needs a value")));
+            match arg.as_str() {
+                "--cpu" => args.cpu = Some(value().into()),
+                "--output" => args.output = Some(value().into()),
+                "--size" => args.size = Some(parse_size(&value())),
+                "--view" => args.view = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--transform" => args.transform = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--exposure" => {
+                    let v = value();
+                    args.exposure = Some(v.parse().unwrap_or_else(
+                        |_| usage(&format!("bad exposure {v}"))));
+                }
+                "--samples" => {
+                    let v = value();
+                    args.samples = Some(v.parse().unwrap_or_else(
+                        |_| usage(&format!("bad sample count {v}"))));
+                }
+                "--quality" => args.quality = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--normals" => args.normals = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--projection" => args.projection = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--stereo" => args.stereo = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--path-trace" => args.path_trace = true,
+                "--denoise" => args.denoise = true,
+                "--march-bench" => args.march_bench = true,
+                "--stats" => args.stats = true,
+                "--profile" => args.profile = true,
+                "--bench" => args.bench = Some(value().into()),
+                "--windowed" => args.windowed = true,
+                "--redraw" => args.redraw = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--backend" => args.backends = Some(
+                    value().split(',')
+                        .map(|b| b.parse().unwrap_or_else(|e: String| usage(&e)))
+                        .collect()),
+                "--power" => args.power = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--fallback-adapter" => args.fallback_adapter = true,
+                "--adapter" => args.adapter = Some(value()),
+                "--present-mode" => args.present_mode = Some(
+                    value().parse().unwrap_or_else(|e: String| usage(&e))),
+                "--frame-latency" => {
+                    let v = value();
+                    args.frame_latency = Some(v.parse().unwrap_or_else(
+                        |_| usage(&format!("bad frame latency {v}"))));
+                }
+                "-h" | "--help" => usage(""),
+                _ if arg.starts_with('-') => usage(&format!("unknown {arg}")),
+                _ => args.scene = Some(arg.into()),
+            }
+        }
+        args
+    }
+
+    // What the options change in a scene, also done to a reloaded one
+    fn apply(&self, config: &mut SceneConfig) {
+        if let Some(view) = self.view {
+            config.view = view;
+        }
+        if let Some(transform) = self.transform {
+            config.output.transform = transform;
+        }
+        if let Some(exposure) = self.exposure {
+            config.output.exposure = exposure;
+        }
+        if let Some(samples) = self.samples {
+            config.sampling.samples = samples;
+        }
+        if let Some(quality) = self.quality {
+            config.march.quality = quality;
+        }
+        if let Some(method) = self.normals {
+            config.march.normal_method = Some(method);
+        }
+        if let Some(projection) = self.projection {
+            config.camera.projection = projection;
+        }
+        if let Some(stereo) = self.stereo {
+            config.camera.stereo = stereo;
+        }
+        if self.path_trace {
+            config.path_trace.enabled = true;
+        }
+        if self.denoise {
+            config.denoise.enabled = true;
+        }
+        if self.stats {
+            config.stats.enabled = true;
+        }
+        if self.profile {
+            config.profile.enabled = true;
+        }
+        if let Some(redraw) = self.redraw {
+            config.redraw = redraw;
+        }
+        if let Some(backends) = self.backends.clone() {
+            config.gpu.backends = backends;
+        }
+        if let Some(power) = self.power {
+            config.gpu.power = power;
+        }
+        if self.fallback_adapter {
+            config.gpu.fallback = true;
+        }
+        if let Some(adapter) = self.adapter.clone() {
+            config.gpu.adapter = Some(adapter);
+        }
+        if let Some(mode) = self.present_mode {
+            config.gpu.present_mode = mode;
+        }
+        if let Some(latency) = self.frame_latency {
+            config.gpu.frame_latency = latency;
+        }
+    }
+}
+
+fn parse_size(size: &str) -> (u32, u32) {
+    let (width, height) = size.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .unwrap_or_else(|| usage(&format!("bad size {size}")));
+    if width == 0 || height == 0 {
+        usage(&format!("size {size} has no pixels"));
+    }
+    (width, height)
+}
+
+fn usage(message: &str) -> ! {
+    if !message.is_empty() {
+        eprintln!("{message}");
+    }
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+// For errors that aren't the caller's usage, like a bad scene file or
+// a GPU that can't be set up
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
 
 fn main() {
     // wgpu uses `log` for all of our logging, so we initialize a logger with the `env_logger` crate.
@@ -12,20 +245,136 @@ fn main() {
     // documentation for more information.
     env_logger::init();
 
+    let args = Args::parse();
+
+    // Optional scene file, otherwise the built in default scene
+    let mut config = match &args.scene {
+        Some(path) => SceneConfig::load(path)
+            .unwrap_or_else(|e| fail(&format!("can't load scene {e}"))),
+        None => SceneConfig::default(),
+    };
+    args.apply(&mut config);
+
+    if args.march_bench {
+        let (width, height) = args.size.unwrap_or((512, 512));
+        march_bench(&config, width, height);
+        return;
+    }
+    if let Some(path) = &args.cpu {
+        let (width, height) = args.size.unwrap_or((512, 512));
+        render_cpu(&config, width, height, path);
+        return;
+    }
+    if let Some(path) = &args.output {
+        let (width, height) = args.size.unwrap_or((512, 512));
+        render_offscreen(&config, width, height, path);
+        return;
+    }
+    let mut bench = args.bench.as_ref().map(|report| {
+        bench::configure(&mut config);
+        let (width, height) = args.size.unwrap_or((512, 512));
+        BenchRun {
+            bench: Bench::new(&config.bench, &config.camera),
+            scene: args.scene.as_ref()
+                .map_or("default".into(), |path| path.display().to_string()),
+            width,
+            height,
+            report: report.clone(),
+        }
+    });
+    if !args.windowed && let Some(run) = bench.take() {
+        pollster::block_on(bench::offscreen(&config, run))
+            .unwrap_or_else(|e| fail(&format!("bench failed: {e}")));
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
 
     // When the current loop iteration finishes, immediately begin a new
     // iteration regardless of whether or not new events are available to
     // process. Preferred for applications that want to render as fast as
     // possible, like games.
-    event_loop.set_control_flow(ControlFlow::Poll);
+    // event_loop.set_control_flow(ControlFlow::Poll);
 
     // When the current loop iteration finishes, suspend the thread until
     // another event arrives. Helps keeping CPU utilization low if nothing
     // is happening, which is preferred if the application might be idling in
-    // the background.
-    // event_loop.set_control_flow(ControlFlow::Wait);
+    // the background. The app switches to Poll when it draws
+    // continuously, see Redraw.
+    event_loop.set_control_flow(ControlFlow::Wait);
 
-    let mut app = raymarch::App::default();
+    let title = match &args.scene {
+        Some(path) => format!("raymarch - {}", path.display()),
+        None => "raymarch".to_string(),
+    };
+    let mut app = raymarch::App::new(config, title);
+    if let Some(path) = args.scene.clone() {
+        app = app.with_reload(move || {
+            let mut config = SceneConfig::load(&path)?;
+            args.apply(&mut config);
+            Ok(config)
+        });
+    }
+    if let Some(run) = bench {
+        app = app.with_bench(run);
+    }
     event_loop.run_app(&mut app).unwrap();
+    if let Some(e) = app.take_error() {
+        fail(&e.to_string());
+    }
+}
+
+fn render_cpu(config: &SceneConfig, width: u32, height: u32, path: &Path) {
+    let start = std::time::Instant::now();
+    let renderer = CpuRenderer::new(config)
+        .unwrap_or_else(|e| fail(&format!("can't set up the cpu renderer: {e}")));
+    let pixels: Vec<[u8; 3]> = renderer
+        .render(width, height)
+        .into_iter()
+        .map(|c| renderer.encode(c))
+        .collect();
+    log::info!("cpu render took {:?}", start.elapsed());
+    raymarch::image::write_ppm(path, width, height, &pixels)
+        .unwrap_or_else(|e| fail(&format!("can't write {}: {e}", path.display())));
+}
+
+fn render_offscreen(config: &SceneConfig, width: u32, height: u32, path: &Path) {
+    let start = std::time::Instant::now();
+    let pixels = pollster::block_on(
+        raymarch::offscreen::render(config, width, height))
+        .unwrap_or_else(|e| fail(&format!("can't render on the gpu: {e}")));
+    log::info!("gpu render took {:?}", start.elapsed());
+    raymarch::image::write_ppm(path, width, height, &pixels)
+        .unwrap_or_else(|e| fail(&format!("can't write {}: {e}", path.display())));
+}
+
+// Renders on the CPU with plain sphere tracing, no relaxation, cone or
+// far plane, then as configured, and prints how many steps each took
+fn march_bench(config: &SceneConfig, width: u32, height: u32) {
+    let mut plain = config.clone();
+    plain.march.relaxation = Some(1.0);
+    plain.march.cone = Some(0.0);
+    plain.march.far_plane = Some(0.0);
+
+    let pixel_count = (width * height) as f64;
+    let mut images = Vec::new();
+    for (name, config) in [("plain", &plain), ("configured", config)] {
+        let renderer = CpuRenderer::new(config)
+            .unwrap_or_else(|e| fail(&format!("can't set up the cpu renderer: {e}")));
+        let start = std::time::Instant::now();
+        let pixels: Vec<[u8; 3]> = renderer
+            .render(width, height)
+            .into_iter()
+            .map(|c| renderer.encode(c))
+            .collect();
+        println!(
+            "{name:>10}: {:.1} steps per pixel, {:?}",
+            renderer.steps() as f64 / pixel_count, start.elapsed());
+        images.push(pixels);
+    }
+    let max_diff = images[0].iter().zip(&images[1])
+        .flat_map(|(a, b)| a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)))
+        .max()
+        .unwrap_or(0);
+    println!("largest difference {max_diff} of 255");
 }
@@ -0,0 +1,219 @@
+use serde::Deserialize;
+
+// Trade between speed and accuracy of marching. Each preset is a set of
+// March values, anything given in [march] overrides it.
+named_enum! {
+    #[derive(Default)]
+    pub enum Quality("quality") {
+        // For moving around
+        Draft = "draft",
+        #[default]
+        Normal = "normal",
+        // For still renders, slow
+        Final = "final",
+    }
+}
+
+impl Quality {
+    pub const fn march(self) -> March {
+        match self {
+            Quality::Draft => March {
+                max_steps: 64,
+                epsilon: 0.004,
+                start_distance: 1.0,
+                max_distance: 50.0,
+                normal_epsilon: 0.002,
+                relaxation: 1.6,
+                cone: 1.0,
+                far_plane: 0.0,
+                normal_method: NormalMethod::Forward,
+                normal_scale: 0.001,
+            },
+            Quality::Normal => March {
+                max_steps: 128,
+                epsilon: 0.001,
+                start_distance: 1.0,
+                max_distance: 100.0,
+                normal_epsilon: 0.001,
+                relaxation: 1.0,
+                cone: 0.0,
+                far_plane: 0.0,
+                normal_method: NormalMethod::Forward,
+                normal_scale: 0.0005,
+            },
+            Quality::Final => March {
+                max_steps: 512,
+                epsilon: 0.0002,
+                start_distance: 1.0,
+                max_distance: 200.0,
+                normal_epsilon: 0.0005,
+                relaxation: 1.0,
+                cone: 0.0,
+                far_plane: 0.0,
+                normal_method: NormalMethod::Tetrahedral,
+                normal_scale: 0.00025,
+            },
+        }
+    }
+}
+
+// How calcNormal finds the gradient of the distance field. Must match
+// the NORMAL_ constants in shader.wgsl.
+named_enum! {
+    #[derive(Default)]
+    pub enum NormalMethod("normal method") {
+        // 4 samples, one sided so a little off toward +x, +y and +z
+        #[default]
+        Forward = "forward",
+        // 6 samples either side
+        Central = "central",
+        // 4 samples at the corners of a tetrahedron, as good as central
+        // for the cost of forward
+        Tetrahedral = "tetrahedral",
+        // The gradient worked out from the shapes, exact but only as
+        // right as theGradient is kept with theShape
+        Analytic = "analytic",
+    }
+}
+
+//  [march]
+//  quality = "final"
+//  max_steps = 1024
+//  relaxation = 1.6
+//  far_plane = 20.0
+//  normal_method = "tetrahedral"
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarchConfig {
+    pub quality: Quality,
+    pub max_steps: Option<u32>,
+    pub epsilon: Option<f32>,
+    pub start_distance: Option<f32>,
+    pub max_distance: Option<f32>,
+    pub normal_epsilon: Option<f32>,
+    pub relaxation: Option<f32>,
+    pub cone: Option<f32>,
+    pub far_plane: Option<f32>,
+    pub normal_method: Option<NormalMethod>,
+    pub normal_scale: Option<f32>,
+}
+
+impl MarchConfig {
+    // The preset with the overrides applied
+    pub fn march(&self) -> March {
+        let mut march = self.quality.march();
+        if let Some(n) = self.max_steps { march.max_steps = n as i32; }
+        if let Some(e) = self.epsilon { march.epsilon = e; }
+        if let Some(d) = self.start_distance { march.start_distance = d; }
+        if let Some(d) = self.max_distance { march.max_distance = d; }
+        if let Some(e) = self.normal_epsilon { march.normal_epsilon = e; }
+        if let Some(r) = self.relaxation { march.relaxation = r.max(1.0); }
+        if let Some(c) = self.cone { march.cone = c.max(0.0); }
+        if let Some(d) = self.far_plane { march.far_plane = d.max(0.0); }
+        if let Some(m) = self.normal_method { march.normal_method = m; }
+        if let Some(s) = self.normal_scale { march.normal_scale = s.max(0.0); }
+        march
+    }
+}
+
+// Fixed for the life of a pipeline, so they are WGSL override constants
+// rather than uniforms and the compiler can unroll and fold with them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct March {
+    pub max_steps: i32,
+    // Hit threshold, scaled by the distance marched
+    pub epsilon: f32,
+    // Where primary rays start
+    pub start_distance: f32,
+    // Rays that get this far have missed
+    pub max_distance: f32,
+    // Offset for the gradient in calcNormal
+    pub normal_epsilon: f32,
+    // Steps are this many times the distance, falling back to plain
+    // sphere tracing after an overshoot. 1 is plain sphere tracing, 1.2
+    // to 1.8 usually saves steps.
+    pub relaxation: f32,
+    // Hits are taken once the distance is under the radius of this many
+    // pixels at the hit, if that is more than epsilon. 0 is off.
+    pub cone: f32,
+    // How far the camera sees along its axis, 0 is as far as
+    // max_distance
+    pub far_plane: f32,
+    pub normal_method: NormalMethod,
+    // normal_epsilon grows to this times the distance from the ray's
+    // origin, so far away surfaces aren't sampled finer than a pixel.
+    // A pixel 1920 across with the default 90 degree fov is about 0.001
+    // per unit of distance, the finer presets use a fraction of that.
+    pub normal_scale: f32,
+}
+
+impl March {
+    // For PipelineCompilationOptions::constants, the names of the
+    // overrides in shader.wgsl
+    pub fn constants(&self) -> [(&'static str, f64); 10] {
+        [
+            ("maxSteps", self.max_steps as f64),
+            ("epsilon", self.epsilon as f64),
+            ("startDistance", self.start_distance as f64),
+            ("maxDistance", self.max_distance as f64),
+            ("normalEpsilon", self.normal_epsilon as f64),
+            ("relaxation", self.relaxation as f64),
+            ("cone", self.cone as f64),
+            ("farPlane", self.far_plane as f64),
+            ("normalMethod", self.normal_method as u32 as f64),
+            ("normalScale", self.normal_scale as f64),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_overrides_is_the_preset() {
+        for &quality in Quality::ALL {
+            let config = MarchConfig { quality, ..Default::default() };
+            assert_eq!(config.march(), quality.march());
+        }
+    }
+
+    #[test]
+    fn overrides_replace_only_their_own_value() {
+        let config = MarchConfig {
+            quality: Quality::Final,
+            max_steps: Some(1000),
+            epsilon: Some(0.01),
+            normal_method: Some(NormalMethod::Central),
+            ..Default::default()
+        };
+        let expected = March {
+            max_steps: 1000,
+            epsilon: 0.01,
+            normal_method: NormalMethod::Central,
+            ..Quality::Final.march()
+        };
+        assert_eq!(config.march(), expected);
+    }
+
+    #[test]
+    fn overrides_are_kept_in_range() {
+        let config = MarchConfig {
+            relaxation: Some(0.5),
+            cone: Some(-1.0),
+            far_plane: Some(-2.0),
+            normal_scale: Some(-0.1),
+            ..Default::default()
+        };
+        let march = config.march();
+        assert_eq!(march.relaxation, 1.0);
+        assert_eq!(march.cone, 0.0);
+        assert_eq!(march.far_plane, 0.0);
+        assert_eq!(march.normal_scale, 0.0);
+    }
+
+    #[test]
+    fn presets_scale_the_normal_epsilon() {
+        assert!(Quality::ALL.iter().all(|q| q.march().normal_scale > 0.0));
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::uniform::ShaderType;
+
+// The materials the shader knows by name. The index is the material id,
+// so the order must match the material constants in shader.wgsl.
+pub const MATERIAL_NAMES: [&str; 6] =
+    ["black", "red", "green", "blue", "mirror", "glass"];
+
+pub fn default_materials() -> Vec<Material> {
+    let diffuse = |r, g, b| Material::new([r, g, b], 0.0, 0.0, 1.0);
+    vec![
+        diffuse(0.0, 0.0, 0.0),
+        diffuse(0.2, 0.0, 0.0),
+        diffuse(0.0, 0.2, 0.0),
+        diffuse(0.0, 0.0, 0.2),
+        Material::new([0.9, 0.9, 0.9], 0.9, 0.0, 1.0),
+        Material::new([1.0, 1.0, 1.0], 0.0, 0.95, 1.5),
+    ]
+}
+
+// Changes to one of the named materials in a scene file, anything left
+// out keeps its default.
+//
+//  [materials.green]
+//  reflectivity = 0.5
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialConfig {
+    pub color: Option<[f32; 3]>,
+    // Fraction of light mirrored, boosted towards 1 at grazing angles
+    pub reflectivity: Option<f32>,
+    // Fraction of light let through, split with reflection by Fresnel
+    pub transparency: Option<f32>,
+    pub ior: Option<f32>,
+    // Light given off, only the path tracer lights other surfaces with it
+    pub emission: Option<[f32; 3]>,
+}
+
+impl MaterialConfig {
+    fn apply(&self, m: &mut Material) {
+        if let Some(c) = self.color { m.color = [c[0], c[1], c[2], 1.0]; }
+        if let Some(r) = self.reflectivity { m.reflectivity = r; }
+        if let Some(t) = self.transparency { m.transparency = t; }
+        if let Some(i) = self.ior { m.ior = i; }
+        if let Some(e) = self.emission { m.emission = e; }
+    }
+}
+
+// The material table with the scene file changes applied
+pub fn materials(
+    configs: &BTreeMap<String, MaterialConfig>,
+) -> Result<Vec<Material>, String> {
+    let mut materials = default_materials();
+    for (name, config) in configs {
+        let id = MATERIAL_NAMES.iter().position(|n| n == name)
+            .ok_or_else(|| format!(
+                "unknown material {name}, expected one of {MATERIAL_NAMES:?}"))?;
+        config.apply(&mut materials[id]);
+    }
+    Ok(materials)
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Material {
+    pub color: [f32; 4],
+    pub reflectivity: f32,
+    pub transparency: f32,
+    pub ior: f32,
+    pub _pad0: f32,
+    pub emission: [f32; 3],
+    pub _pad1: f32,
+}
+
+impl Material {
+    fn new(
+        color: [f32; 3], reflectivity: f32, transparency: f32, ior: f32,
+    ) -> Self {
+        Self {
+            color: [color[0], color[1], color[2], 1.0],
+            reflectivity,
+            transparency,
+            ior,
+            _pad0: 0.0,
+            emission: [0.0; 3],
+            _pad1: 0.0,
+        }
+    }
+}
+
+impl ShaderType for Material {
+    const WGSL_TYPE: &'static str = "Material";
+    const WGSL_STRUCT: &'static str = "
+struct Material {
+    color: vec4f,
+    reflectivity: f32,
+    transparency: f32,
+    ior: f32,
+    _pad0: f32,
+    emission: vec3f,
+    _pad1: f32,
+}
+";
+}
+
+// Secondary rays. WGSL can't recurse so the shader keeps its own stack
+// of pending rays, bounces beyond max_bounces are dropped.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TraceConfig {
+    pub max_bounces: u32,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self { max_bounces: 3 }
+    }
+}
+
+impl TraceConfig {
+    // Each bounce can leave one extra ray waiting on the stack
+    pub const MAX_BOUNCES: u32 = 6;
+
+    pub fn to_shader(&self) -> Trace {
+        Trace {
+            max_bounces: self.max_bounces.min(Self::MAX_BOUNCES),
+            _pad: [0; 3],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Trace {
+    pub max_bounces: u32,
+    pub _pad: [u32; 3],
+}
+
+impl ShaderType for Trace {
+    const WGSL_TYPE: &'static str = "Trace";
+    const WGSL_STRUCT: &'static str = "
+struct Trace {
+    max_bounces: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+}
+";
+}
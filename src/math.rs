@@ -0,0 +1,92 @@
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+// Just enough vector math to mirror the shader on the CPU. Names follow
+// WGSL so ported code reads the same.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+pub const fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+    Vec3 { x, y, z }
+}
+
+impl Vec3 {
+    pub const ZERO: Vec3 = vec3(0.0, 0.0, 0.0);
+
+    pub fn splat(v: f32) -> Self { vec3(v, v, v) }
+
+    pub fn dot(self, o: Vec3) -> f32 {
+        self.x * o.x + self.y * o.y + self.z * o.z
+    }
+    pub fn cross(self, o: Vec3) -> Vec3 {
+        vec3(
+            self.y * o.z - self.z * o.y,
+            self.z * o.x - self.x * o.z,
+            self.x * o.y - self.y * o.x,
+        )
+    }
+    pub fn length(self) -> f32 { self.dot(self).sqrt() }
+    pub fn normalize(self) -> Vec3 { self / self.length() }
+
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Vec3 {
+        vec3(f(self.x), f(self.y), f(self.z))
+    }
+    pub fn abs(self) -> Vec3 { self.map(f32::abs) }
+    pub fn max(self, o: Vec3) -> Vec3 {
+        vec3(self.x.max(o.x), self.y.max(o.y), self.z.max(o.z))
+    }
+    pub fn max_element(self) -> f32 { self.x.max(self.y).max(self.z) }
+    pub fn mix(self, o: Vec3, t: f32) -> Vec3 { self + (o - self) * t }
+
+    // WGSL reflect and refract, n must be normalized
+    pub fn reflect(self, n: Vec3) -> Vec3 { self - n * (2.0 * n.dot(self)) }
+    pub fn refract(self, n: Vec3, eta: f32) -> Vec3 {
+        let cos = n.dot(self);
+        let k = 1.0 - eta * eta * (1.0 - cos * cos);
+        if k < 0.0 { return Vec3::ZERO; }
+        self * eta - n * (eta * cos + k.sqrt())
+    }
+}
+
+impl From<[f32; 3]> for Vec3 {
+    fn from(v: [f32; 3]) -> Self { vec3(v[0], v[1], v[2]) }
+}
+
+impl From<Vec3> for [f32; 3] {
+    fn from(v: Vec3) -> Self { [v.x, v.y, v.z] }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+    fn add(self, o: Vec3) -> Vec3 { vec3(self.x + o.x, self.y + o.y, self.z + o.z) }
+}
+impl Sub for Vec3 {
+    type Output = Vec3;
+    fn sub(self, o: Vec3) -> Vec3 { vec3(self.x - o.x, self.y - o.y, self.z - o.z) }
+}
+impl Mul for Vec3 {
+    type Output = Vec3;
+    fn mul(self, o: Vec3) -> Vec3 { vec3(self.x * o.x, self.y * o.y, self.z * o.z) }
+}
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, s: f32) -> Vec3 { vec3(self.x * s, self.y * s, self.z * s) }
+}
+impl Div<f32> for Vec3 {
+    type Output = Vec3;
+    fn div(self, s: f32) -> Vec3 { vec3(self.x / s, self.y / s, self.z / s) }
+}
+impl Neg for Vec3 {
+    type Output = Vec3;
+    fn neg(self) -> Vec3 { vec3(-self.x, -self.y, -self.z) }
+}
+
+pub fn saturate(x: f32) -> f32 { x.clamp(0.0, 1.0) }
+
+pub fn smoothstep(e0: f32, e1: f32, x: f32) -> f32 {
+    let t = saturate((x - e0) / (e1 - e0));
+    t * t * (3.0 - 2.0 * t)
+}
@@ -0,0 +1,105 @@
+//  Enums picked by name in the scene file and on the command line. Each
+//  value is listed once with its name, and gets ALL in the order given,
+//  name, next and FromStr from that, so the names can't drift apart.
+//
+//  named_enum! {
+//      #[derive(Default)]
+//      pub enum ViewMode("view mode") {
+//          #[default]
+//          Shaded = "shaded",
+//          Occlusion = "ao",
+//      }
+//  }
+//
+//  The values count up from 0 in order, which some shader constants
+//  rely on.
+
+macro_rules! named_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident($what:literal) {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident = $text:literal,
+            )+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+        $vis enum $name {
+            $(
+                $(#[$variant_meta])*
+                #[serde(rename = $text)]
+                $variant,
+            )+
+        }
+
+        impl $name {
+            pub const ALL: &'static [$name] = &[$($name::$variant),+];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($name::$variant => $text,)+
+                }
+            }
+
+            pub fn next(self) -> Self {
+                Self::ALL[(self as usize + 1) % Self::ALL.len()]
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+                Self::ALL.iter()
+                    .copied()
+                    .find(|v| v.name() == s)
+                    .ok_or_else(|| format!(concat!("unknown ", $what, " {}"), s))
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    named_enum! {
+        enum Fruit("fruit") {
+            Apple = "apple",
+            BloodOrange = "blood_orange",
+            Pear = "pear",
+        }
+    }
+
+    #[test]
+    fn values_count_up_in_order() {
+        assert_eq!(Fruit::ALL, [Fruit::Apple, Fruit::BloodOrange, Fruit::Pear]);
+        for (i, fruit) in Fruit::ALL.iter().enumerate() {
+            assert_eq!(*fruit as usize, i);
+        }
+    }
+
+    #[test]
+    fn next_wraps_around() {
+        assert_eq!(Fruit::Apple.next(), Fruit::BloodOrange);
+        assert_eq!(Fruit::Pear.next(), Fruit::Apple);
+    }
+
+    #[test]
+    fn unknown_names_say_what_they_are() {
+        assert_eq!(Fruit::from_str("plum"), Err("unknown fruit plum".into()));
+        assert!(Fruit::from_str("Apple").is_err());
+    }
+
+    #[test]
+    fn serde_uses_the_names() {
+        #[derive(serde::Deserialize)]
+        struct Basket {
+            fruit: Fruit,
+        }
+        let basket: Basket = toml::from_str("fruit = \"blood_orange\"").unwrap();
+        assert_eq!(basket.fruit, Fruit::BloodOrange);
+    }
+}
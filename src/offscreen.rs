@@ -0,0 +1,188 @@
+//  Renders a scene with the GPU to an image instead of a window, so GPU
+//  output can be saved and compared with the CPU renderer's.
+
+use crate::camera::CameraConfig;
+use crate::config::SceneConfig;
+use crate::error::{Error, Result};
+use crate::post::{PostChain, HDR_FORMAT};
+use crate::profile::{FrameTiming, Profiler};
+use crate::sampling::SamplingConfig;
+use crate::stats::{MarchStats, Stats};
+use crate::uniform::PipelineBindGroups;
+use crate::{Renderer, Scene, BINDINGS, CAMERA};
+
+// 8 bit with the sRGB encoding done in hardware, like most surfaces
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+// Renders config at width x height, returns the pixels row by row from
+// the top left. Fails if the size is more than the device can render to.
+pub async fn render(
+    config: &SceneConfig,
+    width: u32,
+    height: u32,
+) -> Result<Vec<[u8; 3]>> {
+    let mut offscreen = Offscreen::new(config, width, height).await?;
+    // Every frame of a progressive render, each submitted on its own
+    for _ in 0..offscreen.sampling.frames() {
+        offscreen.render_frame();
+    }
+    // The frames since the last full window
+    if let Some(timing) = offscreen.profiler.as_mut().and_then(|p| p.flush()) {
+        log::info!("frame timing: {timing}");
+    }
+    Ok(offscreen.read_pixels())
+}
+
+// What a window's Renderer has, drawing to a texture that can be read
+// back
+pub struct Offscreen {
+    adapter: wgpu::Adapter,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    bindings: PipelineBindGroups,
+    scene: Scene,
+    post: PostChain,
+    sampling: SamplingConfig,
+    stats: Option<Stats>,
+    profiler: Option<Profiler>,
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+}
+
+impl Offscreen {
+    // Fails if the size is more than the device can render to
+    pub async fn new(config: &SceneConfig, width: u32, height: u32) -> Result<Self> {
+        let instance = config.gpu.instance();
+        let (adapter, device, queue) =
+            crate::request_device(&config.gpu, &instance, None).await?;
+        let max = device.limits().max_texture_dimension_2d;
+        if width > max || height > max {
+            return Err(Error::TooLarge {
+                what: "image".into(),
+                size: format!("{width}x{height}"),
+                max: format!("{max}x{max}"),
+            });
+        }
+
+        let mut bindings = PipelineBindGroups::new(BINDINGS);
+        Renderer::init_bindings(
+            &mut bindings, width, height, config, &device, &queue,
+        )?;
+        let stats = Stats::new(&config.stats, &adapter, &device, &mut bindings);
+        let profiler = Profiler::new(&config.profile, &device, &queue);
+        let scene = Scene::new(
+            &device, HDR_FORMAT, &mut bindings, config.march.march(),
+            config.denoise.enabled)?;
+        let post = PostChain::new(
+            &adapter, &device, &queue, config, width, height, FORMAT)?;
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("offscreen"),
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&Default::default());
+        Ok(Self {
+            adapter,
+            device,
+            queue,
+            bindings,
+            scene,
+            post,
+            sampling: config.effective_sampling(),
+            stats,
+            profiler,
+            texture,
+            view,
+        })
+    }
+
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter.get_info()
+    }
+
+    pub fn set_camera(&mut self, camera: &CameraConfig) {
+        self.bindings.set_uniform(CAMERA, camera.to_shader(), &self.queue);
+        self.post.reset_accumulation();
+    }
+
+    // Renders and waits for one frame
+    pub fn render_frame(&mut self) {
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        crate::render_frame(
+            &mut encoder, &self.device, &self.queue, &self.scene,
+            &mut self.bindings, &mut self.post, &self.sampling,
+            self.stats.as_mut(), self.profiler.as_mut(), &self.view);
+        self.queue.submit([encoder.finish()]);
+        if let Some(stats) = &mut self.stats
+            && let Some(counts) = stats.read(&self.device, true)
+        {
+            log::info!("march stats: {counts}");
+        }
+        if let Some(profiler) = &mut self.profiler
+            && let Some(timing) = profiler.finish_frame(&self.device, true)
+        {
+            log::info!("frame timing: {timing}");
+        }
+    }
+
+    // Counts from the last frame [stats] sampled
+    pub fn march_stats(&self) -> Option<MarchStats> {
+        self.stats.as_ref().and_then(|s| s.last())
+    }
+
+    // Of the last frame, None when [profile] is off
+    pub fn last_frame_timing(&self) -> Option<&FrameTiming> {
+        self.profiler.as_ref().and_then(|p| p.latest())
+    }
+
+    // Row by row from the top left
+    pub fn read_pixels(&self) -> Vec<[u8; 3]> {
+        let size = self.texture.size();
+        let (width, height) = (size.width, size.height);
+        // Rows of a texture to buffer copy have to be 256 byte aligned
+        let row_bytes = width * 4;
+        let padded_row_bytes = row_bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("offscreen readback"),
+            size: (padded_row_bytes * height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &readback,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes),
+                    rows_per_image: Some(height),
+                },
+            },
+            size,
+        );
+        self.queue.submit([encoder.finish()]);
+
+        let slice = readback.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("can't map the offscreen readback buffer")
+        });
+        self.device.poll(wgpu::PollType::wait_indefinitely())
+            .expect("offscreen render didn't finish");
+
+        let data = slice.get_mapped_range();
+        data.chunks(padded_row_bytes as usize)
+            .flat_map(|row| row[..row_bytes as usize].chunks(4))
+            .map(|p| [p[0], p[1], p[2]])
+            .collect()
+    }
+}
@@ -0,0 +1,272 @@
+//  Text drawn over the window after the post chain: the frame rate, what
+//  is being shown and anything that went wrong. The text is laid out on
+//  the CPU into a mask texture a few times a second and drawn by its own
+//  small pipeline, so the raymarch shader knows nothing about it.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::error::Result;
+use crate::font;
+use crate::uniform::{GroupIndex, PipelineBindGroups, ShaderType};
+
+const OVERLAY: &str = "overlay";
+const TEXT: &str = "text";
+
+// Of the text texture, longer lines and extra lines are cut off
+const MAX_COLUMNS: usize = 64;
+const MAX_ROWS: usize = 12;
+// A glyph and the space after it, in font pixels
+const CELL_WIDTH: usize = font::WIDTH + 1;
+const CELL_HEIGHT: usize = font::HEIGHT + 2;
+// Around the text, in font pixels
+const MARGIN: usize = 3;
+const TEXT_WIDTH: usize = MAX_COLUMNS * CELL_WIDTH + 2 * MARGIN;
+const TEXT_HEIGHT: usize = MAX_ROWS * CELL_HEIGHT + 2 * MARGIN;
+
+// Between updates of the frame rate and the text
+const UPDATE: Duration = Duration::from_millis(500);
+
+//  [overlay]
+//  visible = true
+//  scale = 3
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OverlayConfig {
+    // At startup, O turns it on and off
+    pub visible: bool,
+    // Screen pixels per font pixel
+    pub scale: u32,
+}
+
+impl Default for OverlayConfig {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            scale: 2,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct OverlayParams {
+    // Top left corner and size of the box, in screen pixels
+    pub origin: [f32; 2],
+    pub size: [f32; 2],
+    pub scale: f32,
+    pub _pad: f32,
+}
+
+impl ShaderType for OverlayParams {
+    const WGSL_TYPE: &'static str = "OverlayParams";
+    const WGSL_STRUCT: &'static str = "
+struct OverlayParams {
+    origin: vec2f,
+    size: vec2f,
+    scale: f32,
+    _pad0: f32,
+}
+";
+}
+
+pub struct Overlay {
+    visible: bool,
+    scale: u32,
+    bindings: PipelineBindGroups,
+    pipeline: wgpu::RenderPipeline,
+    texture: wgpu::Texture,
+    // Of the text last laid out, in font pixels
+    size: (usize, usize),
+    // Frames since the rate was last worked out
+    frames: u32,
+    since: Instant,
+    frame_time: Duration,
+    // Set when the text should change before the next update
+    stale: bool,
+    error: Option<String>,
+}
+
+impl Overlay {
+    // format is the format of the view render will draw to
+    pub fn new(
+        config: &OverlayConfig,
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(TEXT),
+            size: wgpu::Extent3d {
+                width: TEXT_WIDTH as u32,
+                height: TEXT_HEIGHT as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let scale = config.scale.max(1);
+        let mut bindings = PipelineBindGroups::new(OVERLAY);
+        bindings.new_uniform(
+            OVERLAY, GroupIndex::Scalars,
+            Self::params(scale, (0, 0)), device);
+        bindings.new_unfiltered_texture(
+            TEXT, GroupIndex::Textures,
+            texture.create_view(&Default::default()));
+        let source = bindings.make_wgsl() + include_str!("overlay.wgsl");
+        let pipeline = crate::fullscreen_pipeline(
+            device, OVERLAY, source, format, &mut bindings)?;
+        Ok(Self {
+            visible: config.visible,
+            scale,
+            bindings,
+            pipeline,
+            texture,
+            size: (0, 0),
+            frames: 0,
+            since: Instant::now(),
+            frame_time: Duration::ZERO,
+            stale: true,
+            error: None,
+        })
+    }
+
+    fn params(scale: u32, size: (usize, usize)) -> OverlayParams {
+        let scale = scale as f32;
+        OverlayParams {
+            origin: [8.0, 8.0],
+            size: [size.0 as f32 * scale, size.1 as f32 * scale],
+            scale,
+            _pad: 0.0,
+        }
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+        self.stale = true;
+    }
+
+    // Shown under the rest of the text until it is cleared with None
+    pub fn set_error(&mut self, error: Option<String>) {
+        self.error = error;
+        self.stale = true;
+    }
+
+    // Lays the text out again on the next tick, for when what it shows
+    // changed
+    pub fn refresh(&mut self) {
+        self.stale = true;
+    }
+
+    // Call once a frame. Returns whether it is time to update the text.
+    pub fn tick(&mut self) -> bool {
+        self.frames += 1;
+        let elapsed = self.since.elapsed();
+        if elapsed < UPDATE && !self.stale { return false; }
+        self.frame_time = elapsed / self.frames;
+        self.frames = 0;
+        self.since = Instant::now();
+        self.stale = false;
+        self.visible
+    }
+
+    // Averaged since the last update
+    pub fn frame_time(&self) -> Duration {
+        self.frame_time
+    }
+
+    // Lays out lines, then the error if there is one, wrapping the error
+    // to the width of the box
+    pub fn set_text(&mut self, lines: &[String], queue: &wgpu::Queue) {
+        let error_lines = self.error.iter()
+            .flat_map(|e| e.lines())
+            .flat_map(|line| {
+                let chars: Vec<char> = line.chars().collect();
+                chars.chunks(MAX_COLUMNS)
+                    .map(|c| c.iter().collect::<String>())
+                    .collect::<Vec<_>>()
+            });
+        let lines: Vec<String> = lines.iter().cloned()
+            .chain(error_lines)
+            .take(MAX_ROWS)
+            .collect();
+
+        let columns = lines.iter()
+            .map(|l| l.chars().count().min(MAX_COLUMNS))
+            .max()
+            .unwrap_or(0);
+        let mut mask = vec![0u8; TEXT_WIDTH * TEXT_HEIGHT];
+        for (row, line) in lines.iter().enumerate() {
+            for (column, c) in line.chars().take(MAX_COLUMNS).enumerate() {
+                let x0 = MARGIN + column * CELL_WIDTH;
+                let y0 = MARGIN + row * CELL_HEIGHT;
+                for (y, bits) in font::glyph(c).iter().enumerate() {
+                    for x in 0..font::WIDTH {
+                        if bits & (1 << (font::WIDTH - 1 - x)) != 0 {
+                            mask[(y0 + y) * TEXT_WIDTH + x0 + x] = 255;
+                        }
+                    }
+                }
+            }
+        }
+        // Without the space after the last glyph and the gap under the
+        // last line
+        self.size = (
+            (columns * CELL_WIDTH).saturating_sub(1) + 2 * MARGIN,
+            (lines.len() * CELL_HEIGHT).saturating_sub(2) + 2 * MARGIN,
+        );
+        if lines.is_empty() {
+            self.size = (0, 0);
+        }
+
+        queue.write_texture(
+            self.texture.as_image_copy(),
+            &mask,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(TEXT_WIDTH as u32),
+                rows_per_image: Some(TEXT_HEIGHT as u32),
+            },
+            self.texture.size(),
+        );
+        self.bindings.set_uniform(
+            OVERLAY, Self::params(self.scale, self.size), queue);
+    }
+
+    // Draws the box over what is already in view
+    pub fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        device: &wgpu::Device,
+    ) {
+        if !self.visible || self.size == (0, 0) { return; }
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(OVERLAY),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        self.bindings.set_render_pass(device, &mut pass);
+        pass.draw(0..6, 0..1);
+    }
+}
@@ -0,0 +1,43 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Overlay - a box of text in the top left corner, drawn over the output
+//  after the post chain. The text is laid out by overlay.rs as a mask,
+//  one texel per font pixel.
+//
+//////////////////////////////////////////////////////////////////////////
+
+const TEXT_COLOR = vec3f(1.0, 1.0, 0.85);
+const BOX_COLOR = vec3f(0.02, 0.02, 0.03);
+
+struct VertexOutput {
+    @builtin(position) position: vec4f,
+};
+
+// The same two triangles as the other passes, the box is cut out of
+// them by fs_main
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var pos = array<vec2f, 6>(
+        vec2f(-1.0, -1.0),
+        vec2f( 1.0, -1.0),
+        vec2f(-1.0,  1.0),
+
+        vec2f(-1.0,  1.0),
+        vec2f( 1.0, -1.0),
+        vec2f( 1.0,  1.0),
+    );
+    var out: VertexOutput;
+    out.position = vec4f(pos[index], 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    // In screen pixels from the box's corner
+    let pixel = in.position.xy - overlay.origin;
+    if any(pixel < vec2f(0.0)) || any(pixel >= overlay.size) {
+        discard;
+    }
+    let mask = textureLoad(text, vec2i(pixel / overlay.scale), 0).r;
+    return vec4f(mix(BOX_COLOR, TEXT_COLOR, mask), 1.0);
+}
@@ -0,0 +1,62 @@
+use serde::Deserialize;
+
+use crate::uniform::ShaderType;
+
+// Monte Carlo path tracing in place of the direct lit render, for ground
+// truth. Each frame follows one random path per ray, so it is always
+// averaged over frames.
+//
+//  [path_trace]
+//  enabled = true
+//  max_bounces = 8
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PathTraceConfig {
+    pub enabled: bool,
+    // Surfaces a path can bounce off after the first hit
+    pub max_bounces: u32,
+    // Bounces before Russian roulette may end a dim path early
+    pub roulette_start: u32,
+}
+
+impl Default for PathTraceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_bounces: 8,
+            roulette_start: 3,
+        }
+    }
+}
+
+impl PathTraceConfig {
+    pub fn to_shader(&self) -> PathTrace {
+        PathTrace {
+            enabled: self.enabled as u32,
+            max_bounces: self.max_bounces,
+            roulette_start: self.roulette_start,
+            _pad: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PathTrace {
+    pub enabled: u32,
+    pub max_bounces: u32,
+    pub roulette_start: u32,
+    pub _pad: u32,
+}
+
+impl ShaderType for PathTrace {
+    const WGSL_TYPE: &'static str = "PathTrace";
+    const WGSL_STRUCT: &'static str = "
+struct PathTrace {
+    enabled: u32,
+    max_bounces: u32,
+    roulette_start: u32,
+    _pad: u32,
+}
+";
+}
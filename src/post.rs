@@ -0,0 +1,855 @@
+//  Post processing - the raymarch pass renders scene linear color into an
+//  HDR texture and an ordered list of passes takes it from there to the
+//  screen. Each pass is one or more full screen stages, each stage its own
+//  pipeline and bindings. The denoiser, when on, runs as compute stages
+//  ahead of the passes.
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::color::OutputConfig;
+use crate::config::SceneConfig;
+use crate::denoise::DenoiseConfig;
+use crate::error::{self, Error, Result};
+use crate::lut::Lut;
+use crate::uniform::{GroupIndex, PipelineBindGroups, ShaderType};
+use crate::view::ViewMode;
+
+// What the raymarch pass writes and the passes before the last hand on
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+// The running average of a progressive render, kept at full precision
+// where it can be drawn to
+const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+
+fn accum_format(adapter: &wgpu::Adapter) -> wgpu::TextureFormat {
+    let features = adapter.get_texture_format_features(ACCUM_FORMAT);
+    if features.allowed_usages.contains(wgpu::TextureUsages::RENDER_ATTACHMENT) {
+        ACCUM_FORMAT
+    } else {
+        log::warn!("can't draw to {ACCUM_FORMAT:?}, long progressive renders will stop improving");
+        HDR_FORMAT
+    }
+}
+
+const COMMON: &str = include_str!("post/common.wgsl");
+
+// Binding names shared by every stage
+const SOURCE: &str = "source";
+const SAMPLER: &str = "linear_sampler";
+const ENCODE: &str = "encode";
+const FRAME: &str = "frame";
+const HISTORY: &str = "history";
+const ACCUMULATED: &str = "accumulated";
+const NORMAL_DEPTH: &str = "normal_depth";
+const ALBEDO: &str = "albedo";
+const FILTERED: &str = "filtered";
+
+//  [[post]]
+//  pass = "bloom"
+//  threshold = 2.0
+//
+//  [[post]]
+//  pass = "tonemap"
+//
+//  [[post]]
+//  pass = "lut"
+//  path = "warm.cube"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "pass", rename_all = "lowercase")]
+pub enum PostPass {
+    // Scene linear to display, set up by [output]. Added at the end if
+    // the list doesn't have one.
+    Tonemap,
+    Bloom(BloomConfig),
+    Vignette(VignetteConfig),
+    Chromatic(ChromaticConfig),
+    Grain(GrainConfig),
+    Dither,
+    Lut(LutConfig),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BloomConfig {
+    // Luminance where the glow starts
+    pub threshold: f32,
+    pub intensity: f32,
+    // Blur size in half resolution pixels
+    pub radius: f32,
+}
+
+impl Default for BloomConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            intensity: 0.3,
+            radius: 4.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VignetteConfig {
+    // How dark the corners get, 0 to 1
+    pub strength: f32,
+    // Where the darkening starts and how long it takes, as fractions of
+    // the distance from the center to a corner
+    pub radius: f32,
+    pub softness: f32,
+}
+
+impl Default for VignetteConfig {
+    fn default() -> Self {
+        Self {
+            strength: 0.5,
+            radius: 0.5,
+            softness: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChromaticConfig {
+    // Red and blue offset at the edges, as a fraction of the image
+    pub strength: f32,
+}
+
+impl Default for ChromaticConfig {
+    fn default() -> Self {
+        Self { strength: 0.01 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GrainConfig {
+    pub strength: f32,
+}
+
+impl Default for GrainConfig {
+    fn default() -> Self {
+        Self { strength: 0.1 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LutConfig {
+    // .cube file, relative to the scene file
+    pub path: Option<PathBuf>,
+    // Mix between the original and graded color
+    pub strength: f32,
+}
+
+impl Default for LutConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            strength: 1.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct Bloom {
+    threshold: f32,
+    intensity: f32,
+    _pad: [f32; 2],
+}
+
+impl ShaderType for Bloom {
+    const WGSL_TYPE: &'static str = "Bloom";
+    const WGSL_STRUCT: &'static str = "
+struct Bloom {
+    threshold: f32,
+    intensity: f32,
+    _pad0: f32,
+    _pad1: f32,
+}
+";
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct Blur {
+    // One texel across or down
+    direction: [f32; 2],
+    sigma: f32,
+    _pad: f32,
+}
+
+impl ShaderType for Blur {
+    const WGSL_TYPE: &'static str = "Blur";
+    const WGSL_STRUCT: &'static str = "
+struct Blur {
+    direction: vec2f,
+    sigma: f32,
+    _pad: f32,
+}
+";
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct Vignette {
+    strength: f32,
+    radius: f32,
+    softness: f32,
+    _pad: f32,
+}
+
+impl ShaderType for Vignette {
+    const WGSL_TYPE: &'static str = "Vignette";
+    const WGSL_STRUCT: &'static str = "
+struct Vignette {
+    strength: f32,
+    radius: f32,
+    softness: f32,
+    _pad: f32,
+}
+";
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct LutParams {
+    domain_min: [f32; 3],
+    size: f32,
+    domain_max: [f32; 3],
+    strength: f32,
+}
+
+impl ShaderType for LutParams {
+    const WGSL_TYPE: &'static str = "Lut";
+    const WGSL_STRUCT: &'static str = "
+struct Lut {
+    domain_min: vec3f,
+    size: f32,
+    domain_max: vec3f,
+    strength: f32,
+}
+";
+}
+
+// Where a stage reads from or draws to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    // What the raymarch pass drew
+    Hdr,
+    // Full size, passes alternate between the two
+    Ping,
+    Pong,
+    // Half size, for bloom
+    HalfA,
+    HalfB,
+    // The average so far and the next one of a progressive render, they
+    // swap every frame
+    Accum(usize),
+    // What the raymarch pass finds under each pixel center, for the
+    // denoiser
+    NormalDepth,
+    Albedo,
+    // Denoiser iterations alternate between the two
+    Denoised(usize),
+    // The surface or offscreen texture handed to render
+    Output,
+}
+
+struct Targets {
+    hdr: wgpu::TextureView,
+    ping: wgpu::TextureView,
+    pong: wgpu::TextureView,
+    half_a: wgpu::TextureView,
+    half_b: wgpu::TextureView,
+    // Only made for progressive renders
+    accum: Vec<wgpu::TextureView>,
+    // Only made when denoising: normal_depth, albedo then the two
+    // denoised
+    denoise: Vec<wgpu::TextureView>,
+    sampler: wgpu::Sampler,
+    // Of the full size targets
+    size: (u32, u32),
+}
+
+impl Targets {
+    // accum is the format of the accumulation textures, if there are any
+    fn new(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        accum: Option<wgpu::TextureFormat>,
+        denoise: bool,
+    ) -> Self {
+        let texture = |label, width: u32, height: u32, format, usage| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width: width.max(1),
+                    height: height.max(1),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | usage,
+                view_formats: &[],
+            }).create_view(&Default::default())
+        };
+        let view = |label, width, height, format| texture(
+            label, width, height, format,
+            wgpu::TextureUsages::RENDER_ATTACHMENT);
+        let storage = |label| texture(
+            label, width, height, HDR_FORMAT,
+            wgpu::TextureUsages::STORAGE_BINDING);
+        Self {
+            hdr: view("hdr", width, height, HDR_FORMAT),
+            ping: view("ping", width, height, HDR_FORMAT),
+            pong: view("pong", width, height, HDR_FORMAT),
+            half_a: view("half a", width / 2, height / 2, HDR_FORMAT),
+            half_b: view("half b", width / 2, height / 2, HDR_FORMAT),
+            accum: match accum {
+                Some(format) => ["accum a", "accum b"].map(
+                    |label| view(label, width, height, format)).into(),
+                None => Vec::new(),
+            },
+            size: (width.max(1), height.max(1)),
+            denoise: if denoise {
+                vec![
+                    view("normal depth", width, height, HDR_FORMAT),
+                    view("albedo", width, height, HDR_FORMAT),
+                    storage("denoised a"),
+                    storage("denoised b"),
+                ]
+            } else {
+                Vec::new()
+            },
+            sampler: device.create_sampler(&wgpu::SamplerDescriptor {
+                label: Some("post"),
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn view(&self, target: Target) -> &wgpu::TextureView {
+        match target {
+            Target::Hdr => &self.hdr,
+            Target::Ping => &self.ping,
+            Target::Pong => &self.pong,
+            Target::HalfA => &self.half_a,
+            Target::HalfB => &self.half_b,
+            Target::Accum(i) => &self.accum[i],
+            Target::NormalDepth => &self.denoise[0],
+            Target::Albedo => &self.denoise[1],
+            Target::Denoised(i) => &self.denoise[2 + i],
+            Target::Output => panic!("the output view is passed to render"),
+        }
+    }
+}
+
+// One full screen draw
+struct Stage {
+    name: &'static str,
+    bindings: PipelineBindGroups,
+    pipeline: wgpu::RenderPipeline,
+    // Texture bindings and what's bound to them, redone on resize
+    inputs: Vec<(&'static str, Target)>,
+    output: Target,
+}
+
+impl Stage {
+    // Points the texture binding name at target
+    fn set_input(&mut self, name: &str, target: Target, targets: &Targets) {
+        for input in &mut self.inputs {
+            if input.0 == name {
+                input.1 = target;
+            }
+        }
+        self.bindings.set_texture(name, targets.view(target).clone());
+    }
+}
+
+// One compute dispatch over every pixel, writing output as a storage
+// texture
+struct ComputeStage {
+    name: &'static str,
+    bindings: PipelineBindGroups,
+    pipeline: wgpu::ComputePipeline,
+    inputs: Vec<(&'static str, Target)>,
+    output: Target,
+}
+
+// Averages frames while nothing changes, set up by [sampling]
+struct Accumulation {
+    // Frames in the average so far
+    frames: u32,
+    max_frames: u32,
+    format: wgpu::TextureFormat,
+    // Adds the new frame to the average, then copies the average to a
+    // texture the passes can filter. Made by build.
+    stages: Vec<Stage>,
+}
+
+pub struct PostChain {
+    passes: Vec<PostPass>,
+    output: OutputConfig,
+    view: ViewMode,
+    // Of the output view
+    format: wgpu::TextureFormat,
+    targets: Targets,
+    stages: Vec<Stage>,
+    accumulation: Option<Accumulation>,
+    // Set up by [denoise], its stages run before the passes
+    denoise: Option<DenoiseConfig>,
+    denoise_stages: Vec<ComputeStage>,
+    frame: u32,
+}
+
+impl PostChain {
+    // format is the format of the view render will draw to
+    pub fn new(
+        adapter: &wgpu::Adapter,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        config: &SceneConfig,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        // Without a tonemap nothing brings the HDR image into range
+        let mut passes = config.post.clone();
+        if !passes.iter().any(|p| matches!(p, PostPass::Tonemap)) {
+            log::warn!("no tonemap post pass, adding one at the end");
+            passes.push(PostPass::Tonemap);
+        }
+        let sampling = config.effective_sampling();
+        let accumulation = sampling.progressive.then(|| Accumulation {
+            frames: 0,
+            max_frames: sampling.frames(),
+            format: accum_format(adapter),
+            stages: Vec::new(),
+        });
+        let denoise = config.denoise.enabled.then(|| config.denoise.clone());
+        let mut chain = Self {
+            passes,
+            output: config.output.clone(),
+            view: config.view,
+            format,
+            targets: Targets::new(
+                device, width, height, accumulation.as_ref().map(|a| a.format),
+                denoise.is_some()),
+            stages: Vec::new(),
+            accumulation,
+            denoise,
+            denoise_stages: Vec::new(),
+            frame: 0,
+        };
+        chain.build(device, queue)?;
+        Ok(chain)
+    }
+
+    // What the raymarch pass draws to
+    pub fn hdr_view(&self) -> &wgpu::TextureView {
+        &self.targets.hdr
+    }
+
+    // Where the raymarch pass puts its normal and depth, and albedo, when
+    // the denoiser needs them
+    pub fn aov_views(&self) -> Option<[&wgpu::TextureView; 2]> {
+        self.denoise.as_ref().map(|_| [
+            self.targets.view(Target::NormalDepth),
+            self.targets.view(Target::Albedo),
+        ])
+    }
+
+    // Whether render wants a new frame from the raymarch pass, false once
+    // a progressive render has all its frames
+    pub fn needs_frame(&self) -> bool {
+        self.accumulation.as_ref().is_none_or(|a| a.frames < a.max_frames)
+    }
+
+    // Frames averaged since the last change, which the raymarch pass
+    // jitters by
+    pub fn accumulated(&self) -> u32 {
+        self.accumulation.as_ref().map_or(0, |a| a.frames)
+    }
+
+    // Starts the average over, for when anything in the scene changes
+    pub fn reset_accumulation(&mut self) {
+        if let Some(accumulation) = &mut self.accumulation {
+            accumulation.frames = 0;
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        let accum = self.accumulation.as_ref().map(|a| a.format);
+        self.targets = Targets::new(
+            device, width, height, accum, self.denoise.is_some());
+        self.reset_accumulation();
+        let accumulating = self.accumulation.iter_mut()
+            .flat_map(|a| &mut a.stages);
+        for stage in self.stages.iter_mut().chain(accumulating) {
+            for &(name, target) in &stage.inputs {
+                stage.bindings.set_texture(
+                    name, self.targets.view(target).clone());
+            }
+        }
+        for stage in &mut self.denoise_stages {
+            for &(name, target) in &stage.inputs {
+                stage.bindings.set_texture(
+                    name, self.targets.view(target).clone());
+            }
+            stage.bindings.set_texture(
+                FILTERED, self.targets.view(stage.output).clone());
+        }
+    }
+
+    // Debug views skip everything but a pass through tonemap
+    pub fn set_view_mode(
+        &mut self,
+        view: ViewMode,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<()> {
+        self.view = view;
+        self.reset_accumulation();
+        self.build(device, queue)
+    }
+
+    pub fn set_output(
+        &mut self,
+        output: &OutputConfig,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<()> {
+        self.output = output.clone();
+        self.build(device, queue)
+    }
+
+    fn build(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<()> {
+        let passes = if self.view == ViewMode::Shaded {
+            self.passes.clone()
+        } else {
+            vec![PostPass::Tonemap]
+        };
+        let mut stages = Vec::new();
+        let mut source = Target::Hdr;
+        if self.accumulation.is_some() {
+            // Which of the two is which gets set every frame by render
+            let accumulate = self.new_stage(
+                device, "accumulate", include_str!("post/accumulate.wgsl"),
+                vec![(SOURCE, Target::Hdr), (HISTORY, Target::Accum(0))],
+                Target::Accum(1), |b| b.new_uniform(
+                    ACCUMULATED, GroupIndex::Scalars, 0u32, device),
+            )?;
+            let resolve = self.new_stage(
+                device, "resolve", include_str!("post/resolve.wgsl"),
+                vec![(SOURCE, Target::Accum(1))], Target::Ping, |_| {},
+            )?;
+            if let Some(accumulation) = &mut self.accumulation {
+                accumulation.stages = vec![accumulate, resolve];
+            }
+            source = Target::Ping;
+        }
+        self.denoise_stages.clear();
+        if let Some(config) = self.denoise.clone()
+            && self.view == ViewMode::Shaded
+        {
+            for iteration in 0..config.iteration_count() {
+                let output = Target::Denoised((iteration % 2) as usize);
+                let stage = self.new_denoise_stage(
+                    device, source, output, &config, iteration)?;
+                self.denoise_stages.push(stage);
+                source = output;
+            }
+        }
+        for (i, pass) in passes.iter().enumerate() {
+            let last = i + 1 == passes.len();
+            let output = match (last, source) {
+                (true, _) => Target::Output,
+                (false, Target::Ping) => Target::Pong,
+                (false, _) => Target::Ping,
+            };
+            self.build_pass(pass, source, output, &mut stages, device, queue)?;
+            source = output;
+        }
+        self.stages = stages;
+        Ok(())
+    }
+
+    // Adds the stages of pass, reading source and drawing to output
+    fn build_pass(
+        &self,
+        pass: &PostPass,
+        source: Target,
+        output: Target,
+        stages: &mut Vec<Stage>,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<()> {
+        let inputs = vec![(SOURCE, source)];
+        match pass {
+            PostPass::Tonemap => {
+                let config = if self.view == ViewMode::Shaded {
+                    self.output.clone()
+                } else {
+                    OutputConfig::raw()
+                };
+                stages.push(self.new_stage(
+                    device, "tonemap", include_str!("post/tonemap.wgsl"),
+                    inputs, output, |b| b.new_uniform(
+                        "output", GroupIndex::Scalars, config.to_shader(), device),
+                )?);
+            }
+            PostPass::Bloom(config) => {
+                let bloom = Bloom {
+                    threshold: config.threshold,
+                    intensity: config.intensity,
+                    _pad: [0.0; 2],
+                };
+                stages.push(self.new_stage(
+                    device, "bloom bright", include_str!("post/bloom_bright.wgsl"),
+                    inputs.clone(), Target::HalfA, |b| b.new_uniform(
+                        "bloom", GroupIndex::Scalars, bloom, device),
+                )?);
+                for (direction, from, to) in [
+                    ([1.0, 0.0], Target::HalfA, Target::HalfB),
+                    ([0.0, 1.0], Target::HalfB, Target::HalfA),
+                ] {
+                    let blur = Blur { direction, sigma: config.radius.max(0.1), _pad: 0.0 };
+                    stages.push(self.new_stage(
+                        device, "bloom blur", include_str!("post/bloom_blur.wgsl"),
+                        vec![(SOURCE, from)], to, |b| b.new_uniform(
+                            "blur", GroupIndex::Scalars, blur, device),
+                    )?);
+                }
+                let mut inputs = inputs;
+                inputs.push(("bloom_map", Target::HalfA));
+                stages.push(self.new_stage(
+                    device, "bloom composite", include_str!("post/bloom_composite.wgsl"),
+                    inputs, output, |b| b.new_uniform(
+                        "bloom", GroupIndex::Scalars, bloom, device),
+                )?);
+            }
+            PostPass::Vignette(config) => {
+                let vignette = Vignette {
+                    strength: config.strength,
+                    radius: config.radius,
+                    softness: config.softness.max(1e-3),
+                    _pad: 0.0,
+                };
+                stages.push(self.new_stage(
+                    device, "vignette", include_str!("post/vignette.wgsl"),
+                    inputs, output, |b| b.new_uniform(
+                        "vignette", GroupIndex::Scalars, vignette, device),
+                )?);
+            }
+            PostPass::Chromatic(config) => {
+                stages.push(self.new_stage(
+                    device, "chromatic", include_str!("post/chromatic.wgsl"),
+                    inputs, output, |b| b.new_uniform(
+                        "chromatic_strength", GroupIndex::Scalars, config.strength, device),
+                )?);
+            }
+            PostPass::Grain(config) => {
+                stages.push(self.new_stage(
+                    device, "grain", include_str!("post/grain.wgsl"),
+                    inputs, output, |b| b.new_uniform(
+                        "grain_strength", GroupIndex::Scalars, config.strength, device),
+                )?);
+            }
+            PostPass::Dither => {
+                stages.push(self.new_stage(
+                    device, "dither", include_str!("post/dither.wgsl"),
+                    inputs, output, |_| {},
+                )?);
+            }
+            PostPass::Lut(config) => {
+                let path = config.path.as_ref()
+                    .expect("lut paths are checked when the scene is loaded");
+                let lut = Lut::load(path)?;
+                let params = LutParams {
+                    domain_min: lut.domain_min,
+                    size: lut.size as f32,
+                    domain_max: lut.domain_max,
+                    strength: config.strength,
+                };
+                let view = lut.upload(device, queue)?
+                    .create_view(&Default::default());
+                stages.push(self.new_stage(
+                    device, "lut", include_str!("post/lut.wgsl"),
+                    inputs, output, |b| {
+                        b.new_uniform("lut", GroupIndex::Scalars, params, device);
+                        b.new_texture(
+                            "lut_map", GroupIndex::Textures, view,
+                            wgpu::TextureViewDimension::D3);
+                    },
+                )?);
+            }
+        }
+        Ok(())
+    }
+
+    // params adds the stage's own bindings to the shared ones
+    fn new_stage(
+        &self,
+        device: &wgpu::Device,
+        name: &'static str,
+        shader: &str,
+        inputs: Vec<(&'static str, Target)>,
+        output: Target,
+        params: impl FnOnce(&mut PipelineBindGroups),
+    ) -> Result<Stage> {
+        let (format, encode) = match output {
+            Target::Output => (self.format, !self.format.is_srgb()),
+            Target::Accum(_) => (
+                self.accumulation.as_ref().expect("only progressive renders accumulate").format,
+                false,
+            ),
+            _ => (HDR_FORMAT, false),
+        };
+        let mut bindings = PipelineBindGroups::new(name);
+        bindings.new_uniform(ENCODE, GroupIndex::Scalars, encode as u32, device);
+        bindings.new_uniform(FRAME, GroupIndex::Scalars, self.frame, device);
+        params(&mut bindings);
+        for &(binding, target) in &inputs {
+            let view = self.targets.view(target).clone();
+            match target {
+                Target::Accum(_) => bindings.new_unfiltered_texture(
+                    binding, GroupIndex::Textures, view),
+                _ => bindings.new_texture(
+                    binding, GroupIndex::Textures, view,
+                    wgpu::TextureViewDimension::D2),
+            }
+        }
+        bindings.new_sampler(
+            SAMPLER, GroupIndex::Textures, self.targets.sampler.clone());
+
+        let source = bindings.make_wgsl() + COMMON + shader;
+        let pipeline = crate::fullscreen_pipeline(
+            device, name, source, format, &mut bindings)?;
+        Ok(Stage { name, bindings, pipeline, inputs, output })
+    }
+
+    fn new_denoise_stage(
+        &self,
+        device: &wgpu::Device,
+        source: Target,
+        output: Target,
+        config: &DenoiseConfig,
+        iteration: u32,
+    ) -> Result<ComputeStage> {
+        let name = "denoise";
+        let inputs = vec![
+            (SOURCE, source),
+            (NORMAL_DEPTH, Target::NormalDepth),
+            (ALBEDO, Target::Albedo),
+        ];
+        let mut bindings = PipelineBindGroups::new(name);
+        bindings.new_uniform(
+            "denoise", GroupIndex::Scalars, config.to_shader(iteration), device);
+        for &(binding, target) in &inputs {
+            bindings.new_texture(
+                binding, GroupIndex::Textures, self.targets.view(target).clone(),
+                wgpu::TextureViewDimension::D2);
+        }
+        bindings.new_storage_texture(
+            FILTERED, GroupIndex::Textures, self.targets.view(output).clone(),
+            HDR_FORMAT);
+
+        let source = bindings.make_wgsl() + include_str!("post/denoise.wgsl");
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(name),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        error::pop_scope(device, |message| Error::Shader {
+            label: name.to_string(),
+            message,
+        })?;
+        let layout = bindings.pipeline_layout(device);
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let pipeline = device.create_compute_pipeline(
+            &wgpu::ComputePipelineDescriptor {
+                label: Some(name),
+                layout: Some(&layout),
+                module: &module,
+                entry_point: Some("cs_main"),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+        error::pop_scope(device, |message| Error::Binding {
+            label: name.to_string(),
+            message,
+        })?;
+        Ok(ComputeStage { name, bindings, pipeline, inputs, output })
+    }
+
+    // Runs every stage, the last one drawing to output
+    pub fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        output: &wgpu::TextureView,
+        mut profiler: Option<&mut crate::profile::Profiler>,
+    ) {
+        self.frame = self.frame.wrapping_add(1);
+        if let Some(accumulation) = &mut self.accumulation {
+            let [accumulate, resolve] = &mut accumulation.stages[..] else {
+                panic!("accumulation stages are made by build");
+            };
+            // The average so far is in one, the new average goes in the
+            // other
+            let frames = accumulation.frames;
+            let current = Target::Accum((frames % 2) as usize);
+            if frames < accumulation.max_frames {
+                let next = Target::Accum(((frames + 1) % 2) as usize);
+                accumulate.set_input(HISTORY, current, &self.targets);
+                accumulate.output = next;
+                accumulate.bindings.set_uniform(ACCUMULATED, frames, queue);
+                crate::fullscreen_pass(
+                    encoder, self.targets.view(next), accumulate.name,
+                    &accumulate.pipeline, &mut accumulate.bindings, device,
+                    profiler.as_deref_mut()
+                        .and_then(|p| p.render_pass(accumulate.name)));
+                accumulation.frames += 1;
+            }
+            let average = Target::Accum((accumulation.frames % 2) as usize);
+            resolve.set_input(SOURCE, average, &self.targets);
+            crate::fullscreen_pass(
+                encoder, self.targets.view(Target::Ping), resolve.name,
+                &resolve.pipeline, &mut resolve.bindings, device,
+                profiler.as_deref_mut().and_then(|p| p.render_pass(resolve.name)));
+        }
+        for stage in &mut self.denoise_stages {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(stage.name),
+                timestamp_writes: profiler.as_deref_mut()
+                    .and_then(|p| p.compute_pass(stage.name)),
+            });
+            pass.set_pipeline(&stage.pipeline);
+            stage.bindings.set_compute_pass(device, &mut pass);
+            let (width, height) = self.targets.size;
+            pass.dispatch_workgroups(width.div_ceil(8), height.div_ceil(8), 1);
+        }
+        for stage in &mut self.stages {
+            stage.bindings.set_uniform(FRAME, self.frame, queue);
+            let view = match stage.output {
+                Target::Output => output,
+                target => self.targets.view(target),
+            };
+            crate::fullscreen_pass(
+                encoder, view, stage.name, &stage.pipeline,
+                &mut stage.bindings, device,
+                profiler.as_deref_mut().and_then(|p| p.render_pass(stage.name)));
+        }
+    }
+}
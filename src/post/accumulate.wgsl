@@ -0,0 +1,14 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Accumulate - adds the frame just drawn to the running average of a
+//  progressive render
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let frame_color = sampleSource(in.uv);
+    let old = textureLoad(history, vec2i(in.position.xy), 0).rgb;
+    // The first frame replaces whatever was there
+    return finish(mix(old, frame_color, 1.0 / f32(accumulated + 1u)));
+}
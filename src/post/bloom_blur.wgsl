@@ -0,0 +1,21 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Bloom, blur stages - run once across and once down
+//
+//////////////////////////////////////////////////////////////////////////
+
+// Gaussian blur of source along blur.direction
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let texel = blur.direction / vec2f(textureDimensions(source));
+    let radius = min(i32(ceil(blur.sigma * 3.0)), 32);
+    var sum = sampleSource(in.uv);
+    var total = 1.0;
+    for (var i = 1; i <= radius; i++) {
+        let x = f32(i);
+        let w = exp(-x * x / (2.0 * blur.sigma * blur.sigma));
+        sum += w * (sampleSource(in.uv + texel * x) + sampleSource(in.uv - texel * x));
+        total += 2.0 * w;
+    }
+    return finish(sum / total);
+}
@@ -0,0 +1,19 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Bloom, first stage - the parts of source over the threshold at half
+//  size
+//
+//////////////////////////////////////////////////////////////////////////
+
+// The four bilinear taps cover a 4x4 block so small highlights don't
+// flicker
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let texel = 1.0 / vec2f(textureDimensions(source));
+    let c = (sampleSource(in.uv + texel * vec2f(-1.0, -1.0))
+        + sampleSource(in.uv + texel * vec2f(1.0, -1.0))
+        + sampleSource(in.uv + texel * vec2f(-1.0, 1.0))
+        + sampleSource(in.uv + texel * vec2f(1.0, 1.0))) * 0.25;
+    let l = luminance(c);
+    return finish(c * max(l - bloom.threshold, 0.0) / max(l, 1e-4));
+}
@@ -0,0 +1,11 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Bloom, last stage - the blurred highlights added back to source
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let glow = textureSampleLevel(bloom_map, linear_sampler, in.uv, 0.0).rgb;
+    return finish(sampleSource(in.uv) + bloom.intensity * glow);
+}
@@ -0,0 +1,14 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Chromatic aberration - red and blue pulled apart towards the edges
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let offset = (in.uv - 0.5) * chromatic_strength;
+    let r = sampleSource(in.uv + offset).r;
+    let g = sampleSource(in.uv).g;
+    let b = sampleSource(in.uv - offset).b;
+    return finish(vec3f(r, g, b));
+}
@@ -0,0 +1,72 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Shared by every post pass, goes after the bindings from post.rs and
+//  before the pass itself
+//
+//////////////////////////////////////////////////////////////////////////
+
+struct VertexOutput {
+    @builtin(position) position: vec4f,
+    // Texture coordinates, y down
+    @location(0) uv: vec2f,
+};
+
+// The same two triangles as the raymarch pass
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var pos = array<vec2f, 6>(
+        vec2f(-1.0, -1.0),
+        vec2f( 1.0, -1.0),
+        vec2f(-1.0,  1.0),
+
+        vec2f(-1.0,  1.0),
+        vec2f( 1.0, -1.0),
+        vec2f( 1.0,  1.0),
+    );
+    let p = pos[index];
+    var out: VertexOutput;
+    out.position = vec4f(p, 0.0, 1.0);
+    out.uv = vec2f(p.x * 0.5 + 0.5, 0.5 - p.y * 0.5);
+    return out;
+}
+
+fn sampleSource(uv: vec2f) -> vec3f {
+    return textureSampleLevel(source, linear_sampler, uv, 0.0).rgb;
+}
+
+fn luminance(c: vec3f) -> f32 {
+    return dot(c, vec3f(0.2126, 0.7152, 0.0722));
+}
+
+fn linearToSrgb(c: vec3f) -> vec3f {
+    return select(
+        1.055 * pow(c, vec3f(1.0 / 2.4)) - 0.055,
+        c * 12.92,
+        c <= vec3f(0.0031308));
+}
+
+fn srgbToLinear(c: vec3f) -> vec3f {
+    return select(
+        pow((c + 0.055) / 1.055, vec3f(2.4)),
+        c / 12.92,
+        c <= vec3f(0.04045));
+}
+
+// 0..1 noise that changes every pixel and every frame
+fn hash(pixel: vec2f, seed: u32) -> f32 {
+    let p = vec2u(pixel);
+    var h = p.x * 1973u + p.y * 9277u + seed * 26699u;
+    h = (h ^ (h >> 16u)) * 0x7feb352du;
+    h = (h ^ (h >> 15u)) * 0x846ca68bu;
+    h = h ^ (h >> 16u);
+    return f32(h) / 4294967295.0;
+}
+
+// Every pass returns through here. Only the last pass can have encode
+// set, when its target doesn't sRGB encode in hardware.
+fn finish(c: vec3f) -> vec4f {
+    if encode != 0u {
+        return vec4f(linearToSrgb(saturate(c)), 1.0);
+    }
+    return vec4f(c, 1.0);
+}
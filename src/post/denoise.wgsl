@@ -0,0 +1,45 @@
+// One à-trous iteration, source filtered into filtered. Must do the same
+// math as filter in denoise.rs.
+
+const KERNEL = array<f32, 5>(1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
+
+@compute @workgroup_size(8, 8)
+fn cs_main(@builtin(global_invocation_id) id: vec3u) {
+    let size = vec2i(textureDimensions(source));
+    let pixel = vec2i(id.xy);
+    if any(pixel >= size) { return; }
+
+    let c = textureLoad(source, pixel, 0).rgb;
+    let p = textureLoad(normal_depth, pixel, 0);
+    // Misses show the background, which has no noise to remove
+    if p.w == 0.0 {
+        textureStore(filtered, pixel, vec4f(c, 1.0));
+        return;
+    }
+    let pAlbedo = textureLoad(albedo, pixel, 0).rgb;
+
+    var sum = vec3f(0.0);
+    var weights = 0.0;
+    for (var j = 0; j < 5; j++) {
+        for (var i = 0; i < 5; i++) {
+            let q = pixel + vec2i(i - 2, j - 2) * denoise.step;
+            if any(q < vec2i(0)) || any(q >= size) { continue; }
+
+            let cq = textureLoad(source, q, 0).rgb;
+            let nd = textureLoad(normal_depth, q, 0);
+            if nd.w == 0.0 { continue; }
+            let dc = c - cq;
+            let wc = exp(-dot(dc, dc) / (denoise.color_sigma * denoise.color_sigma));
+            let wn = pow(max(dot(p.xyz, nd.xyz), 0.0), denoise.normal_power);
+            let wd = exp(-abs(p.w - nd.w) / (denoise.depth_sigma * f32(denoise.step)));
+            let da = pAlbedo - textureLoad(albedo, q, 0).rgb;
+            let wa = exp(-dot(da, da) / (denoise.albedo_sigma * denoise.albedo_sigma));
+
+            let w = KERNEL[i] * KERNEL[j] * wc * wn * wd * wa;
+            sum += cq * w;
+            weights += w;
+        }
+    }
+    let color = select(c, sum / weights, weights > 0.0);
+    textureStore(filtered, pixel, vec4f(color, 1.0));
+}
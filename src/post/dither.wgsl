@@ -0,0 +1,13 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Dither - triangular noise of one 8 bit step in sRGB, hides banding
+//  in smooth gradients. Only makes sense as the last pass.
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let e = linearToSrgb(saturate(sampleSource(in.uv)));
+    let n = hash(in.position.xy, frame) + hash(in.position.xy, frame + 7919u) - 1.0;
+    return finish(srgbToLinear(saturate(e + n / 255.0)));
+}
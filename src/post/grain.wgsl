@@ -0,0 +1,15 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Film grain - noise that changes every frame, strongest in the mid
+//  tones
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let c = sampleSource(in.uv);
+    let n = hash(in.position.xy, frame) - 0.5;
+    let l = saturate(luminance(c));
+    let amount = grain_strength * 4.0 * l * (1.0 - l);
+    return finish(max(c * (1.0 + n * amount), vec3f(0.0)));
+}
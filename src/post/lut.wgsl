@@ -0,0 +1,17 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  LUT - color grading with a 3D lookup table from a .cube file. Grading
+//  LUTs expect sRGB encoded input and give sRGB encoded output.
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let c = sampleSource(in.uv);
+    let e = (linearToSrgb(saturate(c)) - lut.domain_min)
+        / (lut.domain_max - lut.domain_min);
+    // Texel centers, so the ends of the domain land on the ends of the table
+    let coord = (saturate(e) * (lut.size - 1.0) + 0.5) / lut.size;
+    let graded = textureSampleLevel(lut_map, linear_sampler, coord, 0.0).rgb;
+    return finish(mix(c, srgbToLinear(graded), lut.strength));
+}
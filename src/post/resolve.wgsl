@@ -0,0 +1,11 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Resolve - the average of a progressive render to a texture the other
+//  passes can filter
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    return finish(textureLoad(source, vec2i(in.position.xy), 0).rgb);
+}
@@ -0,0 +1,57 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Tonemap - scene linear to display linear, the same math as color.rs
+//  has for the CPU renderer
+//
+//////////////////////////////////////////////////////////////////////////
+
+// Must match OutputTransform in color.rs
+const OUTPUT_SRGB = 0u;
+const OUTPUT_LINEAR = 1u;
+const OUTPUT_REINHARD = 2u;
+const OUTPUT_ACES = 3u;
+const OUTPUT_AGX = 4u;
+
+// Narkowicz's fit of the ACES filmic curve
+fn aces(c: vec3f) -> vec3f {
+    let x = c * 0.6;
+    return saturate((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14));
+}
+
+// AgX as approximated by Benjamin Wrensch (shadertoy.com/view/cd3XWr)
+const agxInset = mat3x3f(
+    0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+    0.0784335999999992, 0.878468636469772, 0.0784336,
+    0.0792237451477643, 0.0791661274605434, 0.879142973793104);
+const agxOutset = mat3x3f(
+    1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+    -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+    -0.0990297440797205, -0.0989611768448433, 1.15107367264116);
+const agxMinEv = -12.47393;
+const agxMaxEv = 4.026069;
+
+fn agx(c: vec3f) -> vec3f {
+    let ev = clamp(log2(max(agxInset * c, vec3f(1e-10))), vec3f(agxMinEv), vec3f(agxMaxEv));
+    let x = (ev - agxMinEv) / (agxMaxEv - agxMinEv);
+    // Polynomial fit of the AgX contrast curve
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4
+        - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+    // Out of AgX space and undo the 2.2 display encoding the curve assumes
+    return pow(max(agxOutset * curve, vec3f(0.0)), vec3f(2.2));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    var c = sampleSource(in.uv) * output.exposure;
+    switch output.transform {
+        case OUTPUT_REINHARD: { c = c / (1.0 + c); }
+        case OUTPUT_ACES: { c = aces(c); }
+        case OUTPUT_AGX: { c = agx(c); }
+        // Stored as is, so undo the sRGB encode that comes later
+        case OUTPUT_LINEAR: { return finish(srgbToLinear(max(c, vec3f(0.0)))); }
+        default: {}
+    }
+    return finish(saturate(c));
+}
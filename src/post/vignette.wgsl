@@ -0,0 +1,16 @@
+//////////////////////////////////////////////////////////////////////////
+//
+//  Vignette - darkens towards the corners
+//
+//////////////////////////////////////////////////////////////////////////
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let size = vec2f(textureDimensions(source));
+    // 0 at the center to 1 in the corners, round whatever the aspect
+    let aspect = vec2f(size.x / size.y, 1.0);
+    let d = length((in.uv - 0.5) * aspect) / length(0.5 * aspect);
+    let v = 1.0 - vignette.strength
+        * smoothstep(vignette.radius, vignette.radius + vignette.softness, d);
+    return finish(sampleSource(in.uv) * v);
+}
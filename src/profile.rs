@@ -0,0 +1,317 @@
+//  Where frame time goes. With TIMESTAMP_QUERY every pass gets a pair of
+//  GPU timestamps, resolved and read back a few frames later; without it
+//  only the whole frame is timed, on the CPU. Either way the numbers are
+//  averaged over a window of frames.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::readback::ReadbackRing;
+
+// Two per pass, more than a full post chain needs
+const MAX_QUERIES: u32 = 64;
+// Frames whose timestamps can be waiting to be read at once
+const READBACKS: usize = 4;
+
+//  [profile]
+//  enabled = true
+//  window = 120
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    pub enabled: bool,
+    // Frames averaged for each FrameTiming
+    pub window: u32,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window: 60,
+        }
+    }
+}
+
+// Averages per frame over a window
+#[derive(Debug, Clone, Default)]
+pub struct FrameTiming {
+    pub frames: u32,
+    // From starting to record the frame to starting the next one, timed
+    // on the CPU, so waiting for the display counts. Frames rendered one
+    // at a time end when the GPU finishes them instead.
+    pub frame: Duration,
+    // GPU time of each pass in the order they first ran, empty without
+    // TIMESTAMP_QUERY. A pass that only runs on some frames, like the
+    // denoiser's guides, is averaged over all of them.
+    pub passes: Vec<(String, Duration)>,
+}
+
+impl FrameTiming {
+    // Of all the passes, None without TIMESTAMP_QUERY
+    pub fn gpu(&self) -> Option<Duration> {
+        (!self.passes.is_empty())
+            .then(|| self.passes.iter().map(|(_, d)| *d).sum())
+    }
+}
+
+impl fmt::Display for FrameTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame {:.2?}", self.frame)?;
+        if let Some(gpu) = self.gpu() {
+            write!(f, ", gpu {gpu:.2?}:")?;
+            for (label, time) in &self.passes {
+                write!(f, " {label} {time:.2?}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Queries {
+    set: wgpu::QuerySet,
+    resolve: wgpu::Buffer,
+    readbacks: ReadbackRing,
+    // Nanoseconds per tick
+    period: f64,
+}
+
+// Submitted and not yet counted
+struct Frame {
+    passes: Vec<String>,
+    // None until its timestamps are read back
+    times: Option<Vec<Duration>>,
+    // None until the next frame starts
+    frame: Option<Duration>,
+}
+
+pub struct Profiler {
+    queries: Option<Queries>,
+    window: u32,
+    // This frame's passes, in the order of their queries
+    passes: Vec<String>,
+    // Whether this frame's timestamps are copied to a readback buffer
+    copied: bool,
+    started: Option<Instant>,
+    // Oldest first
+    pending: VecDeque<Frame>,
+    // Totals over the window so far
+    frames: u32,
+    frame_total: Duration,
+    pass_totals: Vec<(String, Duration)>,
+    last: Option<FrameTiming>,
+    // The last frame on its own
+    latest: Option<FrameTiming>,
+}
+
+impl Profiler {
+    pub fn new(
+        config: &ProfileConfig,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Option<Self> {
+        if !config.enabled { return None; }
+        let queries = if device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            let size = MAX_QUERIES as u64 * wgpu::QUERY_SIZE as u64;
+            Some(Queries {
+                set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                    label: Some("pass timestamps"),
+                    ty: wgpu::QueryType::Timestamp,
+                    count: MAX_QUERIES,
+                }),
+                resolve: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("timestamp resolve"),
+                    size,
+                    usage: wgpu::BufferUsages::QUERY_RESOLVE
+                        | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+                readbacks: ReadbackRing::new(
+                    device, "timestamp readback", size, READBACKS),
+                period: queue.get_timestamp_period() as f64,
+            })
+        } else {
+            log::warn!("no TIMESTAMP_QUERY, timing whole frames on the CPU");
+            None
+        };
+        Some(Self {
+            queries,
+            window: config.window.max(1),
+            passes: Vec::new(),
+            copied: false,
+            started: None,
+            pending: VecDeque::new(),
+            frames: 0,
+            frame_total: Duration::ZERO,
+            pass_totals: Vec::new(),
+            last: None,
+            latest: None,
+        })
+    }
+
+    // Call before recording the frame. Ends the last one if it wasn't
+    // waited for.
+    pub fn begin_frame(&mut self) {
+        let now = Instant::now();
+        if let Some(started) = self.started.replace(now)
+            && let Some(last) = self.pending.back_mut()
+        {
+            last.frame.get_or_insert(now - started);
+        }
+        self.passes.clear();
+        self.copied = false;
+    }
+
+    // Timestamp writes for a render pass called label, None once the
+    // queries run out or without TIMESTAMP_QUERY
+    pub fn render_pass(&mut self, label: &str) -> Option<wgpu::RenderPassTimestampWrites<'_>> {
+        let (set, begin, end) = self.next_queries(label)?;
+        Some(wgpu::RenderPassTimestampWrites {
+            query_set: set,
+            beginning_of_pass_write_index: Some(begin),
+            end_of_pass_write_index: Some(end),
+        })
+    }
+
+    // render_pass for compute passes
+    pub fn compute_pass(&mut self, label: &str) -> Option<wgpu::ComputePassTimestampWrites<'_>> {
+        let (set, begin, end) = self.next_queries(label)?;
+        Some(wgpu::ComputePassTimestampWrites {
+            query_set: set,
+            beginning_of_pass_write_index: Some(begin),
+            end_of_pass_write_index: Some(end),
+        })
+    }
+
+    fn next_queries(&mut self, label: &str) -> Option<(&wgpu::QuerySet, u32, u32)> {
+        let queries = self.queries.as_ref()?;
+        let begin = self.passes.len() as u32 * 2;
+        if begin + 2 > MAX_QUERIES { return None; }
+        self.passes.push(label.to_string());
+        Some((&queries.set, begin, begin + 1))
+    }
+
+    // Call after the last pass of the frame is recorded
+    pub fn end_frame(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let Some(queries) = &mut self.queries else { return; };
+        let count = self.passes.len() as u32 * 2;
+        if count == 0 { return; }
+        let Some(readback) = queries.readbacks.record() else {
+            log::debug!("timestamp readbacks all in use, not timing this frame");
+            return;
+        };
+        encoder.resolve_query_set(&queries.set, 0..count, &queries.resolve, 0);
+        encoder.copy_buffer_to_buffer(
+            &queries.resolve, 0, readback, 0,
+            count as u64 * wgpu::QUERY_SIZE as u64);
+        self.copied = true;
+    }
+
+    // Call once the frame is submitted. Returns the averages when a
+    // window of frames has been counted. Without wait, the frame's times
+    // are read a few frames later and it ends when the next one starts.
+    // With wait, the GPU is waited for and the frame ends when it is
+    // finished, for rendering frames one at a time like a bench.
+    pub fn finish_frame(&mut self, device: &wgpu::Device, wait: bool) -> Option<FrameTiming> {
+        let started = self.started?;
+        let passes = std::mem::take(&mut self.passes);
+        if self.copied && let Some(queries) = &mut self.queries {
+            queries.readbacks.submitted();
+        }
+        self.pending.push_back(Frame {
+            // Without timestamps there is nothing to wait for
+            times: (!self.copied).then(Vec::new),
+            passes,
+            frame: None,
+        });
+        self.copied = false;
+
+        if wait {
+            device.poll(wgpu::PollType::wait_indefinitely())
+                .expect("frame didn't finish");
+            self.started = None;
+            if let Some(last) = self.pending.back_mut() {
+                last.frame = Some(started.elapsed());
+            }
+        }
+        if let Some(queries) = &mut self.queries {
+            let period = queries.period;
+            let pending = &mut self.pending;
+            queries.readbacks.read(device, wait, |bytes| {
+                let ticks: &[u64] = bytemuck::cast_slice(bytes);
+                let frame = pending.iter_mut()
+                    .find(|f| f.times.is_none())
+                    .expect("a frame for each timestamp readback");
+                frame.times = Some(ticks.chunks(2)
+                    .take(frame.passes.len())
+                    .map(|t| Duration::from_nanos(
+                        (t[1].wrapping_sub(t[0]) as f64 * period) as u64))
+                    .collect());
+            });
+        }
+
+        let mut timing = None;
+        while let Some(next) = self.pending.front()
+            && next.times.is_some()
+            && next.frame.is_some()
+            && let Some(frame) = self.pending.pop_front()
+        {
+            timing = self.count(frame).or(timing);
+        }
+        timing
+    }
+
+    // Adds a frame to the window, returning the averages when that fills
+    // it
+    fn count(&mut self, frame: Frame) -> Option<FrameTiming> {
+        let latest = FrameTiming {
+            frames: 1,
+            frame: frame.frame.unwrap_or_default(),
+            passes: frame.passes.into_iter()
+                .zip(frame.times.unwrap_or_default())
+                .collect(),
+        };
+        self.frame_total += latest.frame;
+        for (label, time) in &latest.passes {
+            match self.pass_totals.iter_mut().find(|(l, _)| l == label) {
+                Some((_, total)) => *total += *time,
+                None => self.pass_totals.push((label.clone(), *time)),
+            }
+        }
+        self.latest = Some(latest);
+        self.frames += 1;
+        if self.frames < self.window { return None; }
+        self.flush()
+    }
+
+    // Averages the frames so far, even if the window isn't full
+    pub fn flush(&mut self) -> Option<FrameTiming> {
+        if self.frames == 0 { return None; }
+        let frames = self.frames;
+        let timing = FrameTiming {
+            frames,
+            frame: self.frame_total / frames,
+            passes: self.pass_totals.drain(..)
+                .map(|(label, total)| (label, total / frames))
+                .collect(),
+        };
+        self.frames = 0;
+        self.frame_total = Duration::ZERO;
+        self.last = Some(timing.clone());
+        Some(timing)
+    }
+
+    // The last window's averages
+    pub fn last(&self) -> Option<&FrameTiming> {
+        self.last.as_ref()
+    }
+
+    // The last frame counted, which is the last one rendered after
+    // finish_frame waited
+    pub fn latest(&self) -> Option<&FrameTiming> {
+        self.latest.as_ref()
+    }
+}
@@ -0,0 +1,76 @@
+//  Buffers the GPU copies results into for the CPU to read, a few frames'
+//  worth, so reading one back doesn't have to wait for the frame that
+//  was just submitted. Results come back in order, a frame or two late.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub struct ReadbackRing {
+    free: Vec<wgpu::Buffer>,
+    // Copied into by the frame being recorded
+    recorded: Option<wgpu::Buffer>,
+    // Submitted, oldest first, each with a flag set once it is mapped
+    mapping: VecDeque<(wgpu::Buffer, Arc<AtomicBool>)>,
+}
+
+impl ReadbackRing {
+    pub fn new(device: &wgpu::Device, label: &str, size: u64, count: usize) -> Self {
+        let free = (0..count)
+            .map(|_| device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }))
+            .collect();
+        Self {
+            free,
+            recorded: None,
+            mapping: VecDeque::new(),
+        }
+    }
+
+    // The buffer for the frame being recorded to copy into, None when
+    // all of them are still waiting to be read
+    pub fn record(&mut self) -> Option<&wgpu::Buffer> {
+        if self.recorded.is_none() {
+            self.recorded = self.free.pop();
+        }
+        self.recorded.as_ref()
+    }
+
+    // Call once the frame that copied into the recorded buffer is
+    // submitted
+    pub fn submitted(&mut self) {
+        let Some(buffer) = self.recorded.take() else { return; };
+        let mapped = Arc::new(AtomicBool::new(false));
+        let flag = mapped.clone();
+        buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            result.expect("can't map a readback buffer");
+            flag.store(true, Ordering::Relaxed);
+        });
+        self.mapping.push_back((buffer, mapped));
+    }
+
+    // Passes each buffer that is ready to read to f, oldest first. With
+    // wait it waits for all of them.
+    pub fn read(&mut self, device: &wgpu::Device, wait: bool, mut f: impl FnMut(&[u8])) {
+        if self.mapping.is_empty() { return; }
+        let poll = if wait {
+            wgpu::PollType::wait_indefinitely()
+        } else {
+            wgpu::PollType::Poll
+        };
+        device.poll(poll).expect("readback didn't finish");
+        while let Some((buffer, mapped)) = self.mapping.front()
+            && mapped.load(Ordering::Relaxed)
+        {
+            f(&buffer.slice(..).get_mapped_range());
+            buffer.unmap();
+            if let Some((buffer, _)) = self.mapping.pop_front() {
+                self.free.push(buffer);
+            }
+        }
+    }
+}
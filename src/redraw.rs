@@ -0,0 +1,14 @@
+// When the window draws a new frame. Either way it keeps drawing while
+// the scene animates or a progressive render is still averaging frames.
+named_enum! {
+    #[derive(Default)]
+    pub enum Redraw("redraw mode") {
+        // Only after something changes, input, a resize or a new
+        // setting, so a still scene leaves the GPU idle
+        #[default]
+        OnDemand = "on_demand",
+        // Every frame as fast as presenting allows, for watching frame
+        // times
+        Continuous = "continuous",
+    }
+}
@@ -0,0 +1,103 @@
+use serde::Deserialize;
+
+use crate::uniform::ShaderType;
+
+//  [sampling]
+//  samples = 2
+//  progressive = true
+//  max_frames = 64
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    // Rays per pixel each frame, samples x samples on a grid
+    pub samples: u32,
+    // Jitters the grid every frame and averages frames while nothing
+    // changes
+    pub progressive: bool,
+    // Frames averaged before stopping. Offscreen and CPU renders use
+    // exactly this many.
+    pub max_frames: u32,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            samples: 1,
+            progressive: false,
+            max_frames: 256,
+        }
+    }
+}
+
+impl SamplingConfig {
+    // How many frames a still image takes
+    pub fn frames(&self) -> u32 {
+        if self.progressive { self.max_frames.max(1) } else { 1 }
+    }
+
+    // frame counts from 0 since the last change
+    pub fn to_shader(&self, frame: u32) -> Sampling {
+        Sampling {
+            samples: self.samples.max(1),
+            frame,
+            jitter: if self.progressive { jitter(frame) } else { [0.0; 2] },
+        }
+    }
+}
+
+// Offset of the sample grid in grid cells, -0.5..0.5. The R2 sequence
+// covers the cell evenly however many frames there are; frame 0 is the
+// cell center.
+pub fn jitter(frame: u32) -> [f32; 2] {
+    const A: [f64; 2] = [0.7548776662466927, 0.5698402909980532];
+    A.map(|a| ((0.5 + a * frame as f64).fract() - 0.5) as f32)
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Sampling {
+    pub samples: u32,
+    // Since the last change, seeds the path tracer's random numbers
+    pub frame: u32,
+    pub jitter: [f32; 2],
+}
+
+impl ShaderType for Sampling {
+    const WGSL_TYPE: &'static str = "Sampling";
+    const WGSL_STRUCT: &'static str = "
+struct Sampling {
+    samples: u32,
+    frame: u32,
+    jitter: vec2f,
+}
+";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_jitter_is_the_center() {
+        assert_eq!(jitter(0), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn jitter_stays_in_the_cell() {
+        for frame in 0..10_000 {
+            let [x, y] = jitter(frame);
+            assert!((-0.5..0.5).contains(&x) && (-0.5..0.5).contains(&y));
+        }
+    }
+
+    #[test]
+    fn jitter_covers_the_cell() {
+        // Every part of a 4x4 split of the cell within a few frames
+        let mut hit = [[false; 4]; 4];
+        for frame in 0..32 {
+            let [x, y] = jitter(frame).map(|v| ((v + 0.5) * 4.0) as usize);
+            hit[y][x] = true;
+        }
+        assert!(hit.iter().flatten().all(|&h| h), "{hit:?}");
+    }
+}
@@ -60,122 +60,832 @@ fn vs_main(
 //
 //////////////////////////////////////////////////////////////////////////
 
-// Material could be returned using function argument pointers if
-// performance is an issue.
-struct Material {
-    color: vec4f,
-}
-
+// Materials are ids into the materials table uploaded from material.rs,
+// which also defines the Material struct. The ids must stay in the order
+// of MATERIAL_NAMES there.
 struct Result {
     dist: f32,
-    aMaterial: Material,
+    aMaterial: u32,
 }
 
-const black = Material(vec4f(0.0, 0.0, 0.0, 1.0));      // black
-const red =   Material(vec4f(0.2, 0.0, 0.0, 1.0));      // red
-const green = Material(vec4f(0.0, 0.2, 0.0, 1.0));      // green
-const blue =  Material(vec4f(0.0, 0.0, 0.2, 1.0));      // blue
+const black = 0u;
+const red =   1u;
+const green = 2u;
+const blue =  3u;
+const mirror = 4u;
+const glass = 5u;
 
 const background = Result(-1.0, black);
 
-fn calcNormal(pos: vec3f) -> vec3f {
-	// Center sample
-    let c = theShape(pos).dist;
-	// Use offset samples to compute gradient / normal
-    var eps_zero: vec2f = vec2f(0.001, 0.0);
-    return normalize(vec3f(
-        theShape(pos + eps_zero.xyy).dist,
-        theShape(pos + eps_zero.yxy).dist,
-        theShape(pos + eps_zero.yyx).dist) - c);
-}
-
-// get gradient in the world
-// const grad_step = 0.02;
-// fn calcNormal(pos: vec3f) -> vec3f {
-// 	let dx = vec3f( grad_step, 0.0, 0.0 );
-// 	let dy = vec3f( 0.0, grad_step, 0.0 );
-// 	let dz = vec3f( 0.0, 0.0, grad_step );    
-// 	return normalize (
-// 		vec3f(
-// 			theShape( pos + dx ).dist - theShape( pos - dx ).dist,
-// 			theShape( pos + dy ).dist - theShape( pos - dy ).dist,
-// 			theShape( pos + dz ).dist - theShape( pos - dz ).dist			
-// 		)
-// 	);
-// }
+// Must match NormalMethod in march.rs
+const NORMAL_FORWARD = 0u;
+const NORMAL_CENTRAL = 1u;
+const NORMAL_TETRAHEDRAL = 2u;
+const NORMAL_ANALYTIC = 3u;
+
+// The surface normal at pos, t along the ray that got there
+fn calcNormal(pos: vec3f, t: f32) -> vec3f {
+    if normalMethod == NORMAL_ANALYTIC { return normalize(theGradient(pos)); }
+
+    let eps = max(normalEpsilon, normalScale * t);
+    switch normalMethod {
+        case NORMAL_CENTRAL: {
+            let e = vec2f(eps, 0.0);
+            return normalize(vec3f(
+                theShape(pos + e.xyy).dist - theShape(pos - e.xyy).dist,
+                theShape(pos + e.yxy).dist - theShape(pos - e.yxy).dist,
+                theShape(pos + e.yyx).dist - theShape(pos - e.yyx).dist));
+        }
+        case NORMAL_TETRAHEDRAL: {
+            let k = vec2f(1.0, -1.0);
+            return normalize(
+                k.xyy * theShape(pos + k.xyy * eps).dist +
+                k.yyx * theShape(pos + k.yyx * eps).dist +
+                k.yxy * theShape(pos + k.yxy * eps).dist +
+                k.xxx * theShape(pos + k.xxx * eps).dist);
+        }
+        default: {
+            // Center sample
+            let c = theShape(pos).dist;
+            let e = vec2f(eps, 0.0);
+            return normalize(vec3f(
+                theShape(pos + e.xyy).dist,
+                theShape(pos + e.yxy).dist,
+                theShape(pos + e.yyx).dist) - c);
+        }
+    }
+}
 
-const maxSteps = 128;
-const epsilon = 0.001;
+// Set for each pipeline from March in march.rs, the defaults are the
+// normal quality preset's
+override maxSteps: i32 = 128;
+override epsilon: f32 = 0.001;
+override startDistance: f32 = 1.0;
+override maxDistance: f32 = 100.0;
+override normalEpsilon: f32 = 0.001;
+override relaxation: f32 = 1.0;
+override cone: f32 = 0.0;
+override farPlane: f32 = 0.0;
+override normalMethod: u32 = NORMAL_FORWARD;
+override normalScale: f32 = 0.0;
+
+// Radius of a pixel one unit from the camera, set by fs_main for cone
+var<private> pixelAngle: f32;
+
+// Steps the last march took and the nearest it came to a surface, for
+// the debug views
+var<private> marchSteps: i32;
+var<private> marchClosest: f32;
+
+// This fragment's work so far, added to the stats buffer by flushStats
+// when there is one. flushStats comes from stats.rs.
+var<private> countRays: u32;
+var<private> countSteps: u32;
+var<private> countExhausted: u32;
+var<private> countMisses: u32;
+var<private> countShadowSteps: u32;
+
+// pixel is the size of a pixel in xy
+fn setPixelAngle(pixel: vec2f) {
+    // Stereo stretches one axis twice, take the wider
+    var size = max(pixel.x, pixel.y) * 0.5;
+    if camera.stereo != STEREO_NONE { size *= 2.0; }
+    switch camera.projection {
+        // Pixels are the same size at any distance
+        case PROJECTION_ORTHOGRAPHIC: { pixelAngle = 0.0; }
+        case PROJECTION_FISHEYE: { pixelAngle = size * camera.fov * 0.5; }
+        case PROJECTION_EQUIRECTANGULAR: { pixelAngle = size * pi * 0.5; }
+        default: { pixelAngle = size / camera.focal; }
+    }
+}
+
+// How far a camera ray going in rayDir is marched
+fn farDistance(rayDir: vec3f) -> f32 {
+    if farPlane <= 0.0 { return maxDistance; }
+    // Flat projections have a flat far plane, the wide ones a sphere
+    if camera.projection <= PROJECTION_ORTHOGRAPHIC {
+        return farPlane / max(dot(rayDir, cameraBasis().forward), 1e-4);
+    }
+    return farPlane;
+}
 
+// Rays go from rayOrigin along +rayDir. This used to march along
+// -rayDir with the camera's forward pointing from the target back to the
+// camera, which came to the same image; the two were flipped together
+// so shadow rays can march towards a light the same way.
 fn ray_march(
     rayOrigin: vec3f,     // camera location
     rayDir: vec3f,     // ray direction
 ) -> Result {
-    var t = 1.0;                // total depth
+    return march(rayOrigin, rayDir, startDistance, farDistance(rayDir), 1.0);
+}
 
-    for (var i = 0; i < maxSteps; i++) {
-        var res = theShape(rayOrigin - rayDir * t);
-        if res.dist < epsilon * t { return Result(t, res.aMaterial); }
-        t += res.dist;
+// side is 1.0 when marching outside of objects and -1.0 inside of one,
+// where the distance is negative and has to be flipped.
+//
+// With relaxation over 1 this is over-relaxed sphere tracing (Keinert et
+// al. 2015): steps go past the distance bound, and when the bound at the
+// new point shows the two spheres don't overlap the step is taken back
+// and marching carries on plainly.
+fn march(rayOrigin: vec3f, rayDir: vec3f, tStart: f32, tEnd: f32, side: f32) -> Result {
+    var t = tStart;                // total depth
+    var omega = relaxation;
+    var lastDist = 0.0;
+    var step = 0.0;
+    let threshold = max(epsilon, cone * pixelAngle);
+    marchSteps = 0;
+    marchClosest = 1e10;
+    countRays++;
+
+    for (var i = 0; i < maxSteps && t < tEnd; i++) {
+        let res = theShape(rayOrigin + rayDir * t);
+        let d = side * res.dist;
+        marchSteps++;
+        marchClosest = min(marchClosest, d);
+        countSteps++;
+        if omega > 1.0 && abs(d) + lastDist < step {
+            // Overshot, back to where a plain step would have gone
+            step -= omega * step;
+            omega = 1.0;
+        } else {
+            if d < threshold * t { return Result(t, res.aMaterial); }
+            step = d * omega;
+        }
+        lastDist = abs(d);
+        t += step;
     }
 
+    if t < tEnd { countExhausted++; } else { countMisses++; }
     return background;
 }
 
-const iTime = pi * 0.25;
+// Where a ray marched to t hit. A cone hit can be well short of the
+// surface, further than the offsets new rays start at, so it is moved
+// onto it.
+fn hitPoint(rayOrigin: vec3f, rayDir: vec3f, t: f32) -> vec3f {
+    let pos = rayOrigin + rayDir * t;
+    if cone <= 0.0 { return pos; }
+    return pos - calcNormal(pos, t) * theShape(pos).dist;
+}
 
-fn render(rayOrigin: vec3f, rayDir: vec3f) -> vec3f {
-    var color: vec3f;
-	var t = ray_march(rayOrigin, rayDir);
+//////////////////////////////////////////////////////////////////////////
+//
+//  Lights - the light list and ambient come from light.rs
+//
+//////////////////////////////////////////////////////////////////////////
+
+// Must match LightKind in light.rs
+const LIGHT_DIRECTIONAL = 0u;
+const LIGHT_POINT = 1u;
+const LIGHT_SPOT = 2u;
+
+// What a light delivers to one surface point
+struct LightSample {
+    dir: vec3f,         // from the surface towards the light
+    dist: f32,          // how far a shadow ray has to go
+    radiance: vec3f,    // color * intensity * falloff
+}
+
+fn sampleLight(light: Light, pos: vec3f) -> LightSample {
+    let radiance = light.color * light.intensity;
+    if light.kind == LIGHT_DIRECTIONAL {
+        return LightSample(-light.direction, 1e10, radiance);
+    }
+
+    let toLight = light.position - pos;
+    let dist = length(toLight);
+    let dir = toLight / dist;
+    // Inverse square that smoothly reaches zero at range
+    let window = saturate(1.0 - pow(dist / light.range, 4.0));
+    var falloff = window * window / (dist * dist + 1.0);
+    if light.kind == LIGHT_SPOT {
+        falloff *= smoothstep(
+            light.cos_outer, light.cos_inner, dot(-dir, light.direction));
+    }
+    return LightSample(dir, dist, radiance * falloff);
+}
+
+// 1.0 when nothing is between pos and the light, otherwise 0.0
+fn shadowRay(rayOrigin: vec3f, rayDir: vec3f, maxDist: f32) -> f32 {
+    var t = 0.0;
+    for (var i = 0; i < maxSteps && t < maxDist; i++) {
+        let d = theShape(rayOrigin + rayDir * t).dist;
+        countShadowSteps++;
+        if d < epsilon { return 0.0; }
+        t += d;
+    }
+    return 1.0;
+}
+
+// Penumbra estimate from how closely the shadow ray passes the scene.
+// Uses the closest approach between the last two samples rather than the
+// raw distance, which removes banding (iquilezles.org/articles/rmshadows).
+// softness is roughly the penumbra width per unit of distance.
+fn softShadow(
+    rayOrigin: vec3f, rayDir: vec3f, maxDist: f32, softness: f32
+) -> f32 {
+    var res = 1.0;
+    var t = 0.0;
+    var lastDist = 1e10;
+    for (var i = 0; i < maxSteps && t < maxDist; i++) {
+        let d = theShape(rayOrigin + rayDir * t).dist;
+        countShadowSteps++;
+        if d < epsilon { return 0.0; }
+        let y = d * d / (2.0 * lastDist);
+        let closest = sqrt(max(d * d - y * y, 0.0));
+        res = min(res, closest / (softness * max(t - y, 0.0001)));
+        lastDist = d;
+        t += d;
+    }
+    res = saturate(res);
+    return res * res * (3.0 - 2.0 * res);
+}
+
+fn shadow(light: Light, rayOrigin: vec3f, ls: LightSample) -> f32 {
+    if light.shadows == 0u { return 1.0; }
+    let maxDist = min(ls.dist, light.shadow_distance);
+    if light.softness <= 0.0 {
+        return shadowRay(rayOrigin, ls.dir, maxDist);
+    }
+    return softShadow(rayOrigin, ls.dir, maxDist, light.softness);
+}
+
+// Ambient blended between ground and sky by how much the normal faces up
+fn hemisphere(n: vec3f) -> vec3f {
+    let up = n.y * 0.5 + 0.5;
+    return mix(lighting.ground, lighting.sky, up) * lighting.intensity;
+}
+
+// Ambient occlusion from how much closer the scene is than expected at
+// a few points along the normal. 1.0 is fully open.
+fn calcAO(pos: vec3f, n: vec3f) -> f32 {
+    var occ = 0.0;
+    var weight = 1.0;
+    for (var i = 1u; i <= occlusion.samples; i++) {
+        let h = occlusion.step * f32(i);
+        let d = theShape(pos + n * h).dist;
+        occ += (h - d) * weight;
+        weight *= 0.5;
+    }
+    return saturate(1.0 - occlusion.strength * occ);
+}
 
+// Must match ViewMode in view.rs
+const VIEW_SHADED = 0u;
+const VIEW_AO = 1u;
+const VIEW_NORMALS = 2u;
+const VIEW_DEPTH = 3u;
+const VIEW_STEPS = 4u;
+const VIEW_MATERIAL = 5u;
+const VIEW_SHADOW = 6u;
+const VIEW_MISS = 7u;
+const VIEW_SLICE = 8u;
+
+// Distance changing faster than this along the slice, allowing for the
+// error of the differences, is flagged. Must match GRADIENT_LIMIT in
+// cpu.rs.
+const GRADIENT_LIMIT = 1.01;
+
+// The slice plane, colored by signed distance
+fn sliceView(rayOrigin: vec3f, rayDir: vec3f) -> vec3f {
+    let facing = dot(rayDir, slice.normal);
+    let t = (slice.offset - dot(rayOrigin, slice.normal)) / facing;
+    if abs(facing) < 1e-6 || t <= 0.0 { return vec3f(0.0); }
+
+    let p = rayOrigin + rayDir * t;
+    let d = theShape(p).dist;
+    var color = select(vec3f(0.4, 0.7, 1.0), vec3f(0.9, 0.6, 0.3), d > 0.0);
+    // Darker toward the surface
+    color *= 1.0 - 0.7 * exp(-4.0 * abs(d));
+
+    // About a pixel wide
+    let width = max(pixelAngle * t, 0.002) * 1.5;
+    let toLine = abs(fract(d / slice.spacing + 0.5) - 0.5) * slice.spacing;
+    color *= 1.0 - 0.6 * (1.0 - smoothstep(0.0, width, toLine));
+
+    let e = vec2f(normalEpsilon, 0.0);
+    let gradient = vec3f(
+        theShape(p + e.xyy).dist - theShape(p - e.xyy).dist,
+        theShape(p + e.yxy).dist - theShape(p - e.yxy).dist,
+        theShape(p + e.yyx).dist - theShape(p - e.yyx).dist) / (2.0 * normalEpsilon);
+    if length(gradient) > GRADIENT_LIMIT {
+        color = mix(color, vec3f(1.0, 0.0, 1.0), 0.75);
+    }
+
+    return mix(color, vec3f(1.0), 1.0 - smoothstep(0.0, width * 2.0, abs(d)));
+}
+
+//////////////////////////////////////////////////////////////////////////
+//
+//  Background - what rays that miss see. Set up by background.rs in
+//  the backdrop uniform, background itself is the missed Result above.
+//
+//////////////////////////////////////////////////////////////////////////
+
+// Must match BackgroundKind in background.rs
+const BACKGROUND_SOLID = 0u;
+const BACKGROUND_GRADIENT = 1u;
+const BACKGROUND_SKY = 2u;
+const BACKGROUND_ENVIRONMENT = 3u;
+
+// Equirectangular lookup, must match env_uv in environment.rs
+fn envUV(dir: vec3f) -> vec2f {
+    let u = atan2(dir.x, -dir.z) / (2.0 * pi) + 0.5
+        + backdrop.rotation / (2.0 * pi);
+    let v = acos(clamp(dir.y, -1.0, 1.0)) / pi;
+    return vec2f(u, v);
+}
+
+// Always an explicit level, derivatives aren't usable inside the
+// bounce loop.
+fn environment(dir: vec3f, level: f32) -> vec3f {
+    return textureSampleLevel(env_map, env_sampler, envUV(dir), level).rgb;
+}
+
+// A cheap daylight model: horizon to zenith falloff, a glow around the
+// sun and the sun disk itself.
+fn sky(dir: vec3f, withSun: bool) -> vec3f {
+    var color = mix(backdrop.color, backdrop.top, sqrt(max(dir.y, 0.0)));
+    if dir.y < 0.0 {
+        // Darker ground below the horizon
+        color *= mix(1.0, 0.3, saturate(-dir.y * 4.0));
+    }
+    let mu = max(dot(dir, backdrop.sun_direction), 0.0);
+    color += backdrop.sun_color * 0.002 * pow(mu, 32.0);
+    if withSun {
+        color += backdrop.sun_color
+            * smoothstep(backdrop.sun_cos - 0.00005, backdrop.sun_cos, mu);
+    }
+    return color;
+}
+
+fn backgroundColor(dir: vec3f) -> vec3f {
+    var color = backdrop.color;
+    switch backdrop.kind {
+        case BACKGROUND_GRADIENT: {
+            color = mix(backdrop.color, backdrop.top, dir.y * 0.5 + 0.5);
+        }
+        case BACKGROUND_SKY: { color = sky(dir, true); }
+        case BACKGROUND_ENVIRONMENT: { color = environment(dir, 0.0); }
+        default: {}
+    }
+    return color * backdrop.intensity;
+}
+
+// Light arriving from the background around a normal. The environment
+// uses a very blurry mip level as a stand in for irradiance.
+fn backgroundAmbient(n: vec3f) -> vec3f {
+    var color = backdrop.color;
+    switch backdrop.kind {
+        case BACKGROUND_GRADIENT: {
+            color = mix(backdrop.color, backdrop.top, n.y * 0.5 + 0.5);
+        }
+        case BACKGROUND_SKY: { color = sky(n, false); }
+        case BACKGROUND_ENVIRONMENT: {
+            color = environment(n, max(backdrop.env_levels - 4.0, 0.0));
+        }
+        default: {}
+    }
+    return color * backdrop.intensity * backdrop.ambient;
+}
+
+// Direct and ambient light reaching a surface point
+fn shade(pos: vec3f, n: vec3f, objectSurfaceColour: vec3f) -> vec3f {
+    let ambient = hemisphere(n) + backgroundAmbient(n);
+    // vec3 objectSurfaceColour = vec3(0.4, 0.8, 0.1);
+    var color = objectSurfaceColour * ambient * calcAO(pos, n);
+
+    // Offset along the normal so shadow rays don't hit their own surface
+    let shadowRayOrigin = pos + n * 0.01;
     // vec3 L = normalize(vec3(sin(iTime)*1.0, cos(iTime*0.5)+0.5, -0.5));
-    var L = normalize(vec3(sin(iTime)*1.0, cos(iTime*0.5)+0.5, -0.5));
+    for (var i = 0u; i < lighting.light_count; i++) {
+        let light = lights[i];
+        let ls = sampleLight(light, pos);
+        // L is vector from surface point to light, N is surface normal. N and L must be normalized!
+        // Here L is ls.dir and N is n.
+        let NoL = max(dot(n, ls.dir), 0.0);
+        if NoL <= 0.0 || all(ls.radiance == vec3f(0.0)) { continue; }
+
+        let visible = shadow(light, shadowRayOrigin, ls);
+        color += objectSurfaceColour * ls.radiance * NoL * visible;
+    }
+    return color;
+}
+
+// Schlick's approximation of the reflected fraction
+fn fresnel(cosTheta: f32, ior: f32) -> f32 {
+    let r0 = pow((1.0 - ior) / (1.0 + ior), 2.0);
+    return r0 + (1.0 - r0) * pow(1.0 - cosTheta, 5.0);
+}
+
+// A ray waiting to be marched, weight is how much it adds to the pixel
+struct TraceRay {
+    origin: vec3f,
+    dir: vec3f,
+    weight: vec3f,
+    depth: u32,
+    side: f32,
+    tStart: f32,
+    tEnd: f32,
+}
+
+// Every bounce leaves at most one ray waiting, so this covers
+// TraceConfig::MAX_BOUNCES in material.rs
+const MAX_RAYS = 8;
+
+fn render(rayOrigin: vec3f, rayDir: vec3f) -> vec3f {
+    var color = vec3f(0.0);
+    var stack: array<TraceRay, MAX_RAYS>;
+    stack[0] = TraceRay(
+        rayOrigin, rayDir, vec3f(1.0), 0u, 1.0, startDistance,
+        farDistance(rayDir));
+    var top = 1;
+
+    while top > 0 {
+        top--;
+        let ray = stack[top];
+        let t = march(ray.origin, ray.dir, ray.tStart, ray.tEnd, ray.side);
+        let m = materials[t.aMaterial];
+
+        if t.dist == -1.0 {
+            color += ray.weight * backgroundColor(ray.dir);
+            continue;
+        }
 
-	if t.dist == -1.0 {
-        // color = vec3(0.30, 0.36, 0.60) - rayDir.y * 0.4;
-        color = t.aMaterial.color.xyz;
-    } else {   
         // vec3 pos = rayOrigin + rayDir * t;
-        var pos = rayOrigin + rayDir * t.dist;
+        let pos = hitPoint(ray.origin, ray.dir, t.dist);
         // vec3 N = calcNormal(pos);
-        var n = calcNormal(pos);
+        let n = calcNormal(pos, t.dist);
+
+        // Split between the surface itself, the mirrored ray and the
+        // transmitted ray. Fresnel moves light from the surface and
+        // transmission into reflection at grazing angles.
+        let facing = n * ray.side;
+        var kr = 0.0;
+        var kt = 0.0;
+        if m.reflectivity > 0.0 || m.transparency > 0.0 {
+            let f = fresnel(saturate(-dot(ray.dir, facing)), m.ior);
+            kr = m.reflectivity + (1.0 - m.reflectivity) * f;
+            kt = m.transparency * (1.0 - kr);
+        }
+
+        // The inside of a transparent object isn't lit directly
+        let local = 1.0 - kr - kt;
+        if local > 0.0 && ray.side > 0.0 {
+            color += ray.weight * local * shade(pos, n, m.color.xyz);
+        }
+        if ray.side > 0.0 { color += ray.weight * m.emission; }
+
+        if ray.depth >= trace.max_bounces { continue; }
+
+        if kt > 0.0 {
+            let eta = select(1.0 / m.ior, m.ior, ray.side < 0.0);
+            let refracted = refract(ray.dir, facing, eta);
+            if all(refracted == vec3f(0.0)) {
+                // Total internal reflection
+                kr += kt;
+            } else if top < MAX_RAYS {
+                // Tinted by the material on the way in
+                let tint = select(vec3f(1.0), m.color.xyz, ray.side > 0.0);
+                stack[top] = TraceRay(
+                    pos - facing * 0.01, refracted, ray.weight * kt * tint,
+                    ray.depth + 1u, -ray.side, 0.0, maxDistance);
+                top++;
+            }
+        }
+        if kr > 0.0 && top < MAX_RAYS {
+            // Mirrors tint what they reflect, glass doesn't
+            let tint = select(vec3f(1.0), m.color.xyz, m.reflectivity > 0.0);
+            stack[top] = TraceRay(
+                pos + facing * 0.01, reflect(ray.dir, facing),
+                ray.weight * kr * tint, ray.depth + 1u, ray.side, 0.0,
+                maxDistance);
+            top++;
+        }
+    }
 
-        // vec3 objectSurfaceColour = vec3(0.4, 0.8, 0.1);
-        var objectSurfaceColour = t.aMaterial.color.xyz;
-        // L is vector from surface point to light, N is surface normal. N and L must be normalized!
-        var NoL = max(dot(n, L), 0.0);
-        var LDirectional = vec3f(1.80,1.27,0.99) * NoL;
-        var LAmbient = vec3f(0.03, 0.04, 0.1);
-        var diffuse = objectSurfaceColour * (LDirectional + LAmbient);
-		
-        color = diffuse;
-        
-        
-        var shadow = 0.0f;
-        var shadowRayOrigin = pos + n * 0.01;
-        var shadowRayDir = L;
-        t = ray_march(shadowRayOrigin, shadowRayDir);
-        if t.dist >= -1.0 { shadow = 1.0; }
-        color = mix(color, color*0.8, shadow);
-        
-        // Visualize normals:
-        // color = n * vec3(0.5) + vec3(0.5);
+    return color;
+}
+
+//////////////////////////////////////////////////////////////////////////
+//
+//  Random numbers - for lens, shutter and path sampling
+//
+//////////////////////////////////////////////////////////////////////////
+
+var<private> rngState: u32;
+
+// PCG hash (jcgt.org/published/0009/03/02)
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// A different sequence for every pixel and sample, must match Rng::new
+// in cpu.rs
+fn seedRandom(pixel: vec2u, sample: u32) {
+    rngState = pcg(pixel.x + pcg(pixel.y + pcg(sample)));
+}
+
+// 0..1, 24 bits so f32 holds it exactly
+fn random() -> f32 {
+    rngState = pcg(rngState);
+    return f32(rngState >> 8u) / 16777216.0;
+}
+
+//////////////////////////////////////////////////////////////////////////
+//
+//  Path tracer - Monte Carlo version of render set up by pathtrace.rs.
+//  Every hit follows one of render's three ways on, picked at random
+//  in proportion to their weights. Light comes from the lights, emissive
+//  materials and the background; the ambient terms are render's stand
+//  ins for what bouncing finds here, so they aren't used.
+//
+//////////////////////////////////////////////////////////////////////////
+
+// Two unit vectors at right angles to n and each other, without a
+// branch on n (jcgt.org/published/0006/01/01)
+fn perpendicular(n: vec3f) -> array<vec3f, 2> {
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    return array<vec3f, 2>(
+        vec3f(1.0 + s * n.x * n.x * a, s * b, -s * n.x),
+        vec3f(b, s + n.y * n.y * a, -n.y));
+}
+
+// Direction around n with probability proportional to the cosine, which
+// cancels the cosine in diffuse reflection
+fn cosineSample(n: vec3f) -> vec3f {
+    let phi = 2.0 * pi * random();
+    let r2 = random();
+    let r = sqrt(r2);
+    let frame = perpendicular(n);
+    return normalize(frame[0] * (r * cos(phi)) + frame[1] * (r * sin(phi))
+        + n * sqrt(1.0 - r2));
+}
+
+// The lights' part of shade, without the ambient
+fn directLight(pos: vec3f, n: vec3f, albedo: vec3f) -> vec3f {
+    var color = vec3f(0.0);
+    let shadowRayOrigin = pos + n * 0.01;
+    for (var i = 0u; i < lighting.light_count; i++) {
+        let light = lights[i];
+        let ls = sampleLight(light, pos);
+        let NoL = max(dot(n, ls.dir), 0.0);
+        if NoL <= 0.0 || all(ls.radiance == vec3f(0.0)) { continue; }
+
+        let visible = shadow(light, shadowRayOrigin, ls);
+        color += albedo * ls.radiance * NoL * visible;
+    }
+    return color;
+}
+
+fn pathTrace(rayOrigin: vec3f, rayDir: vec3f) -> vec3f {
+    var color = vec3f(0.0);
+    // What is left of the light after the surfaces so far
+    var throughput = vec3f(1.0);
+    var origin = rayOrigin;
+    var dir = rayDir;
+    var side = 1.0;
+    var tStart = startDistance;
+    var tEnd = farDistance(rayDir);
+
+    for (var bounce = 0u; bounce <= path_trace.max_bounces; bounce++) {
+        let t = march(origin, dir, tStart, tEnd, side);
+        if t.dist == -1.0 {
+            color += throughput * backgroundColor(dir);
+            break;
+        }
+
+        let m = materials[t.aMaterial];
+        let pos = hitPoint(origin, dir, t.dist);
+        let n = calcNormal(pos, t.dist);
+        let facing = n * side;
+        if side > 0.0 { color += throughput * m.emission; }
+
+        // Same split as render
+        var kr = 0.0;
+        var kt = 0.0;
+        if m.reflectivity > 0.0 || m.transparency > 0.0 {
+            let f = fresnel(saturate(-dot(dir, facing)), m.ior);
+            kr = m.reflectivity + (1.0 - m.reflectivity) * f;
+            kt = m.transparency * (1.0 - kr);
+        }
+
+        let choice = random();
+        if choice < kt {
+            let eta = select(1.0 / m.ior, m.ior, side < 0.0);
+            let refracted = refract(dir, facing, eta);
+            if all(refracted == vec3f(0.0)) {
+                // Total internal reflection, tinted like kr
+                throughput *= select(
+                    vec3f(1.0), m.color.xyz, m.reflectivity > 0.0);
+                dir = reflect(dir, facing);
+                origin = pos + facing * 0.01;
+            } else {
+                throughput *= select(vec3f(1.0), m.color.xyz, side > 0.0);
+                dir = refracted;
+                origin = pos - facing * 0.01;
+                side = -side;
+            }
+        } else if choice < kt + kr {
+            throughput *= select(vec3f(1.0), m.color.xyz, m.reflectivity > 0.0);
+            dir = reflect(dir, facing);
+            origin = pos + facing * 0.01;
+        } else {
+            // Diffuse. Like render the inside of an object isn't lit, so
+            // the path ends there.
+            if side < 0.0 { break; }
+            color += throughput * directLight(pos, n, m.color.xyz);
+            throughput *= m.color.xyz;
+            dir = cosineSample(n);
+            origin = pos + n * 0.01;
+        }
+        tStart = 0.0;
+        tEnd = maxDistance;
+
+        // Russian roulette, surviving paths are scaled up to make up for
+        // the ones ended
+        if bounce >= path_trace.roulette_start {
+            let p = clamp(
+                max(throughput.x, max(throughput.y, throughput.z)), 0.05, 1.0);
+            if random() >= p { break; }
+            throughput /= p;
+        }
     }
-    
     return color;
 }
 
+// Blue through green to red as x goes 0 to 1
+fn heat(x: f32) -> vec3f {
+    let x4 = saturate(x) * 4.0;
+    return saturate(vec3f(1.5) - abs(vec3f(x4) - vec3f(3.0, 2.0, 1.0)));
+}
+
+// Fraction of the lights that reach pos
+fn shadowMask(pos: vec3f, n: vec3f) -> f32 {
+    if lighting.light_count == 0u { return 1.0; }
+    let shadowRayOrigin = pos + n * 0.01;
+    var lit = 0.0;
+    for (var i = 0u; i < lighting.light_count; i++) {
+        let light = lights[i];
+        let ls = sampleLight(light, pos);
+        if dot(n, ls.dir) <= 0.0 { continue; }
+        lit += shadow(light, shadowRayOrigin, ls);
+    }
+    return lit / f32(lighting.light_count);
+}
+
+// The primary ray's first hit in one of the debug view modes
+fn debugView(rayOrigin: vec3f, rayDir: vec3f) -> vec3f {
+    if view_mode == VIEW_SLICE { return sliceView(rayOrigin, rayDir); }
+    let t = march(rayOrigin, rayDir, startDistance, farDistance(rayDir), 1.0);
+    if view_mode == VIEW_STEPS { return heat(f32(marchSteps) / f32(maxSteps)); }
+    if t.dist == -1.0 {
+        switch view_mode {
+            case VIEW_AO: { return backgroundColor(rayDir); }
+            case VIEW_MISS: {
+                // 1 and over is blue, 1e-6 red
+                return heat(-log2(max(marchClosest, 1e-6)) / log2(1e6));
+            }
+            default: { return vec3f(0.0); }
+        }
+    }
+
+    let pos = hitPoint(rayOrigin, rayDir, t.dist);
+    let n = calcNormal(pos, t.dist);
+    switch view_mode {
+        case VIEW_AO: { return vec3f(calcAO(pos, n)); }
+        case VIEW_NORMALS: { return n * 0.5 + 0.5; }
+        // Falls off with distance rather than reaching black at the far
+        // plane, which is usually well past the scene
+        case VIEW_DEPTH: { return vec3f(1.0 / (1.0 + 0.2 * t.dist)); }
+        case VIEW_MATERIAL: {
+            // Hues a golden ratio apart stay distinct for a lot of ids
+            let hue = fract(f32(t.aMaterial) * 0.618034);
+            return 0.5 + 0.5 * cos(2.0 * pi * (hue + vec3f(0.0, 1.0, 2.0) / 3.0));
+        }
+        case VIEW_SHADOW: { return vec3f(shadowMask(pos, n)); }
+        default: { return vec3f(0.2); }
+    }
+}
+
+struct Ray {
+    origin: vec3f,
+    dir: vec3f,
+}
+
+// Must match Projection and Stereo in camera.rs
+const PROJECTION_PERSPECTIVE = 0u;
+const PROJECTION_ORTHOGRAPHIC = 1u;
+const PROJECTION_FISHEYE = 2u;
+const PROJECTION_EQUIRECTANGULAR = 3u;
+const STEREO_NONE = 0u;
+const STEREO_SIDE_BY_SIDE = 1u;
+const STEREO_TOP_BOTTOM = 2u;
+
+struct CameraBasis {
+    forward: vec3f,
+    right: vec3f,
+    up: vec3f,
+}
+
+fn cameraBasis() -> CameraBasis {
+	let camForward: vec3f = normalize(camera.look_at - camera.position);
+	let camRight: vec3f = normalize(cross(camForward, vec3(0.0, 1.0, 0.0)));
+	let camUp: vec3f = normalize(cross(camRight, camForward));
+    return CameraBasis(camForward, camRight, camUp);
+}
+
+// The ray through screen position xy, -1..1 with y up, before the lens
+fn cameraRay(xy: vec2f) -> Ray {
+    let cam = cameraBasis();
+
+    // Stereo gives each eye half the screen, stretched back to -1..1
+    var uv = xy;
+    var eye = 0.0;
+    if camera.stereo == STEREO_SIDE_BY_SIDE {
+        eye = select(0.5, -0.5, xy.x < 0.0);
+        uv.x = xy.x * 2.0 + select(-1.0, 1.0, xy.x < 0.0);
+    } else if camera.stereo == STEREO_TOP_BOTTOM {
+        eye = select(0.5, -0.5, xy.y > 0.0);
+        uv.y = xy.y * 2.0 + select(1.0, -1.0, xy.y > 0.0);
+    }
+
+    var origin = camera.position;
+    var dir: vec3f;
+    // Which way the eyes are apart
+    var side = cam.right;
+    switch camera.projection {
+        case PROJECTION_ORTHOGRAPHIC: {
+            origin += (cam.right * uv.x + cam.up * uv.y) * (camera.view_height * 0.5);
+            dir = cam.forward;
+        }
+        case PROJECTION_FISHEYE: {
+            // The angle from the axis grows evenly with the radius
+            let r = max(length(uv), 1e-6);
+            let theta = r * camera.fov * 0.5;
+            dir = cam.forward * cos(theta)
+                + (cam.right * uv.x + cam.up * uv.y) * (sin(theta) / r);
+        }
+        case PROJECTION_EQUIRECTANGULAR: {
+            let longitude = uv.x * pi;
+            let latitude = uv.y * pi * 0.5;
+            let around = cam.forward * cos(longitude) + cam.right * sin(longitude);
+            dir = around * cos(latitude) + cam.up * sin(latitude);
+            // Eyes turn with the view so stereo works all the way round
+            side = cam.right * cos(longitude) - cam.forward * sin(longitude);
+        }
+        default: {
+            dir = normalize(uv.x * cam.right + uv.y * cam.up + cam.forward * camera.focal);
+        }
+    }
+    origin += side * (eye * camera.eye_separation);
+    return Ray(origin, dir);
+}
+
+// A ray through the thin lens, lens is a random point in the unit
+// square. Without an aperture every ray starts at the camera; otherwise
+// they start spread over the lens and meet again at the focus distance,
+// blurring whatever is nearer or further.
+fn lensRay(xy: vec2f, lens: vec2f) -> Ray {
+    let ray = cameraRay(xy);
+    if camera.aperture <= 0.0 { return ray; }
+
+    // Flat projections focus on a plane, the wide ones on a sphere
+    let cam = cameraBasis();
+    var dist = camera.focus_distance;
+    var lensU = cam.right;
+    var lensV = cam.up;
+    if camera.projection <= PROJECTION_ORTHOGRAPHIC {
+        dist /= dot(ray.dir, cam.forward);
+    } else {
+        let frame = perpendicular(ray.dir);
+        lensU = frame[0];
+        lensV = frame[1];
+    }
+    let focus = ray.origin + ray.dir * dist;
+    // Evenly over the disk
+    let r = camera.aperture * sqrt(lens.x);
+    let phi = 2.0 * pi * lens.y;
+    let origin = ray.origin + (lensU * cos(phi) + lensV * sin(phi)) * r;
+    return Ray(origin, normalize(focus - origin));
+}
+
 fn getCameraRayDir(uv: vec2f, camPos: vec3f, camTarget: vec3f) -> vec3f
 {
-	let camForward: vec3f = normalize(camPos - camTarget);
-	let camRight: vec3f = normalize(cross(vec3(0.0, 1.0, 0.0), camForward));
-	let camUp: vec3f = normalize(cross(camForward, camRight));
+	let camForward: vec3f = normalize(camTarget - camPos);
+	let camRight: vec3f = normalize(cross(camForward, vec3(0.0, 1.0, 0.0)));
+	let camUp: vec3f = normalize(cross(camRight, camForward));
 
     // fPersp controls the camera's field of view. Try changing it!
     let fPersp = 1.0f;
 	let vDir: vec3f =
         // normalize(uv.x * camRight + uv.y * camUp + camForward * fPersp);
-        normalize(-uv.x * camRight - uv.y * camUp + camForward * fPersp);
+        // normalize(-uv.x * camRight - uv.y * camUp + camForward * fPersp);
+        normalize(uv.x * camRight + uv.y * camUp + camForward * fPersp);
 
 	return vDir;
 }
@@ -190,19 +900,56 @@ fn camera_dir(uv: vec2f, camPos: vec3f, camTarget: vec3f) -> vec3f {
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4f {
-    var camPos = vec3f(0, 0, 2);
-    // var at = vec3f(0, 0, 1);
-    var at = vec3f(0, 0, 0);
-    
-    // vec2 uv = normalizeScreenCoords(fragCoord);
-    var rayDir = getCameraRayDir(in.xy, camPos, at);  
-    // var rayDir = camera_dir(in.xy, camPos, at);  
-    
-    var color = render(camPos, rayDir);
-    
-    color = pow(color, vec3f(0.4545)); // Gamma correction (1.0 / 2.2)
-    
-    return vec4f(color, 1.0); // Output to screen
+    // Size of a pixel in xy
+    let pixel = 2.0 / vec2f(f32(screen_x), f32(screen_y));
+    setPixelAngle(pixel);
+
+    // samples x samples rays spread evenly over the pixel. The grid is
+    // jittered each frame when frames are being averaged.
+    let n = sampling.samples;
+    var color = vec3f(0.0);
+    for (var j = 0u; j < n; j++) {
+        for (var i = 0u; i < n; i++) {
+            let offset = (vec2f(f32(i), f32(j)) + 0.5 + sampling.jitter) / f32(n) - 0.5;
+            // xy is up where pixels are down
+            let xy = in.xy + vec2f(offset.x, -offset.y) * pixel;
+            // A point on the lens and a moment while the shutter is open
+            seedRandom(vec2u(in.position.xy), (sampling.frame * n + j) * n + i);
+            let lens = vec2f(random(), random());
+            sceneTime = animation.time + random() * camera.shutter;
+            let ray = lensRay(xy, lens);
+            if view_mode != VIEW_SHADED {
+                color += debugView(ray.origin, ray.dir);
+            } else if path_trace.enabled != 0u {
+                color += pathTrace(ray.origin, ray.dir);
+            } else {
+                color += render(ray.origin, ray.dir);
+            }
+        }
+    }
+
+    flushStats();
+    // Scene linear, the post passes take it from here
+    return vec4f(color / f32(n * n), 1.0); // Output to the HDR target
+}
+
+// What the first hit under the pixel center is like, the guides for the
+// denoiser in post. Depth 0 is a miss.
+struct AovOutput {
+    @location(0) normal_depth: vec4f,
+    @location(1) albedo: vec4f,
+}
+
+@fragment
+fn fs_aov(in: VertexOutput) -> AovOutput {
+    sceneTime = animation.time;
+    setPixelAngle(2.0 / vec2f(f32(screen_x), f32(screen_y)));
+    let ray = cameraRay(in.xy);
+    let t = ray_march(ray.origin, ray.dir);
+    if t.dist == -1.0 { return AovOutput(vec4f(0.0), vec4f(0.0)); }
+
+    let n = calcNormal(ray.origin + ray.dir * t.dist, t.dist);
+    return AovOutput(vec4f(n, t.dist), materials[t.aMaterial].color);
 }
 
 
@@ -214,8 +961,50 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4f {
 
 const pi: f32 = 3.14159265359;
 
+// When the ray being marched sees the scene, set by fs_main
+var<private> sceneTime: f32;
+
+// The scene turned about the vertical axis by [animation], so p turns
+// the other way. Must match spin in cpu.rs.
+fn spin(p: vec3f) -> vec3f {
+    let a = -animation.spin * sceneTime;
+    return vec3f(cos(a) * p.x - sin(a) * p.z, p.y, sin(a) * p.x + cos(a) * p.z);
+}
+
+// Back from spin's frame to the world's
+fn unspin(v: vec3f) -> vec3f {
+    let a = -animation.spin * sceneTime;
+    return vec3f(cos(a) * v.x + sin(a) * v.z, v.y, -sin(a) * v.x + cos(a) * v.z);
+}
+
 // fn theShape(p: vec3f) -> Result { return Result(sphere(p, 1), blue); }
-fn theShape(p: vec3f) -> Result { return shape7(p); }
+fn theShape(p: vec3f) -> Result { return shape7(spin(p)); }
+
+// The gradient of theShape for the analytic normal. Change it along
+// with theShape; only the shapes it needs have Gradient versions.
+fn theGradient(p: vec3f) -> vec3f { return unspin(shape7Gradient(spin(p)).grad); }
+
+// A distance with its gradient
+struct Gradient {
+    dist: f32,
+    grad: vec3f,
+}
+
+fn shape7Gradient(p: vec3f) -> Gradient {
+    let p1 = (translate(-0.5, -0.5, -0.5) * vec4(p, 1.0)).xyz;
+    let p2 = (translate( 0.5,  0.5,  0.5) * vec4(p, 1.0)).xyz;
+    return unionsGradient(sphereGradient(p1, 0.5), sphereGradient(p2, 0.5));
+}
+
+fn sphereGradient(p: vec3f, radius: f32) -> Gradient {
+    return Gradient(sphere(p, radius), p / max(length(p), 1e-6));
+}
+
+// Like unions, the closer one's gradient
+fn unionsGradient(g1: Gradient, g2: Gradient) -> Gradient {
+    if g1.dist < g2.dist { return g1; }
+    return g2;
+}
 
 fn shape1(p: vec3f) -> Result {
     let p2 = (translate( 0.25,  0.0,  0.0) * vec4(p, 1.0)).xyz;
@@ -235,6 +1024,19 @@ fn shape7(p: vec3f) -> Result {
     );
 }
 
+// shape7 seen through a glass ball, with a mirror behind
+fn shape8(p: vec3f) -> Result {
+    let p1 = (translate(0.1, -0.1, 0.9) * vec4(p, 1.0)).xyz;
+    let p2 = (translate(0.0, 0.0, -2.0) * vec4(p, 1.0)).xyz;
+    return unions(
+        shape7(p),
+        unions(
+            Result(sphere(p1, 0.3), glass),
+            Result(box(p2, vec3(2.0, 2.0, 0.1)), mirror),
+        )
+    );
+}
+
 fn shape2(p: vec3f) -> Result {
     return intersect(
         Result(box(p, vec3(0.38)), red),
@@ -328,7 +1130,7 @@ fn trans(p: vec3f, m: mat4x4<f32>) -> vec3f {
 //     return Result(dist, material);
 // }
 
-fn recolor(in: Result, material: Material) -> Result {
+fn recolor(in: Result, material: u32) -> Result {
     return Result(in.dist, material);
 }
 
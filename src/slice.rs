@@ -0,0 +1,118 @@
+use serde::Deserialize;
+
+use crate::math::{vec3, Vec3};
+use crate::uniform::ShaderType;
+
+// The plane the slice view cuts through the distance field. It is
+// colored by signed distance, orange outside and blue inside, with an
+// isoline every spacing and the surface itself in white. Magenta marks
+// where the distance changes faster than 1 per unit, which breaks
+// sphere tracing.
+//
+//  view = "slice"
+//
+//  [slice]
+//  normal = [0.0, 1.0, 0.0]
+//  offset = 0.5
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SliceConfig {
+    // Needn't be unit length
+    pub normal: [f32; 3],
+    // Distance of the plane from the origin along normal
+    pub offset: f32,
+    // Distance between isolines
+    pub spacing: f32,
+}
+
+impl Default for SliceConfig {
+    fn default() -> Self {
+        Self {
+            normal: [0.0, 0.0, 1.0],
+            offset: 0.0,
+            spacing: 0.25,
+        }
+    }
+}
+
+impl SliceConfig {
+    pub fn to_shader(&self) -> Slice {
+        let normal = Vec3::from(self.normal);
+        let normal = if normal == Vec3::ZERO {
+            vec3(0.0, 0.0, 1.0)
+        } else {
+            normal.normalize()
+        };
+        Slice {
+            normal: normal.into(),
+            offset: self.offset,
+            spacing: self.spacing.max(1e-4),
+            _pad: [0.0; 3],
+        }
+    }
+
+    // Moves the plane along its normal
+    pub fn shift(&mut self, distance: f32) {
+        self.offset += distance;
+    }
+
+    // Turns the plane to face along x, y or z, 0 to 2
+    pub fn face_axis(&mut self, axis: usize) {
+        self.normal = [0.0; 3];
+        self.normal[axis] = 1.0;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Slice {
+    // Unit length
+    pub normal: [f32; 3],
+    pub offset: f32,
+    pub spacing: f32,
+    pub _pad: [f32; 3],
+}
+
+impl ShaderType for Slice {
+    const WGSL_TYPE: &'static str = "Slice";
+    const WGSL_STRUCT: &'static str = "
+struct Slice {
+    normal: vec3f,
+    offset: f32,
+    spacing: f32,
+    _pad0: f32,
+    _pad1: f32,
+    _pad2: f32,
+}
+";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_normal_faces_z() {
+        let slice = SliceConfig { normal: [0.0; 3], ..Default::default() };
+        assert_eq!(slice.to_shader().normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn normal_is_made_unit_length() {
+        let slice = SliceConfig {
+            normal: [3.0, 0.0, 4.0],
+            spacing: 0.0,
+            ..Default::default()
+        };
+        let shader = slice.to_shader();
+        assert_eq!(shader.normal, [0.6, 0.0, 0.8]);
+        assert!(shader.spacing > 0.0);
+    }
+
+    #[test]
+    fn face_axis_replaces_the_normal() {
+        let mut slice = SliceConfig { normal: [1.0, 2.0, 3.0], ..Default::default() };
+        slice.face_axis(1);
+        assert_eq!(slice.normal, [0.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,222 @@
+//  Counts of the marching work the GPU does, for telling whether a
+//  change to a scene made it cheaper or dearer. The raymarch pass tallies
+//  each fragment's work in private variables and flushStats adds them to
+//  a buffer of atomics, which is read back for one frame in every
+//  interval, a few frames later.
+
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::readback::ReadbackRing;
+use crate::uniform::{GroupIndex, PipelineBindGroups, ShaderType};
+
+const MARCH_STATS: &str = "march_stats";
+// Counted frames that can be waiting to be read at once
+const READBACKS: usize = 3;
+
+//  [stats]
+//  enabled = true
+//  interval = 30
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    pub enabled: bool,
+    // Frames between the ones counted
+    pub interval: u32,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 60,
+        }
+    }
+}
+
+// Totals over every pixel of one frame
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MarchStats {
+    // Calls to march, camera rays and bounces
+    pub rays: u64,
+    pub steps: u64,
+    // Rays that ran out of steps before hitting or getting to the far
+    // plane, each one a likely artifact
+    pub exhausted: u64,
+    pub misses: u64,
+    pub shadow_steps: u64,
+}
+
+// The counters as the GPU keeps them. WGSL has no 64 bit atomics, so
+// each is a low and a high word, with addCount carrying between them,
+// in the order of the MarchStats fields.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct Counters {
+    words: [u32; 10],
+}
+
+impl ShaderType for Counters {
+    const WGSL_TYPE: &'static str = "MarchCounters";
+    const WGSL_STRUCT: &'static str = "
+struct MarchCounters {
+    words: array<atomic<u32>, 10>,
+}
+";
+}
+
+impl From<Counters> for MarchStats {
+    fn from(counters: Counters) -> Self {
+        let count = |i: usize| {
+            counters.words[2 * i] as u64 | (counters.words[2 * i + 1] as u64) << 32
+        };
+        Self {
+            rays: count(0),
+            steps: count(1),
+            exhausted: count(2),
+            misses: count(3),
+            shadow_steps: count(4),
+        }
+    }
+}
+
+impl MarchStats {
+    pub fn steps_per_ray(&self) -> f32 {
+        self.steps as f32 / self.rays.max(1) as f32
+    }
+}
+
+impl fmt::Display for MarchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |n: u64| 100.0 * n as f32 / self.rays.max(1) as f32;
+        write!(
+            f, "{} rays, {:.1} steps per ray, {:.2}% out of steps, \
+                {:.1}% missed, {} shadow steps",
+            self.rays, self.steps_per_ray(), percent(self.exhausted),
+            percent(self.misses), self.shadow_steps)
+    }
+}
+
+// flushStats for shader.wgsl, adding to the counters when there are some
+pub fn wgsl(enabled: bool) -> &'static str {
+    if enabled {
+        "
+fn addCount(i: u32, n: u32) {
+    let low = atomicAdd(&march_stats.words[2u * i], n);
+    if low > 0xffffffffu - n {
+        atomicAdd(&march_stats.words[2u * i + 1u], 1u);
+    }
+}
+
+fn flushStats() {
+    addCount(0u, countRays);
+    addCount(1u, countSteps);
+    addCount(2u, countExhausted);
+    addCount(3u, countMisses);
+    addCount(4u, countShadowSteps);
+}
+"
+    } else {
+        "
+fn flushStats() {}
+"
+    }
+}
+
+// Whether the raymarch pipelines were given counters by Stats::new
+pub fn counting(bindings: &PipelineBindGroups) -> bool {
+    bindings.contains(MARCH_STATS)
+}
+
+pub struct Stats {
+    interval: u32,
+    frame: u32,
+    // Whether the frame being recorded is one that is counted
+    counting: bool,
+    // Whether its counters were copied to a readback buffer
+    copied: bool,
+    readbacks: ReadbackRing,
+    last: Option<MarchStats>,
+}
+
+impl Stats {
+    // Adds the counters to bindings, None when stats are off or the
+    // adapter can't write to storage buffers from fragment shaders
+    pub fn new(
+        config: &StatsConfig,
+        adapter: &wgpu::Adapter,
+        device: &wgpu::Device,
+        bindings: &mut PipelineBindGroups,
+    ) -> Option<Self> {
+        if !config.enabled { return None; }
+        let flags = adapter.get_downlevel_capabilities().flags;
+        if !flags.contains(wgpu::DownlevelFlags::FRAGMENT_WRITABLE_STORAGE) {
+            log::warn!("stats need fragment shader storage writes, turning them off");
+            return None;
+        }
+        bindings.new_counters::<Counters>(MARCH_STATS, GroupIndex::Buffers, device);
+        let readbacks = ReadbackRing::new(
+            device, "march stats readback", size_of::<Counters>() as u64,
+            READBACKS);
+        Some(Self {
+            interval: config.interval.max(1),
+            frame: 0,
+            counting: false,
+            copied: false,
+            readbacks,
+            last: None,
+        })
+    }
+
+    // Call before the raymarch pass. Zeroes the counters when this frame
+    // is counted.
+    pub fn begin_frame(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        bindings: &mut PipelineBindGroups,
+    ) {
+        self.counting = self.frame.is_multiple_of(self.interval);
+        self.frame = self.frame.wrapping_add(1);
+        if self.counting {
+            encoder.clear_buffer(bindings.buffer(MARCH_STATS), 0, None);
+        }
+    }
+
+    // Call after the raymarch pass
+    pub fn end_frame(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        bindings: &mut PipelineBindGroups,
+    ) {
+        if !std::mem::take(&mut self.counting) { return; }
+        let Some(readback) = self.readbacks.record() else {
+            log::debug!("stats readbacks all in use, not counting this frame");
+            return;
+        };
+        encoder.copy_buffer_to_buffer(
+            bindings.buffer(MARCH_STATS), 0, readback, 0, None);
+        self.copied = true;
+    }
+
+    // Call once every frame is submitted, counted or not. Returns the
+    // stats of the counted frames that have finished since, the last of
+    // them if there are several. With wait, waits for the GPU to finish
+    // so a frame counted just now is among them.
+    pub fn read(&mut self, device: &wgpu::Device, wait: bool) -> Option<MarchStats> {
+        if std::mem::take(&mut self.copied) {
+            self.readbacks.submitted();
+        }
+        let mut read = None;
+        self.readbacks.read(device, wait, |bytes| {
+            read = Some(MarchStats::from(*bytemuck::from_bytes::<Counters>(bytes)));
+        });
+        self.last = read.or(self.last);
+        read
+    }
+
+    // From the last counted frame read back
+    pub fn last(&self) -> Option<MarchStats> {
+        self.last
+    }
+}
@@ -1,4 +1,3 @@
-use wgpu::BindGroupEntry;
 use wgpu::util::DeviceExt;
 use num_traits::cast::ToPrimitive;
 
@@ -14,23 +13,71 @@ use enum_map::{enum_map, Enum, EnumMap};
 //     Float(f32),
 // }
 
+// Rust types that can be stored in a shader buffer. The WGSL type name
+// is used by make_wgsl to declare the binding, and structs also supply
+// their WGSL definition so Rust and the shader are declared in one place.
+pub trait ShaderType: bytemuck::Pod {
+    const WGSL_TYPE: &'static str;
+    const WGSL_STRUCT: &'static str = "";
+}
+
+impl ShaderType for i32 { const WGSL_TYPE: &'static str = "i32"; }
+impl ShaderType for u32 { const WGSL_TYPE: &'static str = "u32"; }
+impl ShaderType for f32 { const WGSL_TYPE: &'static str = "f32"; }
+impl ShaderType for [f32; 4] { const WGSL_TYPE: &'static str = "vec4f"; }
+
+// How the buffer is seen by the shader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferKind {
+    Uniform,
+    // var<storage, read> array<T>
+    Storage,
+    // var<storage, read_write> T, for a struct of atomics the shader
+    // adds to and Rust reads back
+    Counters,
+}
+
+// What a binding holds. Buffers carry their WGSL type, textures and
+// samplers are described by their wgpu binding type.
+enum Resource {
+    Buffer {
+        kind: BufferKind,
+        buffer: wgpu::Buffer,
+        wgsl_type: String,
+        wgsl_struct: &'static str,
+    },
+    Texture {
+        view: wgpu::TextureView,
+        dimension: wgpu::TextureViewDimension,
+        // Formats like Rgba32Float can only be read with textureLoad
+        filterable: bool,
+    },
+    Sampler {
+        sampler: wgpu::Sampler,
+    },
+    // Written by compute shaders, 2D only
+    StorageTexture {
+        view: wgpu::TextureView,
+        format: wgpu::TextureFormat,
+    },
+}
+
 //  Should this have a generic type parameter for value?
+//  Now the value only lives in the buffer and the type is kept as text.
 pub struct Uniform {
     name: String,           // Shader variable name
-    value: i32,                 // Shader variable type
     bind_group: GroupIndex,
     binding: u32,
-    buffer: wgpu::Buffer,
+    resource: Resource,
 }
 
 impl Uniform {
-    fn new(
+    fn new_buffer<T: ShaderType>(
         name: &str,
-        value: i32,
-        bind_group: GroupIndex,
-        binding: u32,
+        value: &[T],
+        kind: BufferKind,
         device: &wgpu::Device,
-    ) -> Self {
+    ) -> Resource {
         // let name = name.to_string();
         // let buffer = device.create_buffer_init(
         //     &wgpu::util::BufferInitDescriptor {
@@ -44,44 +91,76 @@ impl Uniform {
         //     binding: binding,
         //     resource: buffer.as_entire_binding(),
         // };
-        Self {
-            name: name.to_string(),
-            value,
-            bind_group,
-            binding,
-            buffer: device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some(&name),
-                    // contents: bytemuck::cast_slice(&[camera_uniform]),
-                    contents: bytemuck::cast_slice(&[value]),
-                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-                }
-            ),
+        let wgsl_type = match kind {
+            BufferKind::Uniform => T::WGSL_TYPE.to_string(),
+            BufferKind::Storage => format!("array<{}>", T::WGSL_TYPE),
+            BufferKind::Counters => T::WGSL_TYPE.to_string(),
+        };
+        Resource::Buffer {
+            kind,
+            buffer: Self::make_buffer(name, value, kind, device),
+            wgsl_type,
+            wgsl_struct: T::WGSL_STRUCT,
         }
     }
-    fn make_buffer(
-        &mut self,
-        value: i32,
+
+    fn make_buffer<T: ShaderType>(
+        name: &str,
+        value: &[T],
+        kind: BufferKind,
         device: &wgpu::Device,
-    ) {
-        self.value = value;
-        self.buffer = device.create_buffer_init(
+    ) -> wgpu::Buffer {
+        // Zero sized buffers can't be bound, so an empty array still
+        // gets one zeroed element. The shader is told the real count.
+        let zero = [T::zeroed()];
+        let value = if value.is_empty() { &zero[..] } else { value };
+        let usage = match kind {
+            BufferKind::Uniform => wgpu::BufferUsages::UNIFORM,
+            BufferKind::Storage => wgpu::BufferUsages::STORAGE,
+            BufferKind::Counters =>
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        };
+        device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
-                label: Some(&self.name),
-                contents: bytemuck::cast_slice(&[value]),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                label: Some(name),
+                contents: bytemuck::cast_slice(value),
+                usage: usage | wgpu::BufferUsages::COPY_DST,
             }
-        );
+        )
     }
+
     fn make_layout(&self) -> wgpu::BindGroupLayoutEntry {
-        wgpu::BindGroupLayoutEntry {
-            binding: self.binding,
-            visibility: wgpu::ShaderStages::FRAGMENT,
-            ty: wgpu::BindingType::Buffer {
-                ty: wgpu::BufferBindingType::Uniform,
+        let ty = match &self.resource {
+            Resource::Buffer { kind, .. } => wgpu::BindingType::Buffer {
+                ty: match kind {
+                    BufferKind::Uniform => wgpu::BufferBindingType::Uniform,
+                    BufferKind::Storage =>
+                        wgpu::BufferBindingType::Storage { read_only: true },
+                    BufferKind::Counters =>
+                        wgpu::BufferBindingType::Storage { read_only: false },
+                },
                 has_dynamic_offset: false,
                 min_binding_size: None,
             },
+            Resource::Texture { dimension, filterable, .. } => wgpu::BindingType::Texture {
+                sample_type:
+                    wgpu::TextureSampleType::Float { filterable: *filterable },
+                view_dimension: *dimension,
+                multisampled: false,
+            },
+            Resource::Sampler { .. } => wgpu::BindingType::Sampler(
+                wgpu::SamplerBindingType::Filtering),
+            Resource::StorageTexture { format, .. } =>
+                wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: *format,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+        };
+        wgpu::BindGroupLayoutEntry {
+            binding: self.binding,
+            visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+            ty,
             count: None,
         }
     }
@@ -89,12 +168,20 @@ impl Uniform {
     ////    should this be done in BindGroup? and prevent needing
     ///     to annotate lifetime?
     fn make_bind(
-        &mut self,
+        &self,
         // device: &wgpu::Device,
     ) -> wgpu::BindGroupEntry<'_> {
+        let resource = match &self.resource {
+            Resource::Buffer { buffer, .. } => buffer.as_entire_binding(),
+            Resource::Texture { view, .. }
+            | Resource::StorageTexture { view, .. } =>
+                wgpu::BindingResource::TextureView(view),
+            Resource::Sampler { sampler } =>
+                wgpu::BindingResource::Sampler(sampler),
+        };
         wgpu::BindGroupEntry {
             binding: self.binding,
-            resource: self.buffer.as_entire_binding(),
+            resource,
         }
     }
 
@@ -102,8 +189,40 @@ impl Uniform {
         let bind_goup = self.bind_group as u32;
         let binding = self.binding;
         let name = &self.name;
+        let decl = match &self.resource {
+            Resource::Buffer { kind: BufferKind::Uniform, wgsl_type, .. } =>
+                format!("var<uniform> {name}: {wgsl_type};"),
+            Resource::Buffer { kind: BufferKind::Storage, wgsl_type, .. } =>
+                format!("var<storage, read> {name}: {wgsl_type};"),
+            Resource::Buffer { kind: BufferKind::Counters, wgsl_type, .. } =>
+                format!("var<storage, read_write> {name}: {wgsl_type};"),
+            Resource::Texture { dimension, .. } => {
+                let ty = match dimension {
+                    wgpu::TextureViewDimension::D3 => "texture_3d<f32>",
+                    _ => "texture_2d<f32>",
+                };
+                format!("var {name}: {ty};")
+            }
+            Resource::Sampler { .. } => format!("var {name}: sampler;"),
+            Resource::StorageTexture { format, .. } => {
+                let texel = match format {
+                    wgpu::TextureFormat::Rgba16Float => "rgba16float",
+                    wgpu::TextureFormat::Rgba32Float => "rgba32float",
+                    wgpu::TextureFormat::Rgba8Unorm => "rgba8unorm",
+                    _ => panic!("no storage texture format for {format:?}"),
+                };
+                format!("var {name}: texture_storage_2d<{texel}, write>;")
+            }
+        };
         format!("@group({bind_goup}) @binding({binding})
-            var<uniform> {name}: i32;\n")
+            {decl}\n")
+    }
+
+    fn wgsl_struct(&self) -> &'static str {
+        match &self.resource {
+            Resource::Buffer { wgsl_struct, .. } => wgsl_struct,
+            _ => "",
+        }
     }
 }
 
@@ -125,22 +244,24 @@ impl BindGroup {
             layouts: Vec::new(),
         }
     }
-    fn new_uniform(
-        &mut self,
-        name: &str,
-        ii: i32,
-        // bind_group: u32,
-        // binding: u32,
-        device: &wgpu::Device,
-    ) {
-        let binding = self.uniforms.len().to_u32().expect("");
-        let uniform = Uniform::new(
-            name, ii, self.bind_group, binding, device);
+    fn new_binding(&mut self, name: &str, resource: Resource) {
+        // wgpu's limit on bindings per group is far below this
+        let binding = self.uniforms.len().to_u32()
+            .expect("more bindings in a group than fit in a u32");
+        let uniform = Uniform {
+            name: name.to_string(),
+            bind_group: self.bind_group,
+            binding,
+            resource,
+        };
         // self.uniforms.push(Uniform::new(
         //     name, ii, self.bind_group as u32, binding, device));
         self.layouts.push(uniform.make_layout());
         self.uniforms.push(uniform);
     }
+    fn find(&mut self, name: &str) -> Option<&mut Uniform> {
+        self.uniforms.iter_mut().find(|u| u.name == name)
+    }
     fn make_layout(
         &self,
         device: &wgpu::Device,
@@ -149,7 +270,7 @@ impl BindGroup {
         // for uniform in &self.uniforms {
         //     layouts.push(uniform.make_layout());
         // }
-        println!("uniforms length = {}", self.uniforms.len());
+        log::debug!("uniforms length = {}", self.uniforms.len());
         device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
                 // entries: &self.layouts,
@@ -160,24 +281,23 @@ impl BindGroup {
             }
         )
     }
-    fn make_binds(
-        &mut self,
-    ) -> Vec<wgpu::BindGroupEntry<'_>> {
+    fn make_binds(&self) -> Vec<wgpu::BindGroupEntry<'_>> {
         let mut binds: Vec<wgpu::BindGroupEntry> = Vec::new();
         // fill in here
-        for uniform in &mut self.uniforms {
+        for uniform in &self.uniforms {
             binds.push(uniform.make_bind());
         }
         binds
     }
     fn make_group(
-        &mut self,
+        &self,
+        layout: &wgpu::BindGroupLayout,
         device: &wgpu::Device,
    ) -> wgpu::BindGroup {
         let bind_group = self.bind_group;
         device.create_bind_group(
             &wgpu::BindGroupDescriptor {
-                layout: &self.make_layout(device),
+                layout,
                 entries: &self.make_binds(),
                 // label: Some(&(self.name.clone() + "_bind_group")),
                 label: Some(
@@ -185,15 +305,6 @@ impl BindGroup {
             }
         )
     }
-    fn set_render_pass(
-        &mut self,
-        device: &wgpu::Device,
-        render_pass: &mut wgpu::RenderPass
-    ) {
-        let bind_group = self.bind_group as u32;
-        render_pass.set_bind_group(
-            bind_group, &self.make_group(device), &[]);
-    }
     fn make_wgsl(&self) -> String {
         let mut str = String::new();
         for uniform in &self.uniforms {
@@ -207,13 +318,7 @@ impl BindGroup {
 pub enum GroupIndex {
     Scalars=0,
     Textures,
-}
-
-fn group_names(index: GroupIndex) -> &'static str {
-    match index  {
-        GroupIndex::Scalars => { "Scalars" }
-        GroupIndex::Textures => { "Textures" }
-    }
+    Buffers,
 }
 
 //  Need all layouts struct?
@@ -221,6 +326,7 @@ fn group_names(index: GroupIndex) -> &'static str {
 pub struct PipelineBindGroups {
     name: String,
     groups: EnumMap<GroupIndex, BindGroup>,
+    // One per group up to the last used one, indexed by group number
     layouts: Vec<wgpu::BindGroupLayout>,
     // list: GroupArray<BindGroup>,
     // list: [BindGroup; N_OBJECTS],
@@ -233,31 +339,141 @@ impl PipelineBindGroups {
         Self {
             name: name.to_string(),
             groups: enum_map!{
-                GroupIndex::Scalars => 
+                GroupIndex::Scalars =>
                     BindGroup::new(GroupIndex::Scalars),
                 GroupIndex::Textures =>
                     BindGroup::new(GroupIndex::Textures),
+                GroupIndex::Buffers =>
+                    BindGroup::new(GroupIndex::Buffers),
             },
             layouts: Vec::new(),
         }
     }
-    pub fn new_uniform(
+    pub fn new_uniform<T: ShaderType>(
         &mut self,
         name: &str,
         group: GroupIndex,
-        ii: i32,
+        value: T,
         device: &wgpu::Device,
     ) {
         // let mut bind_group = self.list.last().expect("");
         // let mut bind_group = self.find_bind_group(group_name)
         //     .expect(&format!("not a bind group: {group_name}"));
         // bind_group.new_uniform(name, ii, device);
-        println!("new uniform = {}", name);
-        self.groups[group].new_uniform(name, ii, device);
+        log::debug!("new uniform = {}", name);
+        let resource = Uniform::new_buffer(
+            name, &[value], BufferKind::Uniform, device);
+        self.groups[group].new_binding(name, resource);
+    }
+    // Read only array of T, the shader sees it as array<T>
+    pub fn new_storage<T: ShaderType>(
+        &mut self,
+        name: &str,
+        group: GroupIndex,
+        values: &[T],
+        device: &wgpu::Device,
+    ) {
+        log::debug!("new storage = {}", name);
+        let resource = Uniform::new_buffer(
+            name, values, BufferKind::Storage, device);
+        self.groups[group].new_binding(name, resource);
+    }
+    // Sampled float texture, the shader sees texture_2d<f32> or
+    // texture_3d<f32> depending on the view dimension
+    pub fn new_texture(
+        &mut self,
+        name: &str,
+        group: GroupIndex,
+        view: wgpu::TextureView,
+        dimension: wgpu::TextureViewDimension,
+    ) {
+        log::debug!("new texture = {}", name);
+        self.groups[group].new_binding(
+            name, Resource::Texture { view, dimension, filterable: true });
+    }
+    // Zeroed T the shader can write, see BufferKind::Counters
+    pub fn new_counters<T: ShaderType>(
+        &mut self,
+        name: &str,
+        group: GroupIndex,
+        device: &wgpu::Device,
+    ) {
+        log::debug!("new counters = {}", name);
+        let resource = Uniform::new_buffer(
+            name, &[T::zeroed()], BufferKind::Counters, device);
+        self.groups[group].new_binding(name, resource);
+    }
+    // 2D float texture that can't be filtered, read with textureLoad
+    pub fn new_unfiltered_texture(
+        &mut self,
+        name: &str,
+        group: GroupIndex,
+        view: wgpu::TextureView,
+    ) {
+        log::debug!("new unfiltered texture = {}", name);
+        let dimension = wgpu::TextureViewDimension::D2;
+        self.groups[group].new_binding(
+            name, Resource::Texture { view, dimension, filterable: false });
+    }
+    // 2D texture a compute shader writes with textureStore
+    pub fn new_storage_texture(
+        &mut self,
+        name: &str,
+        group: GroupIndex,
+        view: wgpu::TextureView,
+        format: wgpu::TextureFormat,
+    ) {
+        log::debug!("new storage texture = {}", name);
+        self.groups[group].new_binding(
+            name, Resource::StorageTexture { view, format });
+    }
+    pub fn new_sampler(
+        &mut self,
+        name: &str,
+        group: GroupIndex,
+        sampler: wgpu::Sampler,
+    ) {
+        log::debug!("new sampler = {}", name);
+        self.groups[group].new_binding(name, Resource::Sampler { sampler });
+    }
+    fn find(&mut self, name: &str) -> &mut Uniform {
+        self.groups.values_mut()
+            .find_map(|g| g.find(name))
+            .unwrap_or_else(|| panic!("not a uniform: {name}"))
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.groups.values()
+            .any(|g| g.uniforms.iter().any(|u| u.name == name))
+    }
+    // The buffer behind a uniform, storage array or counters
+    pub fn buffer(&mut self, name: &str) -> &wgpu::Buffer {
+        match &self.find(name).resource {
+            Resource::Buffer { buffer, .. } => buffer,
+            _ => panic!("not a buffer: {name}"),
+        }
+    }
+    // Overwrites the value of an existing uniform
+    pub fn set_uniform<T: ShaderType>(
+        &mut self,
+        name: &str,
+        value: T,
+        queue: &wgpu::Queue,
+    ) {
+        match &self.find(name).resource {
+            Resource::Buffer { buffer, .. } =>
+                queue.write_buffer(buffer, 0, bytemuck::bytes_of(&value)),
+            _ => panic!("not a buffer: {name}"),
+        }
+    }
+    // Points an existing texture binding at a different view of the same
+    // dimension
+    pub fn set_texture(&mut self, name: &str, new_view: wgpu::TextureView) {
+        match &mut self.find(name).resource {
+            Resource::Texture { view, .. }
+            | Resource::StorageTexture { view, .. } => *view = new_view,
+            _ => panic!("not a texture: {name}"),
+        }
     }
-        // for uniform in &self.uniforms {
-        //     layouts.push(uniform.make_layout());
-        // }
 
     pub fn pipeline_layout(
         &mut self,
@@ -265,11 +481,17 @@ impl PipelineBindGroups {
     ) -> wgpu::PipelineLayout {
         // Lives long enough for Rust but long enough for wgpu?
         // println!("{:#?}", &self.groups[GroupIndex::Scalars]);
-        for (_k, g) in &self.groups {
-            if !g.uniforms.is_empty() {
-                self.layouts.push(g.make_layout(&device));
-            }
-        }
+        // Group numbers are positions in the layout list, so unused
+        // groups below the last used one still need an (empty) layout.
+        let used = self.groups.iter()
+            .filter(|(_k, g)| !g.uniforms.is_empty())
+            .map(|(k, _g)| k as usize + 1)
+            .max()
+            .unwrap_or(0);
+        self.layouts = self.groups.values()
+            .take(used)
+            .map(|g| g.make_layout(device))
+            .collect();
 
         let layout_ref: Vec<&wgpu::BindGroupLayout> =
             self.layouts.iter().collect();
@@ -277,8 +499,7 @@ impl PipelineBindGroups {
         // for layout in &self.layouts {
         //     layout_ref.push(layout);
         // }
-        let alayout = &layout_ref[..];
-        println!("{:#?}", alayout);
+        log::debug!("{:#?}", layout_ref);
         let aname = &self.name;
         device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
@@ -294,16 +515,92 @@ impl PipelineBindGroups {
         device: &wgpu::Device,
         render_pass: &mut wgpu::RenderPass,
     ) {
-        for (_k, g) in &mut self.groups {
-            g.set_render_pass(device, render_pass);
+        for (g, layout) in self.groups.values().zip(&self.layouts) {
+            let bind_group = g.bind_group as u32;
+            render_pass.set_bind_group(
+                bind_group, &g.make_group(layout, device), &[]);
+        }
+    }
+    pub fn set_compute_pass(
+        &mut self,
+        device: &wgpu::Device,
+        compute_pass: &mut wgpu::ComputePass,
+    ) {
+        for (g, layout) in self.groups.values().zip(&self.layouts) {
+            let bind_group = g.bind_group as u32;
+            compute_pass.set_bind_group(
+                bind_group, &g.make_group(layout, device), &[]);
         }
     }
+    // Struct definitions followed by the binding declarations, to be put
+    // in front of the shader source.
     pub fn make_wgsl(&self) -> String {
         let mut str = String::new();
-        for (_k, g) in &self.groups {
+        let mut structs: Vec<&str> = Vec::new();
+        for g in self.groups.values() {
+            for uniform in &g.uniforms {
+                let wgsl_struct = uniform.wgsl_struct();
+                if !wgsl_struct.is_empty() && !structs.contains(&wgsl_struct) {
+                    structs.push(wgsl_struct);
+                }
+            }
+        }
+        for s in structs {
+            str.push_str(s);
+        }
+        for g in self.groups.values() {
             str.push_str(&g.make_wgsl());
         }
         str
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShaderType;
+
+    // Size of the struct as naga lays it out from its WGSL definition
+    fn wgsl_size<T: ShaderType>() -> u32 {
+        let module = wgpu::naga::front::wgsl::parse_str(T::WGSL_STRUCT)
+            .unwrap_or_else(|e| panic!("{} doesn't parse: {e}", T::WGSL_TYPE));
+        module.types.iter()
+            .find(|(_, ty)| ty.name.as_deref() == Some(T::WGSL_TYPE))
+            .map(|(_, ty)| ty.inner.size(module.to_ctx()))
+            .unwrap_or_else(|| panic!("no struct {} in its WGSL", T::WGSL_TYPE))
+    }
+
+    fn sizes<T: ShaderType>() -> (&'static str, usize, u32) {
+        (T::WGSL_TYPE, std::mem::size_of::<T>(), wgsl_size::<T>())
+    }
+
+    // Every struct handed to the shader, so a field added on one side
+    // only shows up here rather than as garbage on the screen.
+    #[test]
+    fn rust_and_wgsl_sizes_match() {
+        let table = [
+            sizes::<crate::light::Light>(),
+            sizes::<crate::light::Lighting>(),
+            sizes::<crate::light::Occlusion>(),
+            sizes::<crate::material::Material>(),
+            sizes::<crate::material::Trace>(),
+            sizes::<crate::background::Background>(),
+            sizes::<crate::color::Output>(),
+            sizes::<crate::post::Bloom>(),
+            sizes::<crate::post::Blur>(),
+            sizes::<crate::post::Vignette>(),
+            sizes::<crate::post::LutParams>(),
+            sizes::<crate::camera::Camera>(),
+            sizes::<crate::sampling::Sampling>(),
+            sizes::<crate::pathtrace::PathTrace>(),
+            sizes::<crate::denoise::Denoise>(),
+            sizes::<crate::animation::Animation>(),
+            sizes::<crate::slice::Slice>(),
+            sizes::<crate::stats::Counters>(),
+            sizes::<crate::overlay::OverlayParams>(),
+        ];
+        for (name, rust, wgsl) in table {
+            assert_eq!(rust, wgsl as usize, "{name} differs from its WGSL");
+        }
+    }
+}
@@ -0,0 +1,27 @@
+// What fs_main shows. The values must match the VIEW_* constants in
+// shader.wgsl. Everything but Shaded is for looking into the distance
+// field and skips the post passes other than tonemap.
+named_enum! {
+    #[derive(Default)]
+    pub enum ViewMode("view mode") {
+        #[default]
+        Shaded = "shaded",
+        // Ambient occlusion only, white is unoccluded
+        Occlusion = "ao",
+        // The normal mapped from -1..1 to 0..1
+        Normals = "normals",
+        // White near the camera, darker with distance
+        Depth = "depth",
+        // March steps to the first hit or miss, blue few to red max_steps
+        Steps = "steps",
+        // A color for each material id
+        Material = "material",
+        // How many of the lights reach each point, white is all of them
+        Shadow = "shadow",
+        // How close rays that missed came to a surface, red is closest,
+        // on a log scale. Hits are grey.
+        MissDistance = "miss",
+        // The distance field on the plane in [slice], see slice.rs
+        Slice = "slice",
+    }
+}